@@ -0,0 +1,81 @@
+// A clock tells the database the time of accesses, for hotness decay, page
+// access times and GC. Times have nanosecond resolution:
+// - `SystemClock`, wall clock time since the Unix epoch
+// - `LogicalClock`, time set by the caller, e.g. to the recorded time of the
+//   operations of a replayed trace, so that it decays like the original
+//   workload did however fast it is replayed
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Time since the clock's epoch
+    fn now(&self) -> Duration;
+
+    /// `now` in nanoseconds
+    fn now_nanos(&self) -> u64 {
+        self.now().as_nanos() as u64
+    }
+}
+
+/// Wall clock time since the Unix epoch
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+}
+
+/// Time that only moves when it is set or advanced. Clones share the time,
+/// so the caller keeps a clone to drive the clock given to the database.
+#[derive(Debug, Clone, Default)]
+pub struct LogicalClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl LogicalClock {
+    pub fn new(start: Duration) -> Self {
+        LogicalClock {
+            nanos: Arc::new(AtomicU64::new(start.as_nanos() as u64)),
+        }
+    }
+
+    /// Move the clock to `time`. Earlier times are ignored, the clock never
+    /// goes back, e.g. for trace records slightly out of order.
+    pub fn set(&self, time: Duration) {
+        self.nanos
+            .fetch_max(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.nanos
+            .fetch_add(by.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for LogicalClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logical_clock() {
+        let clock = LogicalClock::new(Duration::from_secs(10));
+        let handle = clock.clone();
+        handle.advance(Duration::from_nanos(1500));
+        assert_eq!(clock.now_nanos(), 10_000_001_500);
+
+        handle.set(Duration::from_secs(20));
+        handle.set(Duration::from_secs(15));
+        assert_eq!(clock.now(), Duration::from_secs(20));
+    }
+}
@@ -1,54 +1,160 @@
-use hashlink::LruCache;
 use serde::Serialize;
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::Path;
-use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{self, AtomicU32, AtomicU64};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 
 use hdrhistogram::Histogram;
 use tracing::{debug, error, info, warn};
 
-use crate::storage::device::{SsdDevice, SsdError, SsdMetrics};
-use crate::storage::page::Page;
+use crate::clock::{Clock, SystemClock};
+use crate::hotness::{HotnessConfig, HotnessPolicy, LastAccess};
+use crate::index::{self, key_hash, Index};
+use crate::storage::cache::{CacheConfig, PageCache, PageRef, PolicyStats};
+use crate::storage::device::{
+    AlignedBuffer, Durability, IoBackend, SsdDevice, SsdError, SsdMetrics,
+};
+use crate::storage::io_uring::{Ordering, Rio};
+use crate::storage::manifest::MAX_LISTED_FREE_PAGES;
+use crate::storage::page::{
+    CorruptionError, Page, ENTRY_FLAG_BATCH, ENTRY_FLAG_TOMBSTONE, ENTRY_METADATA_SIZE,
+};
+use crate::storage::store::PageStore;
+use crate::storage::wal::{Wal, WalConfig, WalRecord};
 
 const DEFAULT_PAGE_SIZE: u32 = 4096; // 4KB page size
-const DEFAULT_CACHE_SIZE: usize = 50; // 100 pages in cache
 
-const DECAY_RATE: f64 = 0.2; // Decay rate parameter lambda
+const SCAN_BATCH_SIZE: usize = 64; // Index entries resolved per batch of page loads
+const RECOVERY_BATCH_SIZE: usize = 64; // Pages read per request while recovering
 
-/// `ObjectMetadata` keeps track of access patterns with decay.
+/// Frequencies this fraction of the hot threshold below it reach it, so that
+/// accesses microseconds apart add up to whole accesses despite decaying in
+/// between
+const HOT_THRESHOLD_EPSILON: f64 = 1e-4;
+const WRITE_LATCHES: usize = 256; // Stripes of the per-key write latches
+
+/// `ObjectMetadata` keeps track of where an entry is and how often it is accessed.
 #[derive(Debug, Copy, Clone)]
 pub struct ObjectMetadata {
     pub location: Location,
     pub size: u32,
-    pub freq_accessed: f64, // access frequency with decay
-    pub last_access: u64,   // timestamp of last access
+    pub seq: u64,            // sequence number of the write that produced the entry
+    pub freq_accessed: f64,  // access frequency as of the last access, see `HotnessPolicy`
+    pub last_access: u64,    // time of last access in nanoseconds, see `Clock`
+    pub stale_versions: u32, // older versions of the key still stored on some page
 }
 
 impl ObjectMetadata {
-    /// Update hotness based on access frequency with exponential decay
-    /// Returns true if hot
-    pub fn update_hotness(&mut self, hot_threshold: u32) -> bool {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// Record the frequency `policy` returned for an access at `now`
+    fn accessed(&mut self, freq: f64, now: Duration) {
+        self.freq_accessed = freq;
+        self.last_access = now.as_nanos() as u64;
+    }
 
-        let time_diff = (now - self.last_access) as f64;
-        // Apply exponential decay: old_freq * e^(-λt) + 1
-        self.freq_accessed = self.freq_accessed * (-DECAY_RATE * time_diff).exp() + 1.0;
-        self.last_access = now;
-        self.freq_accessed >= hot_threshold as f64
+    /// The last access, as handed to the hotness policy
+    fn last(&self) -> LastAccess {
+        LastAccess {
+            freq: self.freq_accessed,
+            time: Duration::from_nanos(self.last_access),
+        }
     }
 }
 
+/// A write as counted by the hotness policy
+#[derive(Debug, Clone, Copy, Default)]
+struct WriteHotness {
+    freq: f64,
+    is_hot: bool,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Location {
     pub page_id: u64,
     pub page_index: usize,
 }
 
+/// Entry of a garbage collection victim that is copied to another page
+#[derive(Debug)]
+struct LiveEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    seq: u64,
+    flags: u8,
+    /// Where the entry is on the victim
+    location: Location,
+}
+
+/// Newest version of a key found while scanning the data file on open.
+#[derive(Debug, Copy, Clone)]
+struct RecoveredEntry {
+    location: Location,
+    size: u32,
+    seq: u64,
+    /// The newest version deletes the key
+    is_tombstone: bool,
+    /// Older versions of the key found on the pages
+    stale_versions: u32,
+}
+
+/// State rebuilt from the data file on open
+#[derive(Debug, Default)]
+struct Recovered {
+    /// Newest version of every key, a tombstone for deleted keys
+    entries: BTreeMap<Vec<u8>, RecoveredEntry>,
+    /// Sequence number following every entry in the file, including the
+    /// dropped entries of interrupted batches
+    next_seq: u64,
+}
+
+/// Newest tombstone of a deleted key. It shadows older versions of the key
+/// that may still be stored on other pages, so GC keeps it until the last
+/// of them is gone.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Tombstone {
+    location: Location,
+    seq: u64,
+    /// Older versions of the key still stored on some page
+    stale_versions: u32,
+}
+
+/// How one operation of a write batch changed the index, so that it can be
+/// reverted if the batch fails
+#[derive(Debug)]
+struct BatchUndo {
+    key: Vec<u8>,
+    /// Entry written by the operation
+    location: Location,
+    /// Index entry and tombstone of the key before the operation
+    index: Option<ObjectMetadata>,
+    tombstone: Option<Tombstone>,
+}
+
+/// A page that has to be read from the device before a request can go on,
+/// see `Database::read_value_or_miss`
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct PageMiss {
+    pub page_id: u64,
+    /// Offset of the page in the data file
+    pub offset: u64,
+    /// Generation of the page when the miss was found, a page read from
+    /// the device later is only current if the generation did not change
+    pub generation: u64,
+}
+
+/// Serialized copy of a modified page waiting to be written to the device by
+/// the caller, see `Database::set_deferred`
+#[derive(Debug)]
+pub(crate) struct PageWrite {
+    pub page_id: u64,
+    pub offset: u64,
+    pub buffer: AlignedBuffer,
+    /// `Ordering::Drain` when an earlier write of the page may still be in flight
+    pub ordering: Ordering,
+}
+
 /// Page metrics for visualization
 #[derive(Debug, Serialize, Clone)]
 pub struct PageMetrics {
@@ -56,6 +162,7 @@ pub struct PageMetrics {
     pub is_hot: bool,
     pub free_space: usize,
     pub access_count: u32,
+    /// Nanoseconds, see `Clock`
     pub last_access: u64,
     pub objects: Vec<ObjectMetrics>,
 }
@@ -66,17 +173,25 @@ pub struct ObjectMetrics {
     pub key: String,
     pub freq: f64,
     pub size: u32,
+    /// Nanoseconds, see `Clock`
     pub last_access: u64,
 }
 
 /// Page status in memory or on SSD, with additional "pool" information.
 #[derive(Debug)]
 struct PageStatus {
-    in_memory: Option<Rc<RefCell<Page>>>,
+    in_memory: Option<PageRef>,
     is_hot: bool,
     free_space: usize,
+    /// Bytes taken by entries that were overwritten or deleted since
+    dead_bytes: usize,
     access_count: u32,
+    /// Nanoseconds, see `Clock`
     last_access: u64,
+    /// Writes in progress, the page is served from `in_memory` until they are done
+    writers: usize,
+    /// Value of `PageState::generation` when the page last changed
+    generation: u64,
 }
 
 /// PageManager related errors
@@ -84,11 +199,26 @@ struct PageStatus {
 pub enum PageManagerError {
     Storage(SsdError),
     InvalidPage,
+    /// A page read from the device failed validation
+    Corruption {
+        page_id: u64,
+        error: CorruptionError,
+    },
+    /// The data file holds fewer pages than the manifest recorded
+    TruncatedFile {
+        expected: u64,
+        found: u64,
+    },
 }
 
 impl From<SsdError> for PageManagerError {
     fn from(error: SsdError) -> Self {
-        PageManagerError::Storage(error)
+        match error {
+            SsdError::Corruption { page_id, error } => {
+                PageManagerError::Corruption { page_id, error }
+            }
+            error => PageManagerError::Storage(error),
+        }
     }
 }
 
@@ -99,67 +229,421 @@ pub enum DatabaseError {
     StorageFull,
     InvalidData,
     Storage(PageManagerError),
+    Wal(io::Error),
+    /// Stored data failed validation, the database may need to be restored
+    Corruption {
+        page_id: u64,
+        error: CorruptionError,
+    },
 }
 
 impl From<PageManagerError> for DatabaseError {
     fn from(error: PageManagerError) -> Self {
-        DatabaseError::Storage(error)
+        match error {
+            PageManagerError::Corruption { page_id, error } => {
+                DatabaseError::Corruption { page_id, error }
+            }
+            error => DatabaseError::Storage(error),
+        }
     }
 }
 
-/// PageManager is responsible for managing memory pages and SSD pages, distinguishing between "cold" and "hot" data.
+/// Options controlling how a `Database` is opened
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Access frequency from which entries are placed on hot pages, the
+    /// starting point when `adaptive_threshold` is set
+    pub hot_threshold: u32,
+    /// Recompute the hot threshold periodically, `None` keeps it fixed
+    pub adaptive_threshold: Option<AdaptiveThreshold>,
+    /// Discard any data already stored at the path
+    pub truncate: bool,
+    /// Log every write to a write-ahead log next to the data file and
+    /// flush pages lazily at checkpoints. Disabled by default.
+    pub wal: Option<WalConfig>,
+    /// When page writes reach stable storage. The WAL, when enabled,
+    /// fsyncs its own groups regardless of this setting.
+    pub durability: Durability,
+    /// Reclaim space of overwritten entries automatically, `None` leaves
+    /// collection to explicit `Database::gc` calls
+    pub gc: Option<GcConfig>,
+    /// How the data file issues page I/O, to compare syscalls and io_uring
+    pub io_backend: IoBackend,
+    /// Load the hot pages into the cache on open, see `Database::warm_cache`
+    pub warm_cache: bool,
+    /// Move entries whose classification changed between hot and cold
+    /// pages, `None` leaves them where they were written
+    pub migration: Option<MigrationConfig>,
+    /// Memory budget and eviction policy of the page cache
+    pub cache: CacheConfig,
+    /// How access frequencies are estimated, compared against `hot_threshold`
+    pub hotness: HotnessConfig,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            hot_threshold: 3,
+            adaptive_threshold: None,
+            truncate: false,
+            wal: None,
+            durability: Durability::None,
+            gc: Some(GcConfig::default()),
+            io_backend: IoBackend::Syscall,
+            warm_cache: false,
+            migration: None,
+            cache: CacheConfig::default(),
+            hotness: HotnessConfig::default(),
+        }
+    }
+}
+
+/// How the garbage collector ranks pages that may be collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcPolicy {
+    /// Pages with the most dead bytes first
+    Greedy,
+    /// Weigh the dead bytes of a page against the cost of copying its live
+    /// entries, favouring pages that have not been touched for a while
+    CostBenefit,
+}
+
+/// Garbage collection settings
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    pub policy: GcPolicy,
+    /// Fraction of the data file taken by dead entries that triggers a run.
+    /// Only pages with at least this fraction of dead bytes are collected.
+    pub dead_ratio: f64,
+    /// Most pages collected in a single run
+    pub max_pages: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> GcConfig {
+        GcConfig {
+            policy: GcPolicy::CostBenefit,
+            dead_ratio: 0.5,
+            max_pages: 8,
+        }
+    }
+}
+
+/// Size of the hot set an adaptive hot threshold aims for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HotSetTarget {
+    /// Accesses among the top `percent` of the access frequencies recorded
+    /// since the threshold was last recomputed are hot
+    TopAccesses(f64),
+    /// Hot entries take at most this many bytes, not counting the free space
+    /// left on their pages. Entries count at their frequency as of their
+    /// last access.
+    Bytes(u64),
+}
+
+/// Settings for recomputing the hot threshold from the workload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveThreshold {
+    pub target: HotSetTarget,
+    /// Reads and writes between recomputations
+    pub interval: u64,
+}
+
+impl Default for AdaptiveThreshold {
+    fn default() -> AdaptiveThreshold {
+        AdaptiveThreshold {
+            target: HotSetTarget::TopAccesses(10.0),
+            interval: 10_000,
+        }
+    }
+}
+
+/// Bytes of the indexed entries by their frequency as of their last access,
+/// rounded down, kept up to date as entries are written, read and deleted so
+/// that `HotSetTarget::Bytes` is met without going through the index
+#[derive(Debug, Default)]
+struct FrequencyBytes {
+    bytes: BTreeMap<u64, u64>,
+}
+
+impl FrequencyBytes {
+    fn entry_bytes(metadata: &ObjectMetadata) -> u64 {
+        ENTRY_METADATA_SIZE as u64 + metadata.size as u64
+    }
+
+    fn add(&mut self, metadata: &ObjectMetadata) {
+        *self.bytes.entry(metadata.freq_accessed as u64).or_default() +=
+            Self::entry_bytes(metadata);
+    }
+
+    fn remove(&mut self, metadata: &ObjectMetadata) {
+        let freq = metadata.freq_accessed as u64;
+        if let Some(bytes) = self.bytes.get_mut(&freq) {
+            *bytes = bytes.saturating_sub(Self::entry_bytes(metadata));
+            if *bytes == 0 {
+                self.bytes.remove(&freq);
+            }
+        }
+    }
+
+    /// Lowest threshold keeping the entries at or above it within `budget`
+    fn threshold(&self, budget: u64) -> u64 {
+        // Everything above the first frequency over budget
+        let mut used = 0;
+        self.bytes
+            .iter()
+            .rev()
+            .find(|&(_, &bytes)| {
+                used += bytes;
+                used > budget
+            })
+            .map_or(1, |(&freq, _)| freq + 1)
+    }
+}
+
+/// Frequencies of the reads and writes counted so far, updated by every
+/// access under one lock
 #[derive(Debug)]
-struct PageManager {
-    pages: HashMap<u64, PageStatus>,
-    device: SsdDevice,
-    next_id: u64,
-    page_size: u32,
-    page_cache: LruCache<u64, Rc<RefCell<Page>>>,
-    hit_count: usize,
-    miss_count: usize,
+struct AccessStats {
+    /// Histogram for tracking access frequencies
+    freq_histogram: Histogram<u64>,
+    /// Access frequencies since the hot threshold was last recomputed
+    tuning_histogram: Histogram<u64>,
+    /// Reads and writes since the hot threshold was last recomputed
+    accesses_since_tuning: u64,
+    /// Times the adaptive threshold changed
+    threshold_updates: u64,
+    /// Bytes of the indexed entries by frequency, see `HotSetTarget::Bytes`
+    freq_bytes: FrequencyBytes,
+}
 
-    hot_free_spaces: BTreeMap<usize, Vec<u64>>,
-    cold_free_spaces: BTreeMap<usize, Vec<u64>>,
+impl AccessStats {
+    fn new(freq_bytes: FrequencyBytes) -> Self {
+        AccessStats {
+            freq_histogram: Histogram::<u64>::new(3).unwrap(),
+            tuning_histogram: Histogram::<u64>::new(3).unwrap(),
+            accesses_since_tuning: 0,
+            threshold_updates: 0,
+            freq_bytes,
+        }
+    }
+
+    /// Record the frequency of an access in the histogram
+    fn record_frequency(&mut self, freq: f64) {
+        self.freq_histogram.record(freq as u64).unwrap();
+        self.tuning_histogram.record(freq as u64).unwrap();
+        self.accesses_since_tuning += 1;
+    }
+
+    /// Replace the frequency bytes of an entry after it was accessed
+    fn accessed(&mut self, metadata: &mut ObjectMetadata, freq: f64, now: Duration) {
+        self.freq_bytes.remove(metadata);
+        metadata.accessed(freq, now);
+        self.freq_bytes.add(metadata);
+    }
+
+    /// Lowest threshold keeping the hot set within `target`, None for
+    /// `TopAccesses` when no access was recorded since the last tuning
+    fn tuned_threshold(&self, target: HotSetTarget) -> Option<u32> {
+        let threshold = match target {
+            HotSetTarget::TopAccesses(percent) => {
+                if self.tuning_histogram.is_empty() {
+                    return None;
+                }
+                // Frequencies are recorded rounded down, only those above
+                // the percentile are in the top
+                self.tuning_histogram
+                    .value_at_percentile(100.0 - percent.clamp(0.0, 100.0))
+                    + 1
+            }
+            HotSetTarget::Bytes(budget) => self.freq_bytes.threshold(budget),
+        };
+        Some(threshold.clamp(1, u32::MAX as u64) as u32)
+    }
 }
 
-impl PageManager {
-    fn new<P: AsRef<Path>>(path: P, page_size: u32) -> Result<Self, PageManagerError> {
-        info!("Initializing SSD device at path {:?}", path.as_ref());
-        let device = SsdDevice::new(path, page_size)?;
-        Ok(PageManager {
-            pages: HashMap::new(),
-            device,
-            next_id: 0,
-            page_size,
-            page_cache: LruCache::new(DEFAULT_CACHE_SIZE),
-            hit_count: 0,
-            miss_count: 0,
-            hot_free_spaces: BTreeMap::new(),
-            cold_free_spaces: BTreeMap::new(),
-        })
+/// Settings for moving entries between hot and cold pages when their
+/// classification changes after they were written
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationConfig {
+    /// Move an entry as soon as a `get` changes its classification. Otherwise
+    /// entries wait for the next write or `Database::migrate`.
+    pub on_read: bool,
+    /// Migrations per second on average, by the database clock. Entries
+    /// found over the rate wait like above.
+    pub rate: f64,
+    /// Migrations allowed in a row after an idle period
+    pub burst: u32,
+    /// Entries `Database::migrate` checks for a changed classification
+    /// besides the waiting ones, e.g. hot entries no longer read
+    pub sweep: usize,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> MigrationConfig {
+        MigrationConfig {
+            on_read: true,
+            rate: 1000.0,
+            burst: 100,
+            sweep: 256,
+        }
     }
+}
 
-    /// Get page metrics for visualization
-    pub fn get_page_metrics(&self) -> HashMap<u64, PageMetrics> {
-        let mut metrics = HashMap::new();
+/// Token bucket bounding the extra writes of migrations
+#[derive(Debug)]
+struct RateLimiter {
+    tokens: f64,
+    refilled: Duration,
+}
 
-        for (page_id, status) in &self.pages {
-            metrics.insert(
-                *page_id,
-                PageMetrics {
-                    page_id: *page_id,
-                    is_hot: status.is_hot,
-                    free_space: status.free_space,
-                    access_count: status.access_count,
-                    last_access: status.last_access,
-                    objects: Vec::new(),
-                },
-            );
+impl RateLimiter {
+    fn new(config: &MigrationConfig, now: Duration) -> Self {
+        RateLimiter {
+            tokens: config.burst as f64,
+            refilled: now,
         }
+    }
 
-        metrics
+    /// Tokens left at `now`
+    fn tokens(&self, config: &MigrationConfig, now: Duration) -> f64 {
+        // A clock going back, e.g. after `Database::set_clock`, refills nothing
+        let elapsed = now.saturating_sub(self.refilled).as_secs_f64();
+        (self.tokens + elapsed * config.rate).min(config.burst as f64)
+    }
+
+    /// Whether `try_take` would succeed at `now`
+    fn has_token(&self, config: &MigrationConfig, now: Duration) -> bool {
+        self.tokens(config, now) >= 1.0
+    }
+
+    /// Take a token if one is left at `now`
+    fn try_take(&mut self, config: &MigrationConfig, now: Duration) -> bool {
+        self.tokens = self.tokens(config, now);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Puts and deletes applied atomically by `Database::write`
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone)]
+enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+
+    /// Number of puts and deletes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
     }
+}
+
+/// Garbage collection counters
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct GcStats {
+    pub runs: u64,
+    pub pages_collected: u64,
+    pub entries_relocated: u64,
+    pub bytes_relocated: u64,
+    /// Tombstones dropped once no older version of their key was left
+    pub tombstones_dropped: u64,
+}
+
+/// Hot/cold migration counters
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct MigrationStats {
+    /// Entries moved to hot pages
+    pub promotions: u64,
+    /// Entries moved to cold pages
+    pub demotions: u64,
+    pub bytes_migrated: u64,
+    /// Migrations postponed because the rate was exceeded
+    pub throttled: u64,
+}
+
+/// PageManager is responsible for managing memory pages and SSD pages, distinguishing between "cold" and "hot" data.
+///
+/// Writes through a shared reference latch only the page they modify. The
+/// page bookkeeping is locked while a write picks its page and once the
+/// write is done, never while a page is latched by the same thread or read
+/// from the device.
+#[derive(Debug)]
+struct PageManager {
+    store: Box<dyn PageStore>,
+    page_size: u32,
+    page_cache: PageCache,
+    state: Mutex<PageState>,
+
+    /// Keep modified pages in memory until `flush` instead of writing them at once
+    defer_writes: bool,
+    /// io_uring issuing the writes taken with `take_page_writes`. Writes of a
+    /// page that has such writes in flight are ordered after them through it.
+    uring: Option<Rio>,
+    /// Time of page accesses, shared with the database
+    clock: Arc<dyn Clock>,
+}
+
+/// Bookkeeping of the pages of a `PageManager`
+#[derive(Debug, Default)]
+struct PageState {
+    pages: HashMap<u64, PageStatus>,
+    next_id: u64,
+
+    hot_free_spaces: BTreeMap<usize, Vec<u64>>,
+    cold_free_spaces: BTreeMap<usize, Vec<u64>>,
+    /// Sum of `dead_bytes` over all pages
+    dead_bytes: usize,
+    /// Entries of write batches below this sequence number are committed
+    batch_watermark: u64,
+    /// Watermark of the batches applied in memory, it becomes the committed
+    /// watermark once their pages are durable
+    pending_batch_watermark: Option<u64>,
+    /// Pages holding no entries, reused before the file is extended. The
+    /// manifest lists them so that recovery doesn't have to read them, the
+    /// ones that don't fit in it read back as never written.
+    free_pages: BTreeSet<u64>,
+
+    dirty_pages: BTreeSet<u64>,
+    /// Pages with writes in flight and how many, served from memory until
+    /// the writes are finished
+    writing: HashMap<u64, usize>,
+    /// Bumped whenever a page changes, see `PageStatus::generation`
+    generation: u64,
+}
 
+impl PageState {
     fn find_suitable_page_id(&self, required_space: usize, is_hot: bool) -> Option<u64> {
         let map = if is_hot {
             &self.hot_free_spaces
@@ -206,299 +690,2359 @@ impl PageManager {
         }
     }
 
-    fn ensure_page_loaded(&mut self, page_id: u64) -> Result<Rc<RefCell<Page>>, PageManagerError> {
-        // First check the LRU cache
-        if let Some(page) = self.page_cache.get(&page_id) {
-            self.hit_count += 1;
-
-            // Update access count for the page
-            if let Some(status) = self.pages.get_mut(&page_id) {
-                status.access_count += 1;
-                status.last_access = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-            }
-
-            return Ok(Rc::clone(page));
-        }
-        self.miss_count += 1;
+    /// Change the free space of a page, in its status and the free-space index
+    fn set_free_space(&mut self, page_id: u64, free_space: usize) {
+        let status = self.pages.get_mut(&page_id).unwrap();
+        let old_free = std::mem::replace(&mut status.free_space, free_space);
+        let is_hot = status.is_hot;
+        self.update_free_space_index(page_id, old_free, free_space, is_hot);
+    }
 
-        // Finally read from disk
-        let page = self.device.read_page(page_id)?;
-        let free_space = page.free_space() as usize;
-        let rc_page = Rc::new(RefCell::new(page));
+    /// Whether the device may not hold the latest version of a page yet
+    fn is_pinned(&self, page_id: u64) -> bool {
+        self.dirty_pages.contains(&page_id)
+            || self.writing.contains_key(&page_id)
+            || self
+                .pages
+                .get(&page_id)
+                .is_some_and(|status| status.writers > 0)
+    }
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let entry = self.pages.entry(page_id).or_insert_with(|| PageStatus {
-            in_memory: None,
-            is_hot: false,
-            free_space,
-            access_count: 0,
-            last_access: now,
-        });
-        entry.in_memory = Some(Rc::clone(&rc_page));
-        entry.free_space = free_space;
-        entry.access_count += 1;
-        entry.last_access = now;
-        let is_hot = entry.is_hot;
+    /// The copy of a pinned page that writes modify
+    fn pinned_page(&self, page_id: u64) -> PageRef {
+        self.pages
+            .get(&page_id)
+            .and_then(|status| status.in_memory.clone())
+            .expect("pinned page must stay in memory")
+    }
 
-        self.update_free_space_index(page_id, 0, free_space, is_hot);
+    /// Generation of a page, 0 for a page that is not allocated
+    fn page_generation(&self, page_id: u64) -> u64 {
+        self.pages
+            .get(&page_id)
+            .map_or(0, |status| status.generation)
+    }
 
-        // Add to cache
-        self.page_cache.insert(page_id, Rc::clone(&rc_page));
+    /// Record that a page changed, so that copies read before are not cached
+    fn changed(&mut self, page_id: u64) {
+        self.generation += 1;
+        if let Some(status) = self.pages.get_mut(&page_id) {
+            status.generation = self.generation;
+        }
+    }
 
-        Ok(rc_page)
+    /// Count an access to a page for the page metrics
+    fn touch(&mut self, page_id: u64, now: u64) {
+        if let Some(status) = self.pages.get_mut(&page_id) {
+            status.access_count += 1;
+            status.last_access = now;
+        }
     }
 
-    fn set_inner(
-        &mut self,
-        key: &[u8],
-        value: &[u8],
-        is_hot: bool,
-    ) -> Result<Option<Location>, PageManagerError> {
-        let required_space = key.len() + value.len() + 8;
+    /// Account `bytes` of the entry at `location` as dead, e.g. after it was overwritten
+    fn mark_dead(&mut self, location: &Location, bytes: usize) {
+        if let Some(status) = self.pages.get_mut(&location.page_id) {
+            status.dead_bytes += bytes;
+            self.dead_bytes += bytes;
+        }
+    }
 
-        if let Some(page_id) = self.find_suitable_page_id(required_space, is_hot) {
-            let page_rc = self.ensure_page_loaded(page_id)?;
-            let old_free = {
-                let status = self.pages.get(&page_id).unwrap();
-                status.free_space
-            };
+    /// Take back a `mark_dead` of the same entry
+    fn mark_live(&mut self, location: &Location, bytes: usize) {
+        if let Some(status) = self.pages.get_mut(&location.page_id) {
+            status.dead_bytes -= bytes;
+            self.dead_bytes -= bytes;
+        }
+    }
 
-            {
-                let mut page = page_rc.borrow_mut();
-                if let Some(page_index) = page.push_entry(key, value) {
-                    self.device.write_page(&mut page)?;
+    fn is_hot_page(&self, page_id: u64) -> bool {
+        self.pages.get(&page_id).is_some_and(|status| status.is_hot)
+    }
+}
 
-                    let new_free = page.free_space() as usize;
-                    let status = self.pages.get_mut(&page_id).unwrap();
-                    status.free_space = new_free;
-                    status.is_hot = is_hot; // Update hot status
+impl PageManager {
+    /// Start managing an empty store
+    fn new(
+        store: Box<dyn PageStore>,
+        hot_threshold: u32,
+        cache: &CacheConfig,
+    ) -> Result<Self, PageManagerError> {
+        info!("Initializing page manager on {:?}", store);
+        let manager = Self::with_store(store, cache);
+        manager.write_manifest(hot_threshold)?;
+        Ok(manager)
+    }
 
-                    self.update_free_space_index(page_id, old_free, new_free, is_hot);
+    /// Start managing a store holding pages already and rebuild the page
+    /// bookkeeping from it
+    fn open(
+        store: Box<dyn PageStore>,
+        hot_threshold: u32,
+        cache: &CacheConfig,
+    ) -> Result<(Self, Recovered), PageManagerError> {
+        info!("Opening page manager on {:?}", store);
+        let mut manager = Self::with_store(store, cache);
+        let recovered = manager.recover()?;
 
-                    return Ok(Some(Location {
-                        page_id,
-                        page_index,
-                    }));
-                }
-            }
+        let previous_threshold = manager.store.manifest().hot_threshold;
+        if previous_threshold != hot_threshold {
+            info!(
+                "Hot threshold changed from {} to {}",
+                previous_threshold, hot_threshold
+            );
         }
+        manager.write_manifest(hot_threshold)?;
+        Ok((manager, recovered))
+    }
 
-        let page_id = self.next_id;
-        let mut new_page = Page::new(page_id, self.page_size);
-        if let Some(page_index) = new_page.push_entry(key, value) {
-            debug!("Creating new page {} for entry", page_id);
-            self.device.write_page(&mut new_page)?;
-            let free_space = new_page.free_space() as usize;
-            let rc_page = Rc::new(RefCell::new(new_page));
-
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+    /// Record the current database parameters in the device manifest
+    fn write_manifest(&self, hot_threshold: u32) -> Result<(), PageManagerError> {
+        let state = self.state.lock().unwrap();
+        let mut manifest = self.store.manifest();
+        manifest.hot_threshold = hot_threshold;
+        manifest.page_count = state.next_id;
+        manifest.batch_watermark = state.batch_watermark;
+        manifest.free_pages = state
+            .free_pages
+            .iter()
+            .take(MAX_LISTED_FREE_PAGES)
+            .copied()
+            .collect();
+        self.store.write_manifest(manifest)?;
+        Ok(())
+    }
 
-            self.pages.insert(
-                page_id,
-                PageStatus {
-                    in_memory: Some(Rc::clone(&rc_page)),
-                    is_hot,
-                    free_space,
-                    access_count: 1,
-                    last_access: now,
-                },
-            );
+    /// Durably remove every free page from the manifest, before one of them
+    /// is written. Recovery would take the page for free otherwise. The
+    /// pages still free are listed again with the next manifest write.
+    fn unlist_free_pages(&self) -> Result<(), PageManagerError> {
+        let mut manifest = self.store.manifest();
+        manifest.free_pages.clear();
+        self.store.write_manifest(manifest)?;
+        self.store.sync_data()?;
+        Ok(())
+    }
 
-            self.update_free_space_index(page_id, 0, free_space, is_hot);
+    fn with_store(store: Box<dyn PageStore>, cache: &CacheConfig) -> Self {
+        let batch_watermark = store.manifest().batch_watermark;
+        let page_size = store.page_size();
+        PageManager {
+            page_size,
+            store,
+            page_cache: PageCache::with_config(cache, page_size),
+            state: Mutex::new(PageState {
+                batch_watermark,
+                ..PageState::default()
+            }),
+            defer_writes: false,
+            uring: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
 
-            // Add new page to cache
-            self.page_cache.insert(page_id, rc_page);
+    /// The page bookkeeping, without locking it
+    fn state_mut(&mut self) -> &mut PageState {
+        self.state.get_mut().unwrap()
+    }
 
-            self.next_id += 1;
-            Ok(Some(Location {
-                page_id,
-                page_index,
-            }))
-        } else {
-            warn!(
-                "Entry too large to fit in a new page (page id: {})",
-                page_id
+    /// Scan every page on the device, registering it in `pages` and the
+    /// free-space maps. When a key appears more than once, the entry with
+    /// the highest sequence number wins. Entries of batches that were not
+    /// committed are ignored.
+    fn recover(&mut self) -> Result<Recovered, PageManagerError> {
+        let page_count = self.store.page_count()?;
+        info!("Recovering {} pages from device", page_count);
+
+        let expected = self.store.manifest().page_count;
+        if page_count < expected {
+            error!(
+                "Data file is truncated: manifest expects {} pages, found {}",
+                expected, page_count
             );
-            Ok(None)
+            return Err(PageManagerError::TruncatedFile {
+                expected,
+                found: page_count,
+            });
         }
-    }
 
-    pub fn set(
-        &mut self,
-        key: &[u8],
-        value: &[u8],
-        is_hot: bool,
-    ) -> Result<Option<Location>, PageManagerError> {
-        let location = self.set_inner(key, value, is_hot)?;
-        // After writing, we keep the page in memory since it's already up to date
-        // Only update free space tracking
-        if let Some(loc) = &location {
-            if let Some(status) = self.pages.get(&loc.page_id) {
-                let old_free = status.free_space;
-                let is_hot = status.is_hot;
-                if let Some(page_rc) = &status.in_memory {
-                    let new_free = page_rc.borrow().free_space() as usize;
-                    if let Some(status) = self.pages.get_mut(&loc.page_id) {
-                        status.free_space = new_free;
+        let now = self.clock.now_nanos();
+        let mut recovered: BTreeMap<Vec<u8>, RecoveredEntry> = BTreeMap::new();
+        let mut next_seq = 0;
+        // Bytes of all entries per page, whatever is not live in the end is dead
+        let mut used_bytes: HashMap<u64, usize> = HashMap::new();
+
+        let state = self.state.get_mut().unwrap();
+        // Pages listed as free in the manifest are not read
+        state.free_pages = self
+            .store
+            .manifest()
+            .free_pages
+            .iter()
+            .copied()
+            .filter(|&page_id| page_id < page_count)
+            .collect();
+        debug!("Manifest lists {} free pages", state.free_pages.len());
+
+        // Read the pages in batches, the device serves them with few requests
+        let page_ids: Vec<u64> = (0..page_count)
+            .filter(|page_id| !state.free_pages.contains(page_id))
+            .collect();
+        for chunk in page_ids.chunks(RECOVERY_BATCH_SIZE) {
+            let reads = self.store.read_pages(chunk);
+            for (&page_id, read) in chunk.iter().zip(reads) {
+                let mut page = match read {
+                    Ok(page) => page,
+                    // Only a zeroed page is free, any other page that fails
+                    // to decode may hold the latest version of some keys
+                    Err(SsdError::Corruption {
+                        error: CorruptionError::Unwritten,
+                        ..
+                    }) => {
+                        debug!(
+                            "Page {} was never written or discarded, it is free",
+                            page_id
+                        );
+                        state.free_pages.insert(page_id);
+                        continue;
                     }
-                    self.update_free_space_index(loc.page_id, old_free, new_free, is_hot);
+                    Err(e) => return Err(e.into()),
+                };
+
+                if let Some(max_seq) = page.iter().map(|entry| entry.seq()).max() {
+                    next_seq = next_seq.max(max_seq + 1);
                 }
+                // Remove the entries of batches that were interrupted, a later
+                // batch would otherwise commit them
+                let watermark = state.batch_watermark;
+                let dropped =
+                    page.retain_entries(|entry| !entry.is_batch() || entry.seq() < watermark);
+                if dropped > 0 {
+                    warn!(
+                        "Dropping {} entries of uncommitted batches on page {}",
+                        dropped, page_id
+                    );
+                    self.store.write_page(&mut page)?;
+                }
+
+                if page.iter().next().is_none() {
+                    state.free_pages.insert(page_id);
+                    continue;
+                }
+
+                for (page_index, entry) in page.iter().enumerate() {
+                    *used_bytes.entry(page_id).or_default() +=
+                        Page::entry_size(entry.key(), entry.value());
+                    let stale_versions = match recovered.get_mut(entry.key()) {
+                        Some(existing) if entry.seq() <= existing.seq => {
+                            existing.stale_versions += 1;
+                            continue;
+                        }
+                        Some(existing) => existing.stale_versions + 1,
+                        None => 0,
+                    };
+                    recovered.insert(
+                        entry.key().to_vec(),
+                        RecoveredEntry {
+                            location: Location {
+                                page_id,
+                                page_index,
+                            },
+                            size: (entry.key().len() + entry.value().len()) as u32,
+                            seq: entry.seq(),
+                            is_tombstone: entry.is_tombstone(),
+                            stale_versions,
+                        },
+                    );
+                }
+
+                let free_space = page.free_space() as usize;
+                let is_hot = page.is_hot();
+                state.pages.insert(
+                    page_id,
+                    PageStatus {
+                        in_memory: None,
+                        is_hot,
+                        free_space,
+                        dead_bytes: 0,
+                        access_count: 0,
+                        last_access: now,
+                        writers: 0,
+                        generation: 0,
+                    },
+                );
+                state.changed(page_id);
+                state.update_free_space_index(page_id, 0, free_space, is_hot);
             }
         }
-        Ok(location)
-    }
 
-    pub fn get(
-        &mut self,
-        location: &Location,
-        key: &[u8],
-    ) -> Result<Option<Vec<u8>>, PageManagerError> {
-        let page_rc = self.ensure_page_loaded(location.page_id)?;
-        let page = page_rc.borrow();
-        Ok(page.get(location.page_index, key))
-    }
-}
-
-/// Database structure, maintains a memory index and a PageManager.
-#[derive(Debug)]
-pub struct Database {
-    /// BTreeMap maintains mapping from key to metadata
-    index: BTreeMap<Vec<u8>, ObjectMetadata>,
-    page_manager: PageManager,
-    hot_threshold: u32,
-    /// Histogram for tracking access frequencies
-    freq_histogram: Histogram<u64>,
-    /// Page metrics for visualization
-    page_metrics: HashMap<u64, PageMetrics>,
-}
+        for entry in recovered.values() {
+            let used = used_bytes.get_mut(&entry.location.page_id).unwrap();
+            *used -= ENTRY_METADATA_SIZE + entry.size as usize;
+        }
+        for (page_id, dead_bytes) in used_bytes {
+            state.pages.get_mut(&page_id).unwrap().dead_bytes = dead_bytes;
+            state.dead_bytes += dead_bytes;
+        }
 
-impl Database {
-    /// Create new database
-    pub fn new<P: AsRef<Path>>(path: P, hot_threshold: u32) -> Result<Self, DatabaseError> {
+        state.next_id = page_count;
         info!(
-            "Initializing database with storage path {:?}, hot_threshold: {}",
-            path.as_ref(),
-            hot_threshold
+            "Recovered {} keys from {} pages, {} pages free",
+            recovered.len(),
+            state.pages.len(),
+            state.free_pages.len()
         );
-        Ok(Database {
-            index: BTreeMap::new(),
-            page_manager: PageManager::new(path, DEFAULT_PAGE_SIZE)?,
-            hot_threshold,
-            freq_histogram: Histogram::<u64>::new(3).unwrap(),
-            page_metrics: HashMap::new(),
+        Ok(Recovered {
+            entries: recovered,
+            next_seq,
         })
     }
 
-    /// Set key-value pair
-    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
-        // Default to cold for new entries
-        let mut is_hot = false;
+    /// Get page metrics for visualization, without the objects of the pages
+    pub fn get_page_metrics(&self) -> HashMap<u64, PageMetrics> {
+        let state = self.state.lock().unwrap();
+        let mut metrics = HashMap::new();
 
-        // If key exists, update hotness
-        if let Some(metadata) = self.index.get_mut(key) {
-            is_hot = metadata.update_hotness(self.hot_threshold);
-            // Record frequency in histogram
-            self.freq_histogram
-                .record(metadata.freq_accessed as u64)
-                .unwrap();
+        for (page_id, status) in &state.pages {
+            metrics.insert(
+                *page_id,
+                PageMetrics {
+                    page_id: *page_id,
+                    is_hot: status.is_hot,
+                    free_space: status.free_space,
+                    access_count: status.access_count,
+                    last_access: status.last_access,
+                    objects: Vec::new(),
+                },
+            );
+        }
+
+        metrics
+    }
+
+    fn find_suitable_page_id(&self, required_space: usize, is_hot: bool) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .find_suitable_page_id(required_space, is_hot)
+    }
+
+    /// Look a page up in memory, either in the cache or among the pinned pages
+    fn cached_page(&self, page_id: u64) -> Option<PageRef> {
+        if let Some(page) = self.page_cache.get(page_id) {
+            self.page_cache.record_hit(page_id);
+            return Some(page);
+        }
+
+        // A pinned page evicted from the cache is newer in memory than on the device
+        let state = self.state.lock().unwrap();
+        if state.is_pinned(page_id) {
+            self.page_cache.record_hit(page_id);
+            let page = state.pinned_page(page_id);
+            self.page_cache.insert(page_id, Arc::clone(&page));
+            return Some(page);
+        }
+        None
+    }
+
+    /// Start looking a page up on behalf of a caller that reads it from the
+    /// device itself. Returns the page if it is in memory, counting a miss
+    /// otherwise.
+    fn cached_page_or_miss(&self, page_id: u64) -> Result<PageRef, PageMiss> {
+        if let Some(page) = self.page_cache.get(page_id) {
+            self.page_cache.record_hit(page_id);
+            return Ok(page);
+        }
+        let state = self.state.lock().unwrap();
+        if state.is_pinned(page_id) {
+            self.page_cache.record_hit(page_id);
+            return Ok(state.pinned_page(page_id));
+        }
+        self.page_cache.record_miss(page_id);
+        Err(self.page_miss(&state, page_id))
+    }
+
+    fn page_miss(&self, state: &PageState, page_id: u64) -> PageMiss {
+        PageMiss {
+            page_id,
+            offset: self.store.page_offset(page_id),
+            generation: state.page_generation(page_id),
+        }
+    }
+
+    /// Add a page the caller read from the device for `miss` to the cache.
+    /// Returns None if the page may have changed since, in which case it
+    /// has to be read again.
+    fn install_page(&self, miss: &PageMiss, page: Page) -> Option<PageRef> {
+        let state = self.state.lock().unwrap();
+        if let Some(page) = self.page_cache.get(miss.page_id) {
+            return Some(page);
+        }
+        if state.is_pinned(miss.page_id) {
+            return Some(state.pinned_page(miss.page_id));
+        }
+        if state.page_generation(miss.page_id) != miss.generation {
+            return None;
+        }
+        let page = Arc::new(RwLock::new(page));
+        self.page_cache.insert(miss.page_id, Arc::clone(&page));
+        Some(page)
+    }
+
+    /// Get a page for reading without touching the page bookkeeping, so
+    /// that it can be called from several threads at once
+    fn load_page(&self, page_id: u64) -> Result<PageRef, PageManagerError> {
+        loop {
+            let miss = match self.cached_page_or_miss(page_id) {
+                Ok(page) => return Ok(page),
+                Err(miss) => miss,
+            };
+            let page = self.store.read_page(page_id)?;
+            if let Some(page) = self.install_page(&miss, page) {
+                return Ok(page);
+            }
+        }
+    }
+
+    /// Count an access to a page for the page metrics
+    fn touch_page(&self, page_id: u64) {
+        let now = self.clock.now_nanos();
+        self.state.lock().unwrap().touch(page_id, now);
+    }
+
+    /// Get a page for a caller with exclusive access, which modifies the
+    /// copy kept with the page status
+    fn ensure_page_loaded(&mut self, page_id: u64) -> Result<PageRef, PageManagerError> {
+        let page = self.load_page(page_id)?;
+        let now = self.clock.now_nanos();
+        let state = self.state_mut();
+        if let Some(status) = state.pages.get_mut(&page_id) {
+            status.in_memory = Some(Arc::clone(&page));
+        }
+        state.touch(page_id, now);
+        Ok(page)
+    }
+
+    /// Like `ensure_page_loaded` for several distinct pages, reading the
+    /// pages that are not in memory from the device in one batch
+    fn ensure_pages_loaded(&mut self, page_ids: &[u64]) -> Result<Vec<PageRef>, PageManagerError> {
+        let mut loaded: Vec<Option<PageRef>> = page_ids
+            .iter()
+            .map(|&page_id| self.cached_page(page_id))
+            .collect();
+        let missing: Vec<u64> = page_ids
+            .iter()
+            .zip(&loaded)
+            .filter(|(_, page)| page.is_none())
+            .map(|(&page_id, _)| page_id)
+            .collect();
+        if !missing.is_empty() {
+            let mut reads = missing.iter().copied().zip(self.store.read_pages(&missing));
+            for page in &mut loaded {
+                if page.is_some() {
+                    continue;
+                }
+                let (page_id, read) = reads.next().unwrap();
+                self.page_cache.record_miss(page_id);
+                let rc_page = Arc::new(RwLock::new(read?));
+                self.page_cache.insert(page_id, Arc::clone(&rc_page));
+                *page = Some(rc_page);
+            }
+        }
+
+        let loaded: Vec<PageRef> = loaded.into_iter().map(Option::unwrap).collect();
+        let now = self.clock.now_nanos();
+        let state = self.state_mut();
+        for (&page_id, page) in page_ids.iter().zip(&loaded) {
+            if let Some(status) = state.pages.get_mut(&page_id) {
+                status.in_memory = Some(Arc::clone(page));
+            }
+            state.touch(page_id, now);
+        }
+        Ok(loaded)
+    }
+
+    /// Fill the cache with the pages most likely to be read, hot pages
+    /// first, then by access count. The pages are read from the device in
+    /// batches and do not count as accessed. Returns the number of pages
+    /// loaded.
+    fn warm_cache(&mut self) -> Result<usize, PageManagerError> {
+        let state = &*self.state.get_mut().unwrap();
+        let mut candidates: Vec<(u64, &PageStatus)> = state
+            .pages
+            .iter()
+            .filter(|(page_id, _)| {
+                !state.is_pinned(**page_id) && !self.page_cache.contains(**page_id)
+            })
+            .map(|(page_id, status)| (*page_id, status))
+            .collect();
+        candidates.sort_by(|(a_id, a), (b_id, b)| {
+            (b.is_hot, b.access_count, a_id).cmp(&(a.is_hot, a.access_count, b_id))
+        });
+        let page_ids: Vec<u64> = candidates
+            .into_iter()
+            .take(self.page_cache.capacity())
+            .map(|(page_id, _)| page_id)
+            .collect();
+        debug!("Warming the cache with {} pages", page_ids.len());
+
+        for (page_id, read) in page_ids.iter().zip(self.store.read_pages(&page_ids)) {
+            self.page_cache
+                .insert(*page_id, Arc::new(RwLock::new(read?)));
+        }
+        Ok(page_ids.len())
+    }
+
+    /// Pin a page for a write, so that it is served from the copy the write
+    /// modifies until `unpin_page`. Returns the miss to read from the device
+    /// first if the page is not in memory.
+    fn pin_page(&self, state: &mut PageState, page_id: u64) -> Result<PageRef, PageMiss> {
+        let page = if state.is_pinned(page_id) {
+            state.pinned_page(page_id)
+        } else if let Some(page) = self.page_cache.get(page_id) {
+            page
+        } else {
+            self.page_cache.record_miss(page_id);
+            return Err(self.page_miss(state, page_id));
+        };
+        self.page_cache.record_hit(page_id);
+        let now = self.clock.now_nanos();
+        let status = state.pages.get_mut(&page_id).unwrap();
+        status.in_memory = Some(Arc::clone(&page));
+        status.writers += 1;
+        state.touch(page_id, now);
+        Ok(page)
+    }
+
+    /// Pin a page like `pin_page`, reading it from the device first if needed
+    fn pin_loaded_page(&self, page_id: u64) -> Result<PageRef, PageManagerError> {
+        loop {
+            let miss = match self.pin_page(&mut self.state.lock().unwrap(), page_id) {
+                Ok(page) => return Ok(page),
+                Err(miss) => miss,
+            };
+            let page = self.store.read_page(page_id)?;
+            self.install_page(&miss, page);
+        }
+    }
+
+    /// Finish a write of a pinned page that left it with `freed` more bytes
+    /// of free space than the bookkeeping counted
+    fn unpin_page(&self, page_id: u64, freed: usize) {
+        let mut state = self.state.lock().unwrap();
+        let status = state.pages.get_mut(&page_id).unwrap();
+        status.writers -= 1;
+        let free_space = status.free_space + freed;
+        state.set_free_space(page_id, free_space);
+        state.changed(page_id);
+    }
+
+    /// Store an entry with `ENTRY_FLAG_*` flags in a page with enough free
+    /// space. Only the page written to is latched, so entries can be stored
+    /// from several threads at once.
+    pub fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        seq: u64,
+        flags: u8,
+        is_hot: bool,
+    ) -> Result<Option<Location>, PageManagerError> {
+        let required_space = Page::entry_size(key, value);
+
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let Some(page_id) = state.find_suitable_page_id(required_space, is_hot) else {
+                break;
+            };
+            let page_rc = match self.pin_page(&mut state, page_id) {
+                Ok(page) => page,
+                Err(miss) => {
+                    drop(state);
+                    let page = self.store.read_page(page_id)?;
+                    self.install_page(&miss, page);
+                    continue;
+                }
+            };
+            // Other writes look for space elsewhere while the entry is stored
+            let free_space = state.pages[&page_id].free_space - required_space;
+            state.set_free_space(page_id, free_space);
+            drop(state);
+
+            let (pushed, written, freed) = {
+                let mut page = page_rc.write().unwrap();
+                let old_free = page.free_space() as usize;
+                let pushed = page.push_entry_with_flags(key, value, seq, flags);
+                let written = match pushed {
+                    Some(_) => self.write_page(&mut page),
+                    None => Ok(()),
+                };
+                if written.is_err() {
+                    // The caller is told the write failed, a later write of
+                    // the page must not persist the entry after all
+                    page.retain_entries(|entry| entry.seq() != seq || entry.key() != key);
+                }
+                let freed = required_space + page.free_space() as usize - old_free;
+                (pushed, written, freed)
+            };
+            self.unpin_page(page_id, freed);
+            written?;
+            match pushed {
+                Some(page_index) => {
+                    return Ok(Some(Location {
+                        page_id,
+                        page_index,
+                    }))
+                }
+                None => break,
+            }
+        }
+
+        // Reuse a free page before extending the file
+        let (mut state, page_id) = loop {
+            let state = self.state.lock().unwrap();
+            let page_id = state.free_pages.first().copied().unwrap_or(state.next_id);
+            if !self.store.manifest().free_pages.contains(&page_id) {
+                break (state, page_id);
+            }
+            // The manifest is written without holding up other requests,
+            // the page may be taken in the meantime so it is picked again
+            drop(state);
+            self.unlist_free_pages()?;
+        };
+        let mut new_page = Page::new(page_id, self.page_size);
+        new_page.set_hot(is_hot);
+        let Some(page_index) = new_page.push_entry_with_flags(key, value, seq, flags) else {
+            warn!(
+                "Entry too large to fit in a new page (page id: {})",
+                page_id
+            );
+            return Ok(None);
+        };
+        debug!("Creating new page {} for entry", page_id);
+        if !state.free_pages.remove(&page_id) {
+            state.next_id += 1;
+        }
+        let free_space = new_page.free_space() as usize;
+        let rc_page = Arc::new(RwLock::new(new_page));
+        // Latched before other writes can find it, none of them may write
+        // the entry before this write knows whether it failed
+        let mut page = rc_page.write().unwrap();
+
+        let now = self.clock.now_nanos();
+
+        // Pinned until written
+        state.pages.insert(
+            page_id,
+            PageStatus {
+                in_memory: Some(Arc::clone(&rc_page)),
+                is_hot,
+                free_space,
+                dead_bytes: 0,
+                access_count: 1,
+                last_access: now,
+                writers: 1,
+                generation: 0,
+            },
+        );
+        state.update_free_space_index(page_id, 0, free_space, is_hot);
+
+        // Add new page to cache
+        self.page_cache.insert(page_id, Arc::clone(&rc_page));
+        drop(state);
+
+        let written = self.write_page(&mut page);
+        let freed = if written.is_err() {
+            page.retain_entries(|entry| entry.seq() != seq || entry.key() != key);
+            required_space
+        } else {
+            0
+        };
+        drop(page);
+        self.unpin_page(page_id, freed);
+        written?;
+        Ok(Some(Location {
+            page_id,
+            page_index,
+        }))
+    }
+
+    /// Turn the entry at `location` into a tombstone written by `seq`, so that
+    /// neither it nor older versions of the key come back on recovery.
+    /// Returns false if the location does not hold a live entry for `key`.
+    pub fn delete(
+        &self,
+        location: &Location,
+        key: &[u8],
+        seq: u64,
+    ) -> Result<bool, PageManagerError> {
+        let page_id = location.page_id;
+        let page_rc = self.pin_loaded_page(page_id)?;
+        let (deleted, written, freed) = {
+            let mut page = page_rc.write().unwrap();
+            let old_free = page.free_space() as usize;
+            let before = page.clone();
+            let deleted = page.tombstone_entry(location.page_index, key, seq);
+            let written = if deleted {
+                self.write_page(&mut page)
+            } else {
+                Ok(())
+            };
+            if written.is_err() {
+                // The entry stays live, as the caller is told
+                *page = before;
+            }
+            (deleted, written, page.free_space() as usize - old_free)
+        };
+        self.unpin_page(page_id, freed);
+        written?;
+        Ok(deleted)
+    }
+
+    /// Account `bytes` of the entry at `location` as dead, e.g. after it was overwritten
+    fn mark_dead(&self, location: &Location, bytes: usize) {
+        self.state.lock().unwrap().mark_dead(location, bytes);
+    }
+
+    /// Take back a `mark_dead` of the same entry
+    fn mark_live(&mut self, location: &Location, bytes: usize) {
+        self.state_mut().mark_live(location, bytes);
+    }
+
+    fn is_hot_page(&self, page_id: u64) -> bool {
+        self.state.lock().unwrap().is_hot_page(page_id)
+    }
+
+    /// Copy the entry at `location` to a page of the given temperature,
+    /// leaving the original as dead bytes. The copy keeps the sequence
+    /// number, so recovery finds the same version either way. Returns the
+    /// new location and the size of the entry, None if it did not fit.
+    fn migrate(
+        &mut self,
+        location: &Location,
+        key: &[u8],
+        is_hot: bool,
+    ) -> Result<Option<(Location, usize)>, PageManagerError> {
+        let page_rc = self.ensure_page_loaded(location.page_id)?;
+        let (value, seq, flags) = {
+            let page = page_rc.read().unwrap();
+            match page.iter().nth(location.page_index) {
+                Some(entry) if entry.key() == key && !entry.is_tombstone() => {
+                    (entry.value().to_vec(), entry.seq(), entry.flags())
+                }
+                _ => {
+                    error!(
+                        "Entry for key '{}' not found at {:?}",
+                        String::from_utf8_lossy(key),
+                        location
+                    );
+                    return Err(PageManagerError::InvalidPage);
+                }
+            }
+        };
+
+        let Some(new_location) = self.set(key, &value, seq, flags, is_hot)? else {
+            return Ok(None);
+        };
+        let bytes = Page::entry_size(key, &value);
+        self.state_mut().mark_dead(location, bytes);
+        Ok(Some((new_location, bytes)))
+    }
+
+    /// Fraction of the data file taken by dead entries
+    fn dead_ratio(&self) -> f64 {
+        let state = self.state.lock().unwrap();
+        let allocated = state.pages.len() * self.page_size as usize;
+        if allocated == 0 {
+            return 0.0;
+        }
+        state.dead_bytes as f64 / allocated as f64
+    }
+
+    /// Pick up to `max_pages` pages to collect, best candidate first
+    fn gc_victims(&mut self, config: &GcConfig) -> Vec<u64> {
+        let now = self.clock.now_nanos();
+        let page_size = self.page_size as f64;
+        let mut candidates: Vec<(f64, u64)> = self
+            .state_mut()
+            .pages
+            .iter()
+            .filter_map(|(&page_id, status)| {
+                let dead = status.dead_bytes as f64 / page_size;
+                if status.dead_bytes == 0 || dead < config.dead_ratio {
+                    return None;
+                }
+                let score = match config.policy {
+                    GcPolicy::Greedy => dead,
+                    // LFS cost-benefit: (1 - u) * age / (1 + u), with u the
+                    // fraction of the page that is not dead
+                    GcPolicy::CostBenefit => {
+                        let idle = Duration::from_nanos(now.saturating_sub(status.last_access));
+                        let age = idle.as_secs_f64() + 1.0;
+                        dead * age / (2.0 - dead)
+                    }
+                };
+                Some((score, page_id))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        candidates
+            .into_iter()
+            .take(config.max_pages)
+            .map(|(_, page_id)| page_id)
+            .collect()
+    }
+
+    /// Withdraw a page from the free-space index so that nothing gets
+    /// written to it while it is being collected. Returns whether it is hot
+    /// and the free space it had.
+    fn take_page(&mut self, page_id: u64) -> (bool, usize) {
+        let state = self.state_mut();
+        let free_space = state.pages[&page_id].free_space;
+        state.set_free_space(page_id, 0);
+        (state.is_hot_page(page_id), free_space)
+    }
+
+    /// Put a page that no longer holds live entries on the free list and
+    /// return its space to the filesystem. The page must have been withdrawn
+    /// with `take_page`.
+    fn free_page(&mut self, page_id: u64) -> Result<(), PageManagerError> {
+        let state = self.state_mut();
+        let status = state.pages.remove(&page_id).unwrap();
+        state.dead_bytes -= status.dead_bytes;
+        // A pending write would bring the old entries back
+        state.dirty_pages.remove(&page_id);
+        self.page_cache.remove(page_id);
+
+        self.order_after_writes_in_flight(page_id)?;
+        self.store.discard_page(page_id)?;
+        self.state_mut().free_pages.insert(page_id);
+        Ok(())
+    }
+
+    /// Make every page write so far durable, including deferred ones
+    fn sync_all(&mut self) -> Result<(), PageManagerError> {
+        if self.defer_writes {
+            self.flush()
+        } else {
+            self.store.sync()?;
+            Ok(())
+        }
+    }
+
+    /// Write a modified page to the device, or mark it dirty when writes are
+    /// deferred. The caller holds the latch of the page.
+    fn write_page(&self, page: &mut Page) -> Result<(), PageManagerError> {
+        if self.defer_writes {
+            self.state.lock().unwrap().dirty_pages.insert(page.id());
+        } else {
+            self.order_after_writes_in_flight(page.id())?;
+            self.store.write_page(page)?;
+        }
+        Ok(())
+    }
+
+    /// Write pages kept in memory to the device with as few requests as
+    /// the store allows
+    fn write_pages(&mut self, page_ids: &BTreeSet<u64>) -> Result<(), PageManagerError> {
+        let state = self.state_mut();
+        let pages: Vec<PageRef> = page_ids
+            .iter()
+            .map(|&page_id| state.pinned_page(page_id))
+            .collect();
+        for &page_id in page_ids {
+            self.order_after_writes_in_flight(page_id)?;
+        }
+        // Latched in page id order
+        let mut guards: Vec<_> = pages.iter().map(|page| page.write().unwrap()).collect();
+        let mut pages: Vec<&mut Page> = guards.iter_mut().map(|guard| &mut **guard).collect();
+        self.store.write_pages(&mut pages)?;
+        Ok(())
+    }
+
+    /// Wait until the writes of a page taken with `take_page_writes` reached
+    /// the device, so that they don't overwrite a newer version of the page
+    fn order_after_writes_in_flight(&self, page_id: u64) -> Result<(), PageManagerError> {
+        let Some(uring) = &self.uring else {
+            return Ok(());
+        };
+        if self.state.lock().unwrap().writing.contains_key(&page_id) {
+            debug!("Waiting for the writes in flight of page {}", page_id);
+            uring
+                .nop_ordered(Ordering::Drain)
+                .wait()
+                .map_err(SsdError::Io)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the dirty pages for the caller to write them, e.g. through
+    /// io_uring. The pages stay in memory until `finish_page_writes`.
+    fn take_page_writes(&mut self) -> Result<Vec<PageWrite>, PageManagerError> {
+        let state = self.state.get_mut().unwrap();
+        let mut writes = Vec::with_capacity(state.dirty_pages.len());
+        while let Some(page_id) = state.dirty_pages.pop_first() {
+            let page_rc = state.pinned_page(page_id);
+            let mut buffer = AlignedBuffer::new(self.page_size as usize).map_err(SsdError::Io)?;
+            buffer.as_mut_slice().fill(0);
+            page_rc
+                .write()
+                .unwrap()
+                .write_to_buffer(buffer.as_mut_slice());
+
+            let in_flight = state.writing.entry(page_id).or_default();
+            let ordering = if *in_flight > 0 {
+                Ordering::Drain
+            } else {
+                Ordering::None
+            };
+            *in_flight += 1;
+            writes.push(PageWrite {
+                page_id,
+                offset: self.store.page_offset(page_id),
+                buffer,
+                ordering,
+            });
+        }
+        Ok(writes)
+    }
+
+    /// Account writes taken with `take_page_writes` once they completed.
+    /// Pages of failed writes are dirty again.
+    fn finish_page_writes(&mut self, writes: &[PageWrite], succeeded: bool, elapsed_nanos: u64) {
+        let state = self.state.get_mut().unwrap();
+        for write in writes {
+            if succeeded {
+                self.store
+                    .record_external_write(write.buffer.as_ref().len(), elapsed_nanos);
+            } else {
+                state.dirty_pages.insert(write.page_id);
+            }
+            if let Some(in_flight) = state.writing.get_mut(&write.page_id) {
+                *in_flight -= 1;
+                if *in_flight == 0 {
+                    state.writing.remove(&write.page_id);
+                }
+            }
+        }
+    }
+
+    /// Commit the batches applied so far with the next `flush`. Entries of
+    /// batches below `watermark` are then visible on recovery.
+    fn commit_batches(&mut self, watermark: u64) {
+        self.state_mut().pending_batch_watermark = Some(watermark);
+    }
+
+    /// Remove the entries of the failed batch with sequence numbers `seqs`
+    /// from the pages it wrote to. The pages stay dirty, so that they are
+    /// written again before a later watermark could commit a copy of the
+    /// entries that reached the device.
+    fn revert_batch(
+        &mut self,
+        page_ids: &BTreeSet<u64>,
+        seqs: &Range<u64>,
+    ) -> Result<(), PageManagerError> {
+        for &page_id in page_ids {
+            let page_rc = self.ensure_page_loaded(page_id)?;
+            let new_free = {
+                let mut page = page_rc.write().unwrap();
+                // They were appended last, the other entries keep their index
+                page.retain_entries(|entry| !entry.is_batch() || !seqs.contains(&entry.seq()));
+                page.free_space() as usize
+            };
+            let state = self.state_mut();
+            state.set_free_space(page_id, new_free);
+            state.changed(page_id);
+            state.dirty_pages.insert(page_id);
+        }
+        Ok(())
+    }
+
+    /// Write every dirty page to the device and wait until they are durable
+    fn flush(&mut self) -> Result<(), PageManagerError> {
+        let state = self.state_mut();
+        if state.dirty_pages.is_empty() && state.pending_batch_watermark.is_none() {
+            return Ok(());
+        }
+        debug!("Flushing {} dirty pages", state.dirty_pages.len());
+        let page_ids = std::mem::take(&mut state.dirty_pages);
+        if let Err(e) = self.write_pages(&page_ids) {
+            self.state_mut().dirty_pages.extend(page_ids);
+            return Err(e);
+        }
+        self.store.sync()?;
+
+        let hot_threshold = self.store.manifest().hot_threshold;
+        match self.state_mut().pending_batch_watermark.take() {
+            Some(watermark) => {
+                // Batches are committed once the manifest naming them is durable
+                self.state_mut().batch_watermark = watermark;
+                self.write_manifest(hot_threshold)?;
+                self.store.sync_data()?;
+                Ok(())
+            }
+            None => self.write_manifest(hot_threshold),
+        }
+    }
+}
+
+impl Drop for PageManager {
+    fn drop(&mut self) {
+        // Keep the page count in the manifest up to date for the next open.
+        // Pages that failed to flush are not on the device, so a manifest
+        // naming them (or a batch watermark covering them) would be wrong.
+        let state = self.state_mut();
+        if !state.dirty_pages.is_empty() || state.pending_batch_watermark.is_some() {
+            warn!(
+                "Not writing the manifest on close, {} pages were not flushed",
+                state.dirty_pages.len()
+            );
+            return;
+        }
+        let hot_threshold = self.store.manifest().hot_threshold;
+        if let Err(e) = self.write_manifest(hot_threshold) {
+            error!("Failed to write manifest on close: {:?}", e);
+        }
+    }
+}
+
+/// Database structure, maintains a memory index and a PageManager.
+///
+/// Single writes and reads go through a shared reference, see
+/// `SharedDatabase`. Writes of the same key are serialized by its write
+/// latch, everything else they share is locked only while it is updated,
+/// in this order: index shard, hotness policy, access stats, page objects,
+/// page bookkeeping. Batches, GC, migration and checkpoints need exclusive
+/// access.
+#[derive(Debug)]
+pub struct Database {
+    /// Maps keys to their metadata and tombstones, sharded by key hash
+    index: Index,
+    page_manager: PageManager,
+    hot_threshold: AtomicU32,
+    adaptive_threshold: Option<AdaptiveThreshold>,
+    /// Estimates access frequencies, see `Options::hotness`
+    hotness: Mutex<Box<dyn HotnessPolicy>>,
+    stats: Mutex<AccessStats>,
+    /// Objects of every page for the page metrics, see `get_page_metrics`
+    page_objects: Mutex<HashMap<u64, Vec<ObjectMetrics>>>,
+    /// Sequence number assigned to the next write
+    next_seq: AtomicU64,
+    /// Write-ahead log, present when enabled in `Options`
+    wal: Option<Mutex<Wal>>,
+    /// Serialize the writes of keys with the same stripe, by key hash
+    write_latches: Box<[Mutex<()>]>,
+    /// Automatic garbage collection, see `Options::gc`
+    gc: Option<GcConfig>,
+    gc_stats: GcStats,
+    /// Hot/cold migration, see `Options::migration`
+    migration: Option<MigrationConfig>,
+    migration_limiter: RateLimiter,
+    /// Keys found on a page of the wrong temperature, waiting for migration
+    migration_queue: Mutex<BTreeSet<Vec<u8>>>,
+    /// Where the next sweep of `migrate` starts
+    migration_cursor: Vec<u8>,
+    migration_stats: MigrationStats,
+}
+
+impl Database {
+    /// Create new database, discarding any data already stored at `path`
+    pub fn new<P: AsRef<Path>>(path: P, hot_threshold: u32) -> Result<Self, DatabaseError> {
+        Self::open_with_options(
+            path,
+            Options {
+                hot_threshold,
+                truncate: true,
+                ..Options::default()
+            },
+        )
+    }
+
+    /// Open the database stored at `path`, rebuilding the index from the data file.
+    /// A missing file is created empty.
+    pub fn open<P: AsRef<Path>>(path: P, hot_threshold: u32) -> Result<Self, DatabaseError> {
+        Self::open_with_options(
+            path,
+            Options {
+                hot_threshold,
+                ..Options::default()
+            },
+        )
+    }
+
+    /// Open the database stored at `path` with the given options
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, DatabaseError> {
+        let path = path.as_ref();
+        info!(
+            "Opening database with storage path {:?}, options: {:?}",
+            path, options
+        );
+
+        let device = if options.truncate {
+            SsdDevice::create_with_backend(path, DEFAULT_PAGE_SIZE, options.io_backend)
+        } else {
+            SsdDevice::new_with_backend(path, DEFAULT_PAGE_SIZE, options.io_backend)
+        }
+        .map_err(PageManagerError::from)?;
+        Self::open_with_store(path, Box::new(device), options)
+    }
+
+    /// Open a database on `store`, rebuilding the index from the pages it
+    /// holds unless `options.truncate` is set, in which case the store must
+    /// be empty. `path` is only used to place the write-ahead log.
+    pub fn open_with_store<P: AsRef<Path>>(
+        path: P,
+        store: Box<dyn PageStore>,
+        options: Options,
+    ) -> Result<Self, DatabaseError> {
+        let path = path.as_ref();
+        let (mut page_manager, recovered) = if options.truncate {
+            let page_manager = PageManager::new(store, options.hot_threshold, &options.cache)?;
+            (page_manager, Recovered::default())
+        } else {
+            PageManager::open(store, options.hot_threshold, &options.cache)?
+        };
+        page_manager
+            .store
+            .set_durability(options.durability)
+            .map_err(PageManagerError::from)?;
+
+        let now = page_manager.clock.now();
+        // Tombstones count here, a later write must outrank the deletion
+        let next_seq = recovered.next_seq;
+        let (tombstones, live): (BTreeMap<_, _>, BTreeMap<_, _>) = recovered
+            .entries
+            .into_iter()
+            .partition(|(_, entry)| entry.is_tombstone);
+        let tombstones: BTreeMap<_, _> = tombstones
+            .into_iter()
+            .map(|(key, entry)| {
+                let tombstone = Tombstone {
+                    location: entry.location,
+                    seq: entry.seq,
+                    stale_versions: entry.stale_versions,
+                };
+                (key, tombstone)
+            })
+            .collect();
+        let entries: BTreeMap<_, _> = live
+            .into_iter()
+            .map(|(key, entry)| {
+                let metadata = ObjectMetadata {
+                    location: entry.location,
+                    size: entry.size,
+                    seq: entry.seq,
+                    freq_accessed: 1.0,
+                    last_access: now.as_nanos() as u64,
+                    stale_versions: entry.stale_versions,
+                };
+                (key, metadata)
+            })
+            .collect();
+        let mut freq_bytes = FrequencyBytes::default();
+        for metadata in entries.values() {
+            freq_bytes.add(metadata);
+        }
+
+        let mut db = Database {
+            index: Index::with_entries(entries, tombstones),
+            page_manager,
+            hot_threshold: AtomicU32::new(options.hot_threshold),
+            adaptive_threshold: options.adaptive_threshold,
+            hotness: Mutex::new(options.hotness.build()),
+            stats: Mutex::new(AccessStats::new(freq_bytes)),
+            page_objects: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(next_seq),
+            wal: None,
+            write_latches: (0..WRITE_LATCHES).map(|_| Mutex::default()).collect(),
+            gc: options.gc,
+            gc_stats: GcStats::default(),
+            migration: options.migration,
+            migration_limiter: RateLimiter::new(&options.migration.unwrap_or_default(), now),
+            migration_queue: Mutex::new(BTreeSet::new()),
+            migration_cursor: Vec::new(),
+            migration_stats: MigrationStats::default(),
+        };
+
+        if let Some(config) = options.wal {
+            let (mut wal, records) =
+                Wal::open(path.with_extension("wal"), config).map_err(DatabaseError::Wal)?;
+            if options.truncate {
+                wal.truncate().map_err(DatabaseError::Wal)?;
+            }
+            db.page_manager.defer_writes = true;
+            db.wal = Some(Mutex::new(wal));
+            if !options.truncate {
+                db.replay(records)?;
+            }
+        }
+        if options.warm_cache {
+            let loaded = db.warm_cache()?;
+            info!("Warmed the cache with {} pages", loaded);
+        }
+
+        Ok(db)
+    }
+
+    /// Re-apply logged writes that did not reach the data file, then checkpoint
+    fn replay(&mut self, records: Vec<WalRecord>) -> Result<(), DatabaseError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let mut replayed = 0;
+        for record in records {
+            let next_seq = self.next_seq.get_mut();
+            *next_seq = (*next_seq).max(record.seq() + 1);
+            // Every logged write counts, as it did when it was made, so that
+            // replayed keys are placed on pages of the same temperature
+            let hotness = match &record {
+                WalRecord::Set { key, .. } => self.update_write_hotness(key),
+                WalRecord::Delete { .. } => WriteHotness::default(),
+            };
+            // GC may have flushed pages holding writes that are still in the log
+            let is_newer = self
+                .index
+                .get(record.key())
+                .map(|metadata| metadata.seq)
+                .or_else(|| self.index.tombstone(record.key()).map(|t| t.seq))
+                .is_none_or(|seq| record.seq() > seq);
+            if !is_newer {
+                continue;
+            }
+
+            match record {
+                WalRecord::Set { seq, key, value } => {
+                    match self.apply_set(&key, &value, seq, 0, hotness) {
+                        // The original write failed the same way and was never acknowledged
+                        Err(DatabaseError::StorageFull) => continue,
+                        result => result?,
+                    }
+                }
+                WalRecord::Delete { seq, key } => {
+                    self.apply_delete(&key, seq)?;
+                }
+            }
+            replayed += 1;
+        }
+        info!("Replayed {} records from write-ahead log", replayed);
+
+        self.checkpoint()
+    }
+
+    /// Count a write of key with the hotness policy. The first write of a
+    /// key is always cold: a single access says nothing of how often the
+    /// key will be accessed, and most keys are written once.
+    fn update_write_hotness(&self, key: &[u8]) -> WriteHotness {
+        let now = self.clock().now();
+        let mut shard = self.index.write(key);
+        let metadata = shard.entries.get_mut(key);
+        let last = metadata.as_ref().map(|metadata| metadata.last());
+        let freq = self.hotness.lock().unwrap().record_access(key, last, now);
+        let mut stats = self.stats.lock().unwrap();
+        // Kept by the entry written next, or until then if it is not
+        let is_hot = match metadata {
+            Some(metadata) => {
+                stats.accessed(metadata, freq, now);
+                self.is_hot(freq)
+            }
+            None => false,
+        };
+        stats.record_frequency(freq);
+        WriteHotness { freq, is_hot }
+    }
+
+    /// Frequency of key at `now` without counting an access
+    fn frequency(&self, key: &[u8], now: Duration) -> f64 {
+        let last = self.index.get(key).map(|metadata| metadata.last());
+        self.hotness.lock().unwrap().frequency(key, last, now)
+    }
+
+    fn is_hot(&self, freq: f64) -> bool {
+        let threshold = self.hot_threshold() as f64;
+        freq >= threshold - threshold * HOT_THRESHOLD_EPSILON
+    }
+
+    /// Access frequency from which entries are placed on hot pages
+    pub fn hot_threshold(&self) -> u32 {
+        self.hot_threshold.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Recompute the hot threshold once every interval of accesses
+    fn maybe_adapt_threshold(&self) {
+        let Some(adaptive) = self.adaptive_threshold else {
+            return;
+        };
+        let mut stats = self.stats.lock().unwrap();
+        if stats.accesses_since_tuning < adaptive.interval {
+            return;
+        }
+        stats.accesses_since_tuning = 0;
+
+        let threshold = stats.tuned_threshold(adaptive.target);
+        // Each interval is tuned on its own accesses
+        stats.tuning_histogram.reset();
+        let Some(threshold) = threshold else {
+            return;
+        };
+        let hot_threshold = self.hot_threshold();
+        if threshold != hot_threshold {
+            info!(
+                "Adapting hot threshold from {} to {} for {:?}",
+                hot_threshold, threshold, adaptive.target
+            );
+            self.hot_threshold
+                .store(threshold, atomic::Ordering::Relaxed);
+            stats.threshold_updates += 1;
         }
+    }
+
+    /// Replace the source of time for hotness and page accesses, e.g. with a
+    /// `LogicalClock` following the recorded time of a replayed trace. Times
+    /// recorded before keep the previous clock's time.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.page_manager.clock = clock;
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.page_manager.clock.as_ref()
+    }
+
+    /// Replace the hotness policy, e.g. to compare custom policies on the
+    /// same trace. The new policy starts from the frequencies the index
+    /// kept, state of the previous policy beyond those is lost.
+    pub fn set_hotness_policy(&mut self, policy: Box<dyn HotnessPolicy>) {
+        info!("Switching hotness policy to {}", policy.name());
+        *self.hotness.get_mut().unwrap() = policy;
+    }
+
+    /// The hotness policy, locked until the guard is dropped
+    pub fn hotness_policy(&self) -> MutexGuard<'_, Box<dyn HotnessPolicy>> {
+        self.hotness.lock().unwrap()
+    }
+
+    /// Set key-value pair
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        self.write_value(key, value)?;
+        self.maintain()
+    }
+
+    /// Set key-value pair like `set` through a shared reference, so that
+    /// several threads can write at once. GC, migration and checkpoints are
+    /// left to the caller, see `maintenance_due`.
+    pub(crate) fn write_value(&self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        let _latch = self.write_latch(key);
+        let hotness = self.update_write_hotness(key);
+
+        let seq = self.next_seq.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if let Some(wal) = &self.wal {
+            let record = WalRecord::Set {
+                seq,
+                key: key.to_vec(),
+                value: value.to_vec(),
+            };
+            wal.lock()
+                .unwrap()
+                .append(&record)
+                .map_err(DatabaseError::Wal)?;
+        }
+
+        self.apply_set(key, value, seq, 0, hotness)?;
+        self.maybe_adapt_threshold();
+        Ok(())
+    }
+
+    /// Lock the write latch of key
+    fn write_latch(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.write_latches[key_hash(key) % self.write_latches.len()]
+            .lock()
+            .unwrap()
+    }
 
+    /// Run the GC, migrations and checkpoint that writes made due
+    pub(crate) fn maintain(&mut self) -> Result<(), DatabaseError> {
+        self.maybe_gc()?;
+        self.maybe_migrate();
+        self.maybe_checkpoint()
+    }
+
+    /// Whether `maintain` has work to do after the writes so far
+    pub(crate) fn maintenance_due(&self) -> bool {
+        let gc_due = self
+            .gc
+            .is_some_and(|config| self.page_manager.dead_ratio() >= config.dead_ratio);
+        let checkpoint_due = self
+            .wal
+            .as_ref()
+            .is_some_and(|wal| wal.lock().unwrap().needs_checkpoint());
+        let migration_due = self.migration.is_some_and(|config| {
+            !self.migration_queue.lock().unwrap().is_empty()
+                && self
+                    .migration_limiter
+                    .has_token(&config, self.clock().now())
+        });
+        gc_due || checkpoint_due || migration_due
+    }
+
+    /// Write the entry to a page and point the index at it
+    fn apply_set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        seq: u64,
+        flags: u8,
+        hotness: WriteHotness,
+    ) -> Result<(), DatabaseError> {
         // Call PageManager to write
-        match self.page_manager.set(key, value, is_hot)? {
+        match self
+            .page_manager
+            .set(key, value, seq, flags, hotness.is_hot)?
+        {
             Some(location) => {
                 debug!(
                     "Writing key '{}' to location {:?}",
                     String::from_utf8_lossy(key),
                     location
                 );
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                let metadata = ObjectMetadata {
+                let now = self.clock().now();
+                let mut metadata = ObjectMetadata {
                     location,
                     size: (key.len() + value.len()) as u32,
-                    freq_accessed: 1.0,
-                    last_access: now,
+                    seq,
+                    freq_accessed: hotness.freq,
+                    last_access: now.as_nanos() as u64,
+                    stale_versions: 0,
+                };
+
+                let mut shard = self.index.write(key);
+                let mut stats = self.stats.lock().unwrap();
+                // The previous version of the key, or its tombstone, is now garbage
+                if let Some(old) = shard.entries.get(key) {
+                    self.page_manager
+                        .mark_dead(&old.location, ENTRY_METADATA_SIZE + old.size as usize);
+                    metadata.stale_versions = old.stale_versions + 1;
+                    // Reads since the write was counted are kept
+                    metadata.freq_accessed = old.freq_accessed;
+                    metadata.last_access = old.last_access;
+                    stats.freq_bytes.remove(old);
+                } else if let Some(tombstone) = shard.tombstones.remove(key) {
+                    self.page_manager
+                        .mark_dead(&tombstone.location, ENTRY_METADATA_SIZE + key.len());
+                    metadata.stale_versions = tombstone.stale_versions + 1;
+                }
+                shard.entries.insert(key.to_vec(), metadata);
+                stats.freq_bytes.add(&metadata);
+                drop(stats);
+                drop(shard);
+
+                // Update page metrics for visualization
+                self.update_page_metrics(key, &metadata);
+
+                Ok(())
+            }
+            None => {
+                error!(
+                    "Failed to allocate space for key '{}'",
+                    String::from_utf8_lossy(key)
+                );
+                Err(DatabaseError::StorageFull)
+            }
+        }
+    }
+
+    /// Delete key, returning `KeyNotFound` if it does not exist
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), DatabaseError> {
+        self.delete_value(key)?;
+        self.maintain()
+    }
+
+    /// Delete key like `delete` through a shared reference, see `write_value`
+    pub(crate) fn delete_value(&self, key: &[u8]) -> Result<(), DatabaseError> {
+        let _latch = self.write_latch(key);
+        if !self.index.contains_key(key) {
+            return Err(DatabaseError::KeyNotFound);
+        }
+
+        let seq = self.next_seq.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if let Some(wal) = &self.wal {
+            let record = WalRecord::Delete {
+                seq,
+                key: key.to_vec(),
+            };
+            wal.lock()
+                .unwrap()
+                .append(&record)
+                .map_err(DatabaseError::Wal)?;
+        }
+
+        self.apply_delete(key, seq)
+    }
+
+    /// Tombstone the entry of key on its page and drop it from the index
+    fn apply_delete(&self, key: &[u8], seq: u64) -> Result<(), DatabaseError> {
+        let Some(metadata) = self.index.get(key) else {
+            return Ok(());
+        };
+        let location = metadata.location;
+        // The entry itself becomes the tombstone
+        let stale_versions = metadata.stale_versions;
+
+        if !self.page_manager.delete(&location, key, seq)? {
+            error!(
+                "Entry for key '{}' not found at {:?}",
+                String::from_utf8_lossy(key),
+                location
+            );
+            return Err(DatabaseError::InvalidData);
+        }
+        debug!(
+            "Deleted key '{}' at location {:?}",
+            String::from_utf8_lossy(key),
+            location
+        );
+        let mut shard = self.index.write(key);
+        if let Some(metadata) = shard.entries.remove(key) {
+            self.stats.lock().unwrap().freq_bytes.remove(&metadata);
+        }
+        // Under the shard lock, so that a read counted late doesn't bring it back
+        self.hotness.lock().unwrap().remove(key);
+        shard.tombstones.insert(
+            key.to_vec(),
+            Tombstone {
+                location,
+                seq,
+                stale_versions,
+            },
+        );
+        drop(shard);
+        self.remove_object_metrics(location.page_id, key);
+        Ok(())
+    }
+
+    /// Drop an object from the page metrics used for visualization
+    fn remove_object_metrics(&self, page_id: u64, key: &[u8]) {
+        if let Some(objects) = self.page_objects.lock().unwrap().get_mut(&page_id) {
+            let key_str = String::from_utf8_lossy(key);
+            objects.retain(|object| object.key != key_str);
+        }
+    }
+
+    /// Apply all puts and deletes of `batch` atomically: after a crash either
+    /// all of them or none are visible. The entries are packed into pages
+    /// together and every touched page is written once. Deleting a missing
+    /// key is not an error within a batch.
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<(), DatabaseError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // Refuse entries that fit in no page before anything is logged or written
+        let max_entry_size = Page::new(0, DEFAULT_PAGE_SIZE).free_space() as usize;
+        let too_large = batch.ops.iter().any(|op| match op {
+            BatchOp::Put { key, value } => Page::entry_size(key, value) > max_entry_size,
+            BatchOp::Delete { key } => Page::entry_size(key, &[]) > max_entry_size,
+        });
+        if too_large {
+            return Err(DatabaseError::StorageFull);
+        }
+
+        let first_seq = *self.next_seq.get_mut();
+        let records: Vec<WalRecord> = batch
+            .ops
+            .iter()
+            .zip(first_seq..)
+            .map(|(op, seq)| match op {
+                BatchOp::Put { key, value } => WalRecord::Set {
+                    seq,
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                BatchOp::Delete { key } => WalRecord::Delete {
+                    seq,
+                    key: key.clone(),
+                },
+            })
+            .collect();
+        // Sequence numbers of a failed batch are not reused
+        let next_seq = first_seq + records.len() as u64;
+        *self.next_seq.get_mut() = next_seq;
+
+        // Collect the touched pages to write them once the whole batch is in
+        let defer_writes = self.page_manager.defer_writes;
+        self.page_manager.defer_writes = true;
+        let mut undo = Vec::with_capacity(records.len());
+        let result = self.apply_batch(&records, &mut undo);
+        self.page_manager.defer_writes = defer_writes;
+
+        let pending_watermark = self.page_manager.state_mut().pending_batch_watermark;
+        if let Err(e) = result.and_then(|()| self.commit_batch(&records, next_seq)) {
+            self.revert_batch(first_seq..next_seq, undo, pending_watermark);
+            return Err(e);
+        }
+        self.maybe_adapt_threshold();
+        self.maintain()
+    }
+
+    /// Log a batch applied in memory and commit it. It is only logged once
+    /// all of it fits, so that a failed batch leaves nothing to replay.
+    fn commit_batch(&mut self, records: &[WalRecord], next_seq: u64) -> Result<(), DatabaseError> {
+        if let Some(wal) = &mut self.wal {
+            wal.get_mut()
+                .unwrap()
+                .append_batch(records)
+                .map_err(DatabaseError::Wal)?;
+        }
+        self.page_manager.commit_batches(next_seq);
+        if self.wal.is_none() {
+            self.page_manager.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Undo the operations of a batch that failed, newest first, and remove
+    /// its entries from their pages
+    fn revert_batch(
+        &mut self,
+        seqs: Range<u64>,
+        undo: Vec<BatchUndo>,
+        pending_watermark: Option<u64>,
+    ) {
+        warn!(
+            "Reverting the {} applied operations of batch {:?}",
+            undo.len(),
+            seqs
+        );
+        self.page_manager.state_mut().pending_batch_watermark = pending_watermark;
+        let page_ids = undo.iter().map(|op| op.location.page_id).collect();
+        if let Err(e) = self.page_manager.revert_batch(&page_ids, &seqs) {
+            error!("Failed to remove the entries of batch {:?}: {:?}", seqs, e);
+        }
+
+        for op in undo.into_iter().rev() {
+            self.remove_object_metrics(op.location.page_id, &op.key);
+            let freq_bytes = &mut self.stats.get_mut().unwrap().freq_bytes;
+            if let Some(metadata) = self.index.get(&op.key) {
+                freq_bytes.remove(&metadata);
+            }
+            match op.index {
+                Some(old) => {
+                    self.page_manager
+                        .mark_live(&old.location, ENTRY_METADATA_SIZE + old.size as usize);
+                    self.index.insert(op.key.clone(), old);
+                    freq_bytes.add(&old);
+                    self.update_page_metrics(&op.key, &old);
+                }
+                None => {
+                    self.index.remove(&op.key);
+                }
+            }
+            match op.tombstone {
+                Some(tombstone) => {
+                    self.page_manager
+                        .mark_live(&tombstone.location, ENTRY_METADATA_SIZE + op.key.len());
+                    self.index
+                        .shard_mut(&op.key)
+                        .tombstones
+                        .insert(op.key, tombstone);
+                }
+                None => {
+                    self.index.shard_mut(&op.key).tombstones.remove(&op.key);
+                }
+            }
+        }
+
+        // Nothing else writes the reverted pages back without a WAL
+        if self.wal.is_none() {
+            if let Err(e) = self.page_manager.flush() {
+                warn!("Failed to write the reverted pages: {:?}", e);
+            }
+        }
+    }
+
+    /// Write the entries of a batch marked as such, so that they are dropped
+    /// on recovery unless the batch was committed. Every operation applied
+    /// is recorded in `undo`.
+    fn apply_batch(
+        &mut self,
+        records: &[WalRecord],
+        undo: &mut Vec<BatchUndo>,
+    ) -> Result<(), DatabaseError> {
+        for record in records {
+            let index = self.index.get(record.key());
+            let tombstone = self.index.tombstone(record.key());
+            match record {
+                WalRecord::Set { seq, key, value } => {
+                    let hotness = self.update_write_hotness(key);
+                    self.apply_set(key, value, *seq, ENTRY_FLAG_BATCH, hotness)?;
+                    undo.push(BatchUndo {
+                        key: key.clone(),
+                        location: self.index.get(key).unwrap().location,
+                        index,
+                        tombstone,
+                    });
+                }
+                WalRecord::Delete { seq, key } => {
+                    let Some(old) = index else {
+                        continue;
+                    };
+                    // Unlike `apply_delete`, a new tombstone is written so that
+                    // the old entry survives if the batch is not committed
+                    let location = self
+                        .page_manager
+                        .set(
+                            key,
+                            &[],
+                            *seq,
+                            ENTRY_FLAG_TOMBSTONE | ENTRY_FLAG_BATCH,
+                            false,
+                        )?
+                        .ok_or(DatabaseError::StorageFull)?;
+                    self.index.remove(key);
+                    self.stats.get_mut().unwrap().freq_bytes.remove(&old);
+                    self.hotness.get_mut().unwrap().remove(key);
+                    self.page_manager
+                        .mark_dead(&old.location, ENTRY_METADATA_SIZE + old.size as usize);
+                    self.index.shard_mut(key).tombstones.insert(
+                        key.clone(),
+                        Tombstone {
+                            location,
+                            seq: *seq,
+                            stale_versions: old.stale_versions + 1,
+                        },
+                    );
+                    self.remove_object_metrics(old.location.page_id, key);
+                    undo.push(BatchUndo {
+                        key: key.clone(),
+                        location,
+                        index,
+                        tombstone,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect pages whose dead bytes exceed the ratio of the GC config, or of
+    /// the default config when automatic collection is disabled.
+    /// Returns the number of pages freed.
+    pub fn gc(&mut self) -> Result<usize, DatabaseError> {
+        let config = self.gc.unwrap_or_default();
+        self.collect_garbage(&config)
+    }
+
+    fn maybe_gc(&mut self) -> Result<(), DatabaseError> {
+        if let Some(config) = self.gc {
+            if self.page_manager.dead_ratio() >= config.dead_ratio {
+                self.collect_garbage(&config)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the live entries off the victim pages, then empty the victims
+    fn collect_garbage(&mut self, config: &GcConfig) -> Result<usize, DatabaseError> {
+        let victims = self.page_manager.gc_victims(config);
+        if victims.is_empty() {
+            return Ok(0);
+        }
+        debug!("Collecting pages {:?}", victims);
+
+        // Read the live entries of every victim before relocating anything,
+        // so that no victim receives entries of another
+        let mut live_entries = Vec::new();
+        // Keys of the older versions that go away with the victims
+        let mut stale_keys = Vec::new();
+        for &page_id in &victims {
+            let page_rc = self.page_manager.ensure_page_loaded(page_id)?;
+            for (page_index, entry) in page_rc.read().unwrap().iter().enumerate() {
+                let location = Location {
+                    page_id,
+                    page_index,
+                };
+                let shard = self.index.shard_mut(entry.key());
+                let is_live = if entry.is_tombstone() {
+                    shard
+                        .tombstones
+                        .get(entry.key())
+                        .is_some_and(|tombstone| tombstone.location == location)
+                } else {
+                    shard
+                        .entries
+                        .get(entry.key())
+                        .is_some_and(|metadata| metadata.location == location)
                 };
-                self.index.insert(key.to_vec(), metadata);
+                if !is_live {
+                    stale_keys.push(entry.key().to_vec());
+                } else if entry.is_tombstone() && shard.tombstones[entry.key()].stale_versions == 0
+                {
+                    // Nothing left to shadow, the tombstone goes with the page
+                    shard.tombstones.remove(entry.key());
+                    self.gc_stats.tombstones_dropped += 1;
+                } else {
+                    live_entries.push(LiveEntry {
+                        key: entry.key().to_vec(),
+                        value: entry.value().to_vec(),
+                        seq: entry.seq(),
+                        flags: entry.flags(),
+                        location,
+                    });
+                }
+            }
+        }
+        let taken: HashMap<u64, (bool, usize)> = victims
+            .iter()
+            .map(|&page_id| (page_id, self.page_manager.take_page(page_id)))
+            .collect();
+
+        // Originals of the entries relocated so far and their sizes
+        let mut relocated = Vec::new();
+        if let Err(e) = self.relocate_entries(live_entries, &taken, &mut relocated) {
+            self.return_victims(&taken, &relocated);
+            return Err(e);
+        }
+        for &page_id in &victims {
+            if let Err(e) = self.page_manager.free_page(page_id) {
+                self.return_victims(&taken, &relocated);
+                return Err(e.into());
+            }
+            self.page_objects.get_mut().unwrap().remove(&page_id);
+        }
+        for key in stale_keys {
+            self.forget_stale_version(&key);
+        }
+
+        self.gc_stats.runs += 1;
+        self.gc_stats.pages_collected += victims.len() as u64;
+        info!("Garbage collection freed {} pages", victims.len());
+        Ok(victims.len())
+    }
+
+    /// Copy the live entries of garbage collection victims to other pages
+    /// and make the copies durable, recording the original of every entry
+    /// copied in `relocated`
+    fn relocate_entries(
+        &mut self,
+        live_entries: Vec<LiveEntry>,
+        taken: &HashMap<u64, (bool, usize)>,
+        relocated: &mut Vec<(Location, usize)>,
+    ) -> Result<(), DatabaseError> {
+        for LiveEntry {
+            key,
+            value,
+            seq,
+            flags,
+            location: original,
+        } in live_entries
+        {
+            let (is_hot, _) = taken[&original.page_id];
+            let location = self
+                .page_manager
+                .set(&key, &value, seq, flags, is_hot)?
+                .ok_or(DatabaseError::StorageFull)?;
+            let bytes = Page::entry_size(&key, &value);
+            relocated.push((original, bytes));
+            self.gc_stats.entries_relocated += 1;
+            self.gc_stats.bytes_relocated += bytes as u64;
+
+            if flags & ENTRY_FLAG_TOMBSTONE != 0 {
+                let shard = self.index.shard_mut(&key);
+                shard.tombstones.get_mut(&key).unwrap().location = location;
+            } else {
+                let metadata = self.index.get_mut(&key).unwrap();
+                metadata.location = location;
+                let metadata = *metadata;
+                self.update_page_metrics(&key, &metadata);
+            }
+        }
+
+        // The relocated copies must be durable before the originals are gone
+        self.page_manager.sync_all()?;
+        Ok(())
+    }
+
+    /// Undo `take_page` for the victims of a garbage collection that failed
+    /// and are still there. Their entries relocated already are dead copies
+    /// now, so that a later collection picks the victims again.
+    fn return_victims(
+        &mut self,
+        taken: &HashMap<u64, (bool, usize)>,
+        relocated: &[(Location, usize)],
+    ) {
+        let state = self.page_manager.state_mut();
+        for (original, bytes) in relocated {
+            state.mark_dead(original, *bytes);
+        }
+        for (&page_id, &(_, free_space)) in taken {
+            if state.pages.contains_key(&page_id) {
+                state.set_free_space(page_id, free_space);
+            }
+        }
+    }
+
+    /// Count an older version of key as gone from the device. A tombstone
+    /// with no older version left is dropped, its entry becomes dead bytes.
+    fn forget_stale_version(&mut self, key: &[u8]) {
+        if let Some(metadata) = self.index.get_mut(key) {
+            metadata.stale_versions = metadata.stale_versions.saturating_sub(1);
+            return;
+        }
+        let tombstones = &mut self.index.shard_mut(key).tombstones;
+        let Some(tombstone) = tombstones.get_mut(key) else {
+            return;
+        };
+        tombstone.stale_versions = tombstone.stale_versions.saturating_sub(1);
+        if tombstone.stale_versions == 0 {
+            let location = tombstone.location;
+            tombstones.remove(key);
+            self.page_manager
+                .state_mut()
+                .mark_dead(&location, ENTRY_METADATA_SIZE + key.len());
+            self.gc_stats.tombstones_dropped += 1;
+        }
+    }
+
+    /// Get the garbage collection counters
+    pub fn gc_stats(&self) -> &GcStats {
+        &self.gc_stats
+    }
+
+    /// Whether the entry of key sits on a page of the other temperature
+    /// than `freq` asks for. Always false with migration disabled.
+    fn is_misplaced(&self, key: &[u8], freq: f64) -> bool {
+        let Some(metadata) = self.index.get(key) else {
+            return false;
+        };
+        self.migration.is_some()
+            && self.page_manager.is_hot_page(metadata.location.page_id) != self.is_hot(freq)
+    }
+
+    /// Move the entry of a misplaced key to a page of the right temperature
+    /// if the rate allows, otherwise queue it. Returns whether it moved.
+    fn migrate_entry(
+        &mut self,
+        key: &[u8],
+        config: &MigrationConfig,
+    ) -> Result<bool, DatabaseError> {
+        let now = self.clock().now();
+        if !self.migration_limiter.try_take(config, now) {
+            self.migration_stats.throttled += 1;
+            self.migration_queue.get_mut().unwrap().insert(key.to_vec());
+            return Ok(false);
+        }
+
+        let metadata = self.index.get_mut(key).unwrap();
+        let is_hot = !self
+            .page_manager
+            .state_mut()
+            .is_hot_page(metadata.location.page_id);
+        let Some((location, bytes)) = self.page_manager.migrate(&metadata.location, key, is_hot)?
+        else {
+            return Err(DatabaseError::StorageFull);
+        };
+        debug!(
+            "Migrated key '{}' from {:?} to {:?}, hot: {}",
+            String::from_utf8_lossy(key),
+            metadata.location,
+            location,
+            is_hot
+        );
+        metadata.location = location;
+        // The original stays behind until its page is collected
+        metadata.stale_versions += 1;
+        let metadata = *metadata;
+        self.update_page_metrics(key, &metadata);
+
+        if is_hot {
+            self.migration_stats.promotions += 1;
+        } else {
+            self.migration_stats.demotions += 1;
+        }
+        self.migration_stats.bytes_migrated += bytes as u64;
+        Ok(true)
+    }
+
+    /// Migrate the queued keys that are still misplaced, as far as the rate
+    /// allows. A key that fails to move stays queued.
+    fn migrate_queued(&mut self, config: &MigrationConfig) -> Result<usize, DatabaseError> {
+        let mut migrated = 0;
+        let now = self.clock().now();
+        while let Some(key) = self.migration_queue.get_mut().unwrap().pop_first() {
+            if !self.is_misplaced(&key, self.frequency(&key, now)) {
+                continue;
+            }
+            match self.migrate_entry(&key, config) {
+                Ok(true) => migrated += 1,
+                Ok(false) => break,
+                Err(e) => {
+                    self.migration_queue.get_mut().unwrap().insert(key);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Migrate queued keys after a write. The write itself succeeded, so a
+    /// failure is only logged and the key waits for the next write.
+    fn maybe_migrate(&mut self) {
+        if let Some(config) = self.migration {
+            if !self.migration_queue.get_mut().unwrap().is_empty() {
+                if let Err(e) = self.migrate_queued(&config) {
+                    warn!("Failed to migrate queued entries: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Move entries whose classification changed to pages of the right
+    /// temperature: the queued ones first, then misplaced ones among the next
+    /// `MigrationConfig::sweep` entries of the index, as far as the rate
+    /// allows. Returns the number of entries moved, 0 with migration disabled.
+    pub fn migrate(&mut self) -> Result<usize, DatabaseError> {
+        let Some(config) = self.migration else {
+            return Ok(0);
+        };
+        let mut migrated = self.migrate_queued(&config)?;
+
+        // Sweep from the cursor, wrapping around at the end of the index
+        let now = self.clock().now();
+        let cursor = std::mem::take(&mut self.migration_cursor);
+        let mut keys: Vec<Vec<u8>> = self
+            .index
+            .range(cursor.clone()..)
+            .map(|(key, _)| key.clone())
+            .take(config.sweep)
+            .collect();
+        let wrapped = config.sweep - keys.len();
+        keys.extend(
+            self.index
+                .range(..cursor)
+                .map(|(key, _)| key.clone())
+                .take(wrapped),
+        );
+        let misplaced: Vec<&Vec<u8>> = keys
+            .iter()
+            .filter(|key| self.is_misplaced(key, self.frequency(key, now)))
+            .collect();
+        if let Some(last) = keys.last() {
+            // The smallest key after the last one checked
+            self.migration_cursor = last.clone();
+            self.migration_cursor.push(0);
+        }
+
+        for key in misplaced {
+            if !self.migrate_entry(key, &config)? {
+                break;
+            }
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!("Migrated {} entries between hot and cold pages", migrated);
+        }
+        Ok(migrated)
+    }
+
+    /// Get the hot/cold migration counters
+    pub fn migration_stats(&self) -> &MigrationStats {
+        &self.migration_stats
+    }
+
+    /// Make all writes so far durable by committing the pending WAL group.
+    /// Does nothing when the WAL is disabled.
+    pub fn flush(&mut self) -> Result<(), DatabaseError> {
+        if let Some(wal) = &mut self.wal {
+            wal.get_mut()
+                .unwrap()
+                .commit()
+                .map_err(DatabaseError::Wal)?;
+        }
+        Ok(())
+    }
+
+    /// Load the pages most likely to be read into the page cache, reading
+    /// them from the device in batches. Returns the number of pages loaded.
+    pub fn warm_cache(&mut self) -> Result<usize, DatabaseError> {
+        Ok(self.page_manager.warm_cache()?)
+    }
+
+    /// Write all dirty pages to the data file and truncate the WAL
+    pub fn checkpoint(&mut self) -> Result<(), DatabaseError> {
+        if let Some(wal) = &mut self.wal {
+            self.page_manager.flush()?;
+            wal.get_mut()
+                .unwrap()
+                .truncate()
+                .map_err(DatabaseError::Wal)?;
+            info!("Checkpoint complete");
+        }
+        Ok(())
+    }
+
+    fn maybe_checkpoint(&mut self) -> Result<(), DatabaseError> {
+        if self
+            .wal
+            .as_mut()
+            .is_some_and(|wal| wal.get_mut().unwrap().needs_checkpoint())
+        {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Read value for key
+    pub fn get(&mut self, key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let value = self.read_value(key)?;
+        if !self.count_access(key) {
+            return Ok(value);
+        }
+        match self.migration {
+            Some(config) if config.on_read => {
+                // The value was read, moving the entry may fail and
+                // wait for the next write
+                if let Err(e) = self.migrate_entry(key, &config) {
+                    warn!(
+                        "Failed to migrate key '{}': {:?}",
+                        String::from_utf8_lossy(key),
+                        e
+                    );
+                    self.migration_queue.get_mut().unwrap().insert(key.to_vec());
+                }
+            }
+            _ => {
+                self.migration_queue.get_mut().unwrap().insert(key.to_vec());
+            }
+        }
+        Ok(value)
+    }
+
+    /// Read the value of key through a shared reference, so that several
+    /// threads can read at once. Unlike `get`, the access is not counted,
+    /// callers report it with `record_access` later.
+    pub(crate) fn read_value(&self, key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        loop {
+            let location = self
+                .index
+                .get(key)
+                .ok_or(DatabaseError::KeyNotFound)?
+                .location;
+            let page_rc = self.page_manager.load_page(location.page_id)?;
+            if let Some(value) = page_rc.read().unwrap().get(location.page_index, key) {
+                return Ok(value);
+            }
+            self.check_moved(key, &location)?;
+        }
+    }
+
+    /// Check that key was written or deleted since its entry was looked up
+    /// at `location` and found missing, so that the lookup can be repeated
+    fn check_moved(&self, key: &[u8], location: &Location) -> Result<(), DatabaseError> {
+        match self.index.get(key) {
+            None => Err(DatabaseError::KeyNotFound),
+            Some(metadata) if metadata.location != *location => Ok(()),
+            Some(_) => Err(DatabaseError::InvalidData),
+        }
+    }
+
+    /// Like `read_value`, but returns the page to read from the device instead
+    /// of reading it when it is not in memory. Once read, the caller passes
+    /// it to `read_value_from`.
+    pub(crate) fn read_value_or_miss(
+        &self,
+        key: &[u8],
+    ) -> Result<Result<Vec<u8>, PageMiss>, DatabaseError> {
+        let location = self
+            .index
+            .get(key)
+            .ok_or(DatabaseError::KeyNotFound)?
+            .location;
+        match self.page_manager.cached_page_or_miss(location.page_id) {
+            Ok(page_rc) => {
+                let value = page_rc.read().unwrap().get(location.page_index, key);
+                match value {
+                    Some(value) => Ok(Ok(value)),
+                    None => {
+                        self.check_moved(key, &location)?;
+                        self.read_value(key).map(Ok)
+                    }
+                }
+            }
+            Err(miss) => Ok(Err(miss)),
+        }
+    }
+
+    /// Finish a read that missed in `read_value_or_miss` with the page the
+    /// caller read from the device. Falls back to `read_value` if the key
+    /// moved or the page changed in the meantime.
+    pub(crate) fn read_value_from(
+        &self,
+        key: &[u8],
+        miss: &PageMiss,
+        page: Page,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        let location = self
+            .index
+            .get(key)
+            .ok_or(DatabaseError::KeyNotFound)?
+            .location;
+        let installed = if location.page_id == miss.page_id {
+            self.page_manager.install_page(miss, page)
+        } else {
+            None
+        };
+        let page_rc = match installed {
+            Some(page_rc) => page_rc,
+            None => self.page_manager.load_page(location.page_id)?,
+        };
+        let value = page_rc.read().unwrap().get(location.page_index, key);
+        match value {
+            Some(value) => Ok(value),
+            None => {
+                self.check_moved(key, &location)?;
+                self.read_value(key)
+            }
+        }
+    }
+
+    /// The page a write of key would most likely go to, if it has to be
+    /// read from the device first
+    pub(crate) fn write_page_miss(&self, key: &[u8], value: &[u8]) -> Option<PageMiss> {
+        // Frequency the write would be counted at, without counting it
+        let freq = self.frequency(key, self.clock().now()) + 1.0;
+        let is_hot = self.index.contains_key(key) && self.is_hot(freq);
+        let required_space = Page::entry_size(key, value);
+        let page_id = self
+            .page_manager
+            .find_suitable_page_id(required_space, is_hot)?;
+        self.page_manager.cached_page_or_miss(page_id).err()
+    }
+
+    /// Add a page the caller read from the device for `miss` to the cache
+    pub(crate) fn install_page(&self, miss: &PageMiss, page: Page) {
+        self.page_manager.install_page(miss, page);
+    }
+
+    /// Set key-value pair like `set`, but leave writing the modified pages to
+    /// the caller, who reports back with `finish_page_writes`. With the WAL
+    /// enabled, pages are written at checkpoints as usual and none are returned.
+    pub(crate) fn set_deferred(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Vec<PageWrite>, DatabaseError> {
+        if self.wal.is_some() {
+            self.set(key, value)?;
+            return Ok(Vec::new());
+        }
+
+        let defer_writes = self.page_manager.defer_writes;
+        self.page_manager.defer_writes = true;
+        let result = self.set(key, value);
+        self.page_manager.defer_writes = defer_writes;
+        if let Err(e) = result {
+            // Nobody would write the pages touched before the failure
+            self.page_manager.flush()?;
+            return Err(e);
+        }
+        Ok(self.page_manager.take_page_writes()?)
+    }
+
+    /// Account the page writes returned by `set_deferred` once they completed
+    pub(crate) fn finish_page_writes(
+        &mut self,
+        writes: &[PageWrite],
+        succeeded: bool,
+        elapsed_nanos: u64,
+    ) {
+        self.page_manager
+            .finish_page_writes(writes, succeeded, elapsed_nanos);
+    }
 
-                // Update page metrics for visualization
-                self.update_page_metrics(&key.to_vec(), &metadata);
+    /// Use `uring` to order page writes after the writes returned by
+    /// `set_deferred`, which must be issued through it
+    pub(crate) fn set_uring(&mut self, uring: Rio) {
+        self.page_manager.uring = Some(uring);
+    }
 
-                Ok(())
-            }
-            None => {
-                error!(
-                    "Failed to allocate space for key '{}'",
-                    String::from_utf8_lossy(key)
-                );
-                Err(DatabaseError::StorageFull)
-            }
-        }
+    /// The ring the store issues page I/O through, with `IoBackend::IoUring`
+    pub(crate) fn store_uring(&self) -> Option<Rio> {
+        self.page_manager.store.uring().cloned()
     }
 
-    /// Read value for key
-    pub fn get(&mut self, key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
-        if let Some(metadata) = self.index.get_mut(key) {
-            let is_hot = metadata.update_hotness(self.hot_threshold);
-            let location = metadata.location;
-            let metadata_copy = *metadata;
+    /// Open another handle to the file pages are stored in, e.g. to read
+    /// pages through io_uring. None for stores that are not a file.
+    pub(crate) fn try_clone_data_file(&self) -> io::Result<Option<std::fs::File>> {
+        self.page_manager
+            .store
+            .file()
+            .map(|file| file.try_clone())
+            .transpose()
+    }
 
-            // First get the value to avoid multiple mutable borrows
-            let value = self
-                .page_manager
-                .get(&location, key)?
-                .ok_or(DatabaseError::InvalidData)?;
+    /// Page size of the data file
+    pub(crate) fn page_size(&self) -> u32 {
+        self.page_manager.page_size
+    }
 
-            // Then update page metrics after getting the value
-            self.update_page_metrics(&key.to_vec(), &metadata_copy);
+    /// When page writes are synced to stable storage
+    pub(crate) fn durability(&self) -> Durability {
+        self.page_manager.store.durability()
+    }
 
-            Ok(value)
-        } else {
-            Err(DatabaseError::KeyNotFound)
+    /// Count a read of key done with `read_value`, updating its hotness and
+    /// the page metrics like `get` does. Keys deleted since are ignored. A
+    /// key found on a page of the wrong temperature waits for `maintain`.
+    pub(crate) fn record_access(&self, key: &[u8]) {
+        if self.count_access(key) {
+            self.migration_queue.lock().unwrap().insert(key.to_vec());
         }
     }
 
+    /// Count a read of key with the hotness policy. Returns whether the
+    /// entry of key is misplaced now, see `is_misplaced`.
+    fn count_access(&self, key: &[u8]) -> bool {
+        let now = self.clock().now();
+        let metadata = {
+            let mut shard = self.index.write(key);
+            let Some(metadata) = shard.entries.get_mut(key) else {
+                return false;
+            };
+            let freq = self
+                .hotness
+                .lock()
+                .unwrap()
+                .record_access(key, Some(metadata.last()), now);
+            let mut stats = self.stats.lock().unwrap();
+            stats.accessed(metadata, freq, now);
+            stats.record_frequency(freq);
+            *metadata
+        };
+        self.maybe_adapt_threshold();
+        self.page_manager.touch_page(metadata.location.page_id);
+        self.update_page_metrics(key, &metadata);
+        self.is_misplaced(key, metadata.freq_accessed)
+    }
+
     /// Update page metrics for visualization
-    fn update_page_metrics(&mut self, key: &[u8], metadata: &ObjectMetadata) {
-        // Get the latest page metrics from PageManager
-        let page_metrics = self.page_manager.get_page_metrics();
-
-        // Update our page_metrics with the latest data
-        for (page_id, metrics) in page_metrics {
-            self.page_metrics.insert(page_id, metrics);
-        }
-
-        // Update object metrics in the page
-        if let Some(page_metrics) = self.page_metrics.get_mut(&metadata.location.page_id) {
-            // Try to find existing object metrics
-            let key_str = String::from_utf8_lossy(key).to_string();
-            let mut found = false;
-
-            for obj_metrics in &mut page_metrics.objects {
-                if obj_metrics.key == key_str {
-                    // Update existing object metrics
-                    obj_metrics.freq = metadata.freq_accessed;
-                    obj_metrics.last_access = metadata.last_access;
-                    found = true;
-                    break;
-                }
-            }
+    fn update_page_metrics(&self, key: &[u8], metadata: &ObjectMetadata) {
+        let mut page_objects = self.page_objects.lock().unwrap();
+        let objects = page_objects.entry(metadata.location.page_id).or_default();
 
-            if !found {
+        // Try to find existing object metrics
+        let key_str = String::from_utf8_lossy(key).to_string();
+        match objects.iter_mut().find(|object| object.key == key_str) {
+            Some(object) => {
+                // Update existing object metrics
+                object.freq = metadata.freq_accessed;
+                object.last_access = metadata.last_access;
+            }
+            None => {
                 // Add new object metrics
-                page_metrics.objects.push(ObjectMetrics {
+                objects.push(ObjectMetrics {
                     key: key_str,
                     freq: metadata.freq_accessed,
                     size: metadata.size,
@@ -508,9 +3052,34 @@ impl Database {
         }
     }
 
+    /// Iterate over the key/value pairs in `range` in key order.
+    /// Unlike `get`, scanned keys are not counted as accesses for hotness.
+    pub fn scan<T, R>(&mut self, range: R) -> Scan<'_>
+    where
+        T: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<T>,
+        R: RangeBounds<T>,
+    {
+        Scan {
+            range: self.index.range(range),
+            page_manager: &mut self.page_manager,
+            buffered: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Iterate over the key/value pairs whose key starts with `prefix` in key order
+    pub fn scan_prefix(&mut self, prefix: &[u8]) -> Scan<'_> {
+        let end = match prefix_upper_bound(prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan((Bound::Included(prefix.to_vec()), end))
+    }
+
     /// Return all keys (sorted)
     pub fn keys(&self) -> Vec<Vec<u8>> {
-        self.index.keys().cloned().collect()
+        self.index.keys()
     }
 
     /// Number of keys in database
@@ -525,44 +3094,65 @@ impl Database {
 
     /// Get the SSD device metrics
     pub fn metrics(&self) -> &SsdMetrics {
-        self.page_manager.device.metrics()
+        self.page_manager.store.metrics()
+    }
+
+    /// Get a copy of the frequency histogram
+    pub fn freq_histogram(&self) -> Histogram<u64> {
+        self.stats.lock().unwrap().freq_histogram.clone()
     }
 
-    /// Get the frequency histogram
-    pub fn freq_histogram(&self) -> &Histogram<u64> {
-        &self.freq_histogram
+    /// Page cache hits and misses of the eviction policy in use, followed by
+    /// those of the simulated ones, see `CacheConfig::shadow_policies`
+    pub fn cache_stats(&self) -> Vec<PolicyStats> {
+        self.page_manager.page_cache.policy_stats()
     }
 
     pub fn hit_ratio(&self) -> f64 {
-        info!(
-            "Hit count {}, miss count {}",
-            self.page_manager.hit_count, self.page_manager.miss_count
-        );
-        (self.page_manager.hit_count as f64)
-            / (self.page_manager.hit_count as f64 + self.page_manager.miss_count as f64)
+        let hit_count = self.page_manager.page_cache.hits();
+        let miss_count = self.page_manager.page_cache.misses();
+        info!("Hit count {}, miss count {}", hit_count, miss_count);
+        (hit_count as f64) / (hit_count as f64 + miss_count as f64)
     }
 
     /// Get page metrics for visualization
-    pub fn get_page_metrics(&self) -> &HashMap<u64, PageMetrics> {
-        &self.page_metrics
+    pub fn get_page_metrics(&self) -> HashMap<u64, PageMetrics> {
+        let mut page_metrics = self.page_manager.get_page_metrics();
+        let page_objects = self.page_objects.lock().unwrap();
+        for (page_id, metrics) in &mut page_metrics {
+            if let Some(objects) = page_objects.get(page_id) {
+                metrics.objects.clone_from(objects);
+            }
+        }
+        page_metrics
     }
 
     /// Export metrics to a JSON-serializable structure
     pub fn export_metrics(&self) -> serde_json::Value {
-        let mut page_metrics_vec = Vec::new();
-        for (_, metrics) in &self.page_metrics {
-            page_metrics_vec.push(metrics.clone());
-        }
+        let page_metrics_vec: Vec<PageMetrics> = self.get_page_metrics().into_values().collect();
+        let freq_histogram = self.freq_histogram();
+        let threshold_updates = self.stats.lock().unwrap().threshold_updates;
+        let (dead_bytes, free_pages) = {
+            let state = self.page_manager.state.lock().unwrap();
+            (state.dead_bytes, state.free_pages.len())
+        };
 
         serde_json::json!({
-            "timestamp": SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            "hot_threshold": self.hot_threshold,
+            // Nanoseconds like the access times, see `Clock`
+            "timestamp": self.clock().now_nanos(),
+            "hot_threshold": self.hot_threshold(),
+            "adaptive_threshold": self.adaptive_threshold.is_some(),
+            "threshold_updates": threshold_updates,
+            "hotness_policy": self.hotness_policy().name(),
             "hit_ratio": self.hit_ratio(),
-            "total_pages": self.page_metrics.len(),
+            "cache": {
+                "policy": self.page_manager.page_cache.policy(),
+                "capacity_pages": self.page_manager.page_cache.capacity(),
+                "policies": self.cache_stats(),
+            },
+            "total_pages": page_metrics_vec.len(),
             "total_objects": self.index.len(),
+            "tombstones": self.index.tombstone_count(),
             "ssd_metrics": {
                 "reads": self.metrics().reads(),
                 "writes": self.metrics().writes(),
@@ -570,14 +3160,1028 @@ impl Database {
                 "read_latency_p95": self.metrics().read_latency_percentile(95.0),
                 "write_latency_p50": self.metrics().write_latency_percentile(50.0),
                 "write_latency_p95": self.metrics().write_latency_percentile(95.0),
+                "discards": self.metrics().discards(),
+                "syncs": self.metrics().syncs(),
+                "sync_latency_p50": self.metrics().sync_latency_percentile(50.0),
+                "sync_latency_p95": self.metrics().sync_latency_percentile(95.0),
             },
+            "dead_bytes": dead_bytes,
+            "free_pages": free_pages,
+            "gc": self.gc_stats,
+            "migration": self.migration_stats,
             "freq_histogram": {
-                "p50": self.freq_histogram().value_at_percentile(50.0),
-                "p95": self.freq_histogram().value_at_percentile(95.0),
-                "p99": self.freq_histogram().value_at_percentile(99.0),
-                "max": self.freq_histogram().max(),
+                "p50": freq_histogram.value_at_percentile(50.0),
+                "p95": freq_histogram.value_at_percentile(95.0),
+                "p99": freq_histogram.value_at_percentile(99.0),
+                "max": freq_histogram.max(),
             },
             "pages": page_metrics_vec,
         })
     }
 }
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if let Err(e) = self.checkpoint() {
+            error!("Failed to checkpoint on close: {:?}", e);
+        }
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, None if there is none
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Iterator over a range of the database returned by `Database::scan`.
+/// Index entries are resolved in batches, loading each page once for all
+/// the entries of the batch it holds.
+pub struct Scan<'a> {
+    range: index::Range<'a>,
+    page_manager: &'a mut PageManager,
+    buffered: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Stop after reporting an error
+    failed: bool,
+}
+
+impl Scan<'_> {
+    /// Read the values of the next batch of index entries
+    fn fill(&mut self) -> Result<(), DatabaseError> {
+        let batch: Vec<(&Vec<u8>, Location)> = self
+            .range
+            .by_ref()
+            .take(SCAN_BATCH_SIZE)
+            .map(|(key, metadata)| (key, metadata.location))
+            .collect();
+
+        // Load the pages of the batch together, the cache may not hold all
+        // of them at once so they are kept here
+        let mut page_ids: Vec<u64> = batch.iter().map(|(_, location)| location.page_id).collect();
+        page_ids.sort_unstable();
+        page_ids.dedup();
+        let pages = self.page_manager.ensure_pages_loaded(&page_ids)?;
+
+        let mut values = Vec::with_capacity(batch.len());
+        for &(key, location) in &batch {
+            let i = page_ids.binary_search(&location.page_id).unwrap();
+            let value = pages[i]
+                .read()
+                .unwrap()
+                .get(location.page_index, key)
+                .ok_or(DatabaseError::InvalidData)?;
+            values.push(value);
+        }
+
+        self.buffered.extend(
+            batch
+                .into_iter()
+                .zip(values)
+                .map(|((key, _), value)| (key.clone(), value)),
+        );
+        Ok(())
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.buffered.is_empty() {
+            if let Err(e) = self.fill() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffered.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::LogicalClock;
+    use crate::storage::fault::{FaultInjector, FaultyStore, ReadFault};
+    use crate::storage::manifest::MANIFEST_REGION_SIZE;
+    use crate::storage::memory::MemoryStore;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reopen_rebuilds_index() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("reopen.db");
+
+        {
+            let mut db = Database::new(&path, 3).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            db.set(b"key2", b"value2").unwrap();
+            db.set(b"key1", b"value3").unwrap();
+        }
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(b"key1").unwrap(), b"value3");
+        assert_eq!(db.get(b"key2").unwrap(), b"value2");
+
+        // New writes must not clobber the recovered pages
+        db.set(b"key3", b"value4").unwrap();
+        drop(db);
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get(b"key1").unwrap(), b"value3");
+        assert_eq!(db.get(b"key3").unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_recovery_rejects_garbage_pages() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("garbage.db");
+        {
+            let mut db = Database::new(&path, 3).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+        }
+
+        // Overwrite the header of page 0, as a misdirected write would
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&[0xab; 64], MANIFEST_REGION_SIZE)
+            .unwrap();
+        drop(file);
+
+        assert!(matches!(
+            Database::open(&path, 3),
+            Err(DatabaseError::Corruption {
+                page_id: 0,
+                error: CorruptionError::BadMagic,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_reopen_in_memory() {
+        // No WAL, the path is never touched
+        let path = Path::new("in-memory.db");
+        let store = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let options = Options {
+            truncate: true,
+            ..Options::default()
+        };
+        let mut db = Database::open_with_store(path, Box::new(store.reopen()), options).unwrap();
+        for i in 0..400u32 {
+            db.set(&i.to_le_bytes(), &[i as u8; 600]).unwrap();
+        }
+        db.delete(&7u32.to_le_bytes()).unwrap();
+        drop(db);
+
+        let options = Options {
+            truncate: false,
+            ..options
+        };
+        let mut db = Database::open_with_store(path, Box::new(store.reopen()), options).unwrap();
+        assert_eq!(db.len(), 399);
+        assert_eq!(db.get(&399u32.to_le_bytes()).unwrap(), [143; 600]);
+        assert!(matches!(
+            db.get(&7u32.to_le_bytes()),
+            Err(DatabaseError::KeyNotFound)
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wal_replay_after_crash() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.db");
+        let options = Options {
+            wal: Some(WalConfig::default()),
+            ..Options::default()
+        };
+
+        let mut db = Database::open_with_options(&path, options).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        db.set(b"key1", b"value3").unwrap();
+        db.set(b"key1", b"value4").unwrap();
+        db.flush().unwrap();
+        // Pages are only written at checkpoints
+        assert_eq!(db.metrics().writes(), 0);
+        // Simulate a crash: neither the checkpoint nor the page manager drop runs
+        std::mem::forget(db);
+
+        let mut db = Database::open_with_options(&path, options).unwrap();
+        assert_eq!(db.len(), 2);
+        // Replayed writes count towards hotness like the original ones
+        let is_hot_page = |db: &Database, key: &[u8]| {
+            db.page_manager
+                .is_hot_page(db.index.get(key).unwrap().location.page_id)
+        };
+        assert!(is_hot_page(&db, b"key1"));
+        assert!(!is_hot_page(&db, b"key2"));
+        assert_eq!(db.get(b"key1").unwrap(), b"value4");
+        assert_eq!(db.get(b"key2").unwrap(), b"value2");
+        drop(db);
+
+        // The replayed writes were checkpointed into the data file
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_delete_does_not_resurrect() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("delete.db");
+
+        {
+            let mut db = Database::new(&path, 3).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            // Leaves a stale version of key1 on disk
+            db.set(b"key1", b"value2").unwrap();
+            db.set(b"key2", b"value3").unwrap();
+
+            db.delete(b"key1").unwrap();
+            assert!(matches!(db.get(b"key1"), Err(DatabaseError::KeyNotFound)));
+            assert!(matches!(
+                db.delete(b"key1"),
+                Err(DatabaseError::KeyNotFound)
+            ));
+            assert_eq!(db.len(), 1);
+        }
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 1);
+        assert!(matches!(db.get(b"key1"), Err(DatabaseError::KeyNotFound)));
+        assert_eq!(db.get(b"key2").unwrap(), b"value3");
+
+        // A write after reopening outranks the tombstone
+        db.set(b"key1", b"value4").unwrap();
+        drop(db);
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_gc_reclaims_overwritten_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("gc.db");
+        let options = Options {
+            truncate: true,
+            gc: None,
+            ..Options::default()
+        };
+        // Three entries fill most of a page
+        let value = |i: u8| vec![i; 1200];
+
+        let mut db = Database::open_with_options(&path, options).unwrap();
+        for i in 0..8u8 {
+            db.set(&[i], &value(i)).unwrap();
+        }
+        for i in 0..6u8 {
+            db.set(&[i], &value(i + 100)).unwrap();
+        }
+        db.delete(&[7]).unwrap();
+        let next_id = db.page_manager.state_mut().next_id;
+
+        let freed = db.gc().unwrap();
+        assert!(freed >= 2);
+        assert_eq!(db.gc_stats().runs, 1);
+        assert_eq!(db.page_manager.state_mut().free_pages.len(), freed);
+        for i in 0..6u8 {
+            assert_eq!(db.get(&[i]).unwrap(), value(i + 100));
+        }
+        assert_eq!(db.get(&[6]).unwrap(), value(6));
+
+        // New pages are taken from the free list instead of growing the file
+        for i in 8..12u8 {
+            db.set(&[i], &value(i)).unwrap();
+        }
+        assert_eq!(db.page_manager.state_mut().next_id, next_id);
+        let free_pages = db.page_manager.state_mut().free_pages.clone();
+        assert_eq!(free_pages.len(), freed - 1);
+        drop(db);
+
+        // Free pages are listed in the manifest and not read on recovery
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.page_manager.state_mut().free_pages, free_pages);
+        let listed: BTreeSet<u64> = db
+            .page_manager
+            .store
+            .manifest()
+            .free_pages
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(listed, free_pages);
+        assert_eq!(
+            db.metrics().reads(),
+            db.page_manager.state_mut().next_id - free_pages.len() as u64
+        );
+        assert_eq!(db.len(), 11);
+        assert_eq!(db.get(&[0]).unwrap(), value(100));
+        assert!(matches!(db.get(&[7]), Err(DatabaseError::KeyNotFound)));
+
+        // A reused page leaves the list before it is written, even if the
+        // manifest is not written again
+        for i in 12..16u8 {
+            db.set(&[i], &value(i)).unwrap();
+        }
+        assert!(db.page_manager.store.manifest().free_pages.is_empty());
+        std::mem::forget(db);
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 15);
+        assert_eq!(db.get(&[15]).unwrap(), value(15));
+    }
+
+    #[test]
+    fn test_gc_returns_victims_on_failure() {
+        let path = Path::new("gc_failure.db");
+        let memory = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let faults = FaultInjector::new();
+        let options = Options {
+            truncate: true,
+            gc: None,
+            ..Options::default()
+        };
+        let store = FaultyStore::new(memory.reopen(), faults.clone());
+        let mut db = Database::open_with_store(path, Box::new(store), options).unwrap();
+        let value = |i: u8| vec![i; 1200];
+        for i in 0..8u8 {
+            db.set(&[i], &value(i)).unwrap();
+        }
+        // The first two pages keep one live entry each
+        let overwritten = [0u8, 1, 3, 4];
+        for i in overwritten {
+            db.set(&[i], &value(i + 100)).unwrap();
+        }
+        let free_space: HashMap<u64, usize> = db
+            .page_manager
+            .state_mut()
+            .pages
+            .iter()
+            .map(|(&page_id, status)| (page_id, status.free_space))
+            .collect();
+        let dead_bytes = db.page_manager.state_mut().dead_bytes;
+
+        // The second relocated entry fails to be written
+        faults.fail_write(2);
+        assert!(db.gc().is_err());
+        let state = db.page_manager.state_mut();
+        for (page_id, free_space) in free_space {
+            assert_eq!(state.pages[&page_id].free_space, free_space);
+        }
+        // The original of the entry relocated before is dead now
+        assert_eq!(
+            state.dead_bytes,
+            dead_bytes + Page::entry_size(&[2], &value(2))
+        );
+
+        assert!(db.gc().unwrap() >= 2);
+        for i in 0..8u8 {
+            let expected = if overwritten.contains(&i) {
+                value(i + 100)
+            } else {
+                value(i)
+            };
+            assert_eq!(db.get(&[i]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_gc_drops_tombstones_without_older_versions() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("gc_tombstones.db");
+        let options = Options {
+            truncate: true,
+            gc: None,
+            ..Options::default()
+        };
+
+        let mut db = Database::open_with_options(&path, options).unwrap();
+        // The second version doesn't fit next to the first, which is left
+        // dead on a page of its own
+        db.set(b"key", &[1; 3000]).unwrap();
+        db.set(b"key", &[2; 3000]).unwrap();
+        db.delete(b"key").unwrap();
+        assert_eq!(db.index.tombstone(b"key").unwrap().stale_versions, 1);
+
+        // The tombstone outlives the older version, then goes
+        assert_eq!(db.gc().unwrap(), 1);
+        assert_eq!(db.index.tombstone_count(), 0);
+        assert_eq!(db.gc_stats().tombstones_dropped, 1);
+        assert!(matches!(db.get(b"key"), Err(DatabaseError::KeyNotFound)));
+        drop(db);
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert!(matches!(db.get(b"key"), Err(DatabaseError::KeyNotFound)));
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn test_gc_bounds_file_growth() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("gc_auto.db");
+
+        let mut db = Database::new(&path, 3).unwrap();
+        for i in 0..200u32 {
+            db.set(b"key", &i.to_le_bytes().repeat(256)).unwrap();
+        }
+        assert!(db.gc_stats().pages_collected > 0);
+        assert!(db.page_manager.state_mut().pages.len() <= 4);
+        assert_eq!(db.get(b"key").unwrap(), 199u32.to_le_bytes().repeat(256));
+    }
+
+    #[test]
+    fn test_scan_range_and_prefix() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("scan.db");
+        let value = |i: u8| vec![i; 1000];
+
+        {
+            let mut db = Database::new(&path, 3).unwrap();
+            // Insert out of order so that neighbouring keys end up on different pages
+            for i in (0..20u8).rev() {
+                db.set(format!("a{:02}", i).as_bytes(), &value(i)).unwrap();
+            }
+            db.set(b"b", b"other").unwrap();
+        }
+
+        let mut db = Database::open(&path, 3).unwrap();
+        let reads = db.metrics().reads();
+        let pairs: Vec<_> = db
+            .scan(b"a05".to_vec()..b"a15".to_vec())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<_> = (5..15u8)
+            .map(|i| (format!("a{:02}", i).into_bytes(), value(i)))
+            .collect();
+        assert_eq!(pairs, expected);
+        // Three entries per page, the range spans four pages read once each
+        assert_eq!(db.metrics().reads() - reads, 4);
+
+        let keys: Vec<_> = db.scan_prefix(b"a").map(|pair| pair.unwrap().0).collect();
+        assert_eq!(keys.len(), 20);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert_eq!(db.scan_prefix(b"b").count(), 1);
+        assert_eq!(db.scan_prefix(b"c").count(), 0);
+        assert_eq!(db.scan::<[u8], _>(..).count(), 21);
+        assert_eq!(prefix_upper_bound(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_upper_bound(b"\xff"), None);
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("warm.db");
+        {
+            let mut db = Database::new(&path, 3).unwrap();
+            for i in 0..30u8 {
+                db.set(&[i], &[i; 1000]).unwrap();
+            }
+        }
+
+        let options = Options {
+            warm_cache: true,
+            ..Options::default()
+        };
+        let mut db = Database::open_with_options(&path, options).unwrap();
+        // Ten pages of three entries, recovered and then warmed with batches
+        assert_eq!(db.metrics().reads(), 20);
+        for i in 0..30u8 {
+            assert_eq!(db.get(&[i]).unwrap(), vec![i; 1000]);
+        }
+        assert_eq!(db.metrics().reads(), 20);
+        assert_eq!(db.hit_ratio(), 1.0);
+        assert_eq!(db.warm_cache().unwrap(), 0);
+    }
+
+    /// Calls keys starting with "hot" hot, for placement tests
+    #[derive(Debug)]
+    struct PrefixPolicy;
+
+    impl HotnessPolicy for PrefixPolicy {
+        fn name(&self) -> &'static str {
+            "prefix"
+        }
+
+        fn record_access(&mut self, key: &[u8], last: Option<LastAccess>, now: Duration) -> f64 {
+            self.frequency(key, last, now)
+        }
+
+        fn frequency(&self, key: &[u8], _last: Option<LastAccess>, _now: Duration) -> f64 {
+            if key.starts_with(b"hot") {
+                100.0
+            } else {
+                1.0
+            }
+        }
+    }
+
+    #[test]
+    fn test_hotness_policy() {
+        let path = Path::new("hotness.db");
+        let store = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let options = Options {
+            truncate: true,
+            hotness: HotnessConfig::SlidingWindow {
+                window: Duration::from_secs(60),
+            },
+            ..Options::default()
+        };
+        let mut db = Database::open_with_store(path, Box::new(store), options).unwrap();
+        assert_eq!(db.hotness_policy().name(), "sliding_window");
+        // Writes count as accesses, the third one makes the key hot
+        for _ in 0..3 {
+            db.set(b"key", b"value").unwrap();
+        }
+        db.get(b"key").unwrap();
+        assert_eq!(db.index.get(b"key").unwrap().freq_accessed, 4.0);
+        db.delete(b"key").unwrap();
+        let now = db.clock().now();
+        assert_eq!(db.hotness_policy().frequency(b"key", None, now), 0.0);
+
+        // The first write of a key is cold, however hot the policy calls it
+        db.set_hotness_policy(Box::new(PrefixPolicy));
+        let keys = [b"hot1".as_slice(), b"cold1", b"hot2"];
+        for key in keys {
+            db.set(key, b"value").unwrap();
+        }
+        let page_of = |db: &Database, key: &[u8]| db.index.get(key).unwrap().location.page_id;
+        let is_hot = |db: &Database, key: &[u8]| db.page_manager.is_hot_page(page_of(db, key));
+        assert!(keys.iter().all(|key| !is_hot(&db, key)));
+        assert_eq!(db.index.get(b"hot1").unwrap().freq_accessed, 100.0);
+        for key in keys {
+            db.set(key, b"value").unwrap();
+        }
+        assert!(is_hot(&db, b"hot1") && !is_hot(&db, b"cold1"));
+        assert_eq!(page_of(&db, b"hot1"), page_of(&db, b"hot2"));
+        assert_eq!(db.export_metrics()["hotness_policy"], "prefix");
+    }
+
+    #[test]
+    fn test_logical_clock_drives_decay() {
+        let path = Path::new("clock.db");
+        let store = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let options = Options {
+            truncate: true,
+            ..Options::default()
+        };
+        let mut db = Database::open_with_store(path, Box::new(store), options).unwrap();
+        let clock = LogicalClock::new(Duration::from_secs(1_000));
+        db.set_clock(Arc::new(clock.clone()));
+
+        // Accesses recorded ten minutes apart have decayed in between,
+        // however fast they are replayed
+        for _ in 0..5 {
+            db.set(b"slow", b"value").unwrap();
+            clock.advance(Duration::from_secs(600));
+        }
+        assert!(db.index.get(b"slow").unwrap().freq_accessed < 1.01);
+        // Accesses 100ms apart decay measurably: 1, 1.98, 2.94
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(100));
+            db.get(b"slow").unwrap();
+        }
+        let metadata = db.index.get(b"slow").unwrap();
+        assert!(metadata.freq_accessed > 2.9 && metadata.freq_accessed < 2.95);
+        assert!(!db.is_hot(metadata.freq_accessed));
+        assert_eq!(metadata.last_access, clock.now_nanos());
+        let page = &db.page_manager.state_mut().pages[&metadata.location.page_id];
+        assert_eq!(page.last_access, clock.now_nanos());
+
+        // Accesses microseconds apart still reach the threshold
+        for _ in 0..3 {
+            clock.advance(Duration::from_micros(1));
+            db.set(b"fast", b"value").unwrap();
+        }
+        let freq = db.index.get(b"fast").unwrap().freq_accessed;
+        assert!(freq < 3.0);
+        assert!(db.is_hot(freq));
+    }
+
+    #[test]
+    fn test_migration() {
+        let path = Path::new("migration.db");
+        let store = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let options = Options {
+            truncate: true,
+            migration: Some(MigrationConfig {
+                rate: 1.0,
+                burst: 1,
+                ..MigrationConfig::default()
+            }),
+            // Counts that stay put until the window has passed
+            hotness: HotnessConfig::SlidingWindow {
+                window: Duration::from_secs(10),
+            },
+            ..Options::default()
+        };
+        let mut db = Database::open_with_store(path, Box::new(store.reopen()), options).unwrap();
+        let clock = LogicalClock::new(Duration::from_secs(1_000));
+        db.set_clock(Arc::new(clock.clone()));
+        for key in [b"a", b"b", b"c"] {
+            db.set(key, &[key[0]; 100]).unwrap();
+        }
+        let is_hot = |db: &Database, key: &[u8]| {
+            db.page_manager
+                .is_hot_page(db.index.get(key).unwrap().location.page_id)
+        };
+
+        // The third access promotes on read, the next one is over the rate
+        for key in [b"a", b"b"] {
+            db.get(key).unwrap();
+            db.get(key).unwrap();
+        }
+        assert!(is_hot(&db, b"a") && !is_hot(&db, b"b"));
+        assert_eq!(db.migration_stats().promotions, 1);
+        assert_eq!(db.migration_stats().throttled, 1);
+        // Queued until the next write once the rate allows
+        clock.advance(Duration::from_secs(1));
+        db.set(b"d", b"value").unwrap();
+        assert!(is_hot(&db, b"b"));
+
+        // Entries no longer read are demoted by the sweep
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(db.migrate().unwrap(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(db.migrate().unwrap(), 1);
+        assert!(!is_hot(&db, b"a") && !is_hot(&db, b"b"));
+        let stats = *db.migration_stats();
+        assert_eq!((stats.promotions, stats.demotions), (2, 2));
+        assert_eq!(
+            stats.bytes_migrated,
+            4 * Page::entry_size(b"a", &[0; 100]) as u64
+        );
+        assert_eq!(db.get(b"b").unwrap(), [b'b'; 100]);
+        drop(db);
+
+        // The copies left behind do not shadow anything
+        let options = Options {
+            truncate: false,
+            ..options
+        };
+        let mut db = Database::open_with_store(path, Box::new(store.reopen()), options).unwrap();
+        assert_eq!(db.len(), 4);
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(db.get(key).unwrap(), [key[0]; 100]);
+        }
+    }
+
+    #[test]
+    fn test_failed_migration_is_queued() {
+        let path = Path::new("failed-migration.db");
+        let memory = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let faults = FaultInjector::new();
+        let options = Options {
+            truncate: true,
+            migration: Some(MigrationConfig::default()),
+            hotness: HotnessConfig::SlidingWindow {
+                window: Duration::from_secs(10),
+            },
+            ..Options::default()
+        };
+        let store = FaultyStore::new(memory, faults.clone());
+        let mut db = Database::open_with_store(path, Box::new(store), options).unwrap();
+        let clock = LogicalClock::new(Duration::from_secs(1_000));
+        db.set_clock(Arc::new(clock.clone()));
+        let is_hot = |db: &Database, key: &[u8]| {
+            db.page_manager
+                .is_hot_page(db.index.get(key).unwrap().location.page_id)
+        };
+
+        // The read that makes the key hot still returns its value
+        db.set(b"a", b"value").unwrap();
+        db.get(b"a").unwrap();
+        faults.fail_write(1);
+        assert_eq!(db.get(b"a").unwrap(), b"value");
+        assert!(!is_hot(&db, b"a"));
+        assert!(db
+            .migration_queue
+            .get_mut()
+            .unwrap()
+            .contains(b"a".as_slice()));
+
+        // Nor does a write fail with the migration after it, the first page
+        // write being its own
+        faults.fail_write(2);
+        db.set(b"b", b"value").unwrap();
+        assert!(!is_hot(&db, b"a"));
+        assert!(db
+            .migration_queue
+            .get_mut()
+            .unwrap()
+            .contains(b"a".as_slice()));
+        db.set(b"c", b"value").unwrap();
+        assert!(is_hot(&db, b"a"));
+        assert!(db.migration_queue.get_mut().unwrap().is_empty());
+        assert_eq!(db.migration_stats().promotions, 1);
+        assert_eq!(db.get(b"a").unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_adaptive_threshold() {
+        let open = |target, interval| {
+            let options = Options {
+                truncate: true,
+                adaptive_threshold: Some(AdaptiveThreshold { target, interval }),
+                hotness: HotnessConfig::SlidingWindow {
+                    window: Duration::from_secs(60),
+                },
+                ..Options::default()
+            };
+            let store = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+            let mut db =
+                Database::open_with_store(Path::new("adaptive.db"), Box::new(store), options)
+                    .unwrap();
+            db.set_clock(Arc::new(LogicalClock::new(Duration::from_secs(1_000))));
+            for i in 0..20u8 {
+                db.set(&[i], &[i; 100]).unwrap();
+            }
+            db
+        };
+
+        // 20 writes at frequency 1, then 80 reads of two keys at 2 to 41
+        let mut db = open(HotSetTarget::TopAccesses(10.0), 100);
+        for _ in 0..40 {
+            db.get(&[0]).unwrap();
+            db.get(&[1]).unwrap();
+        }
+        let threshold = db.hot_threshold();
+        assert!(threshold > 30 && threshold <= 41, "{}", threshold);
+        let metrics = db.export_metrics();
+        assert_eq!(metrics["hot_threshold"], threshold);
+        assert_eq!(metrics["threshold_updates"], 1);
+        // The next interval is tuned on its own accesses, 100 first writes
+        for i in 20..120u8 {
+            db.set(&[i], &[i; 100]).unwrap();
+        }
+        assert_eq!(db.hot_threshold(), 2);
+        assert_eq!(db.export_metrics()["threshold_updates"], 2);
+
+        // Room for one entry: only the most frequent key is hot
+        let entry_size = Page::entry_size(&[0], &[0; 100]) as u64;
+        let mut db = open(HotSetTarget::Bytes(entry_size), 1);
+        for _ in 0..5 {
+            db.get(&[0]).unwrap();
+        }
+        for _ in 0..3 {
+            db.get(&[1]).unwrap();
+        }
+        assert_eq!(db.hot_threshold(), 5);
+        db.set(&[1], &[1; 100]).unwrap();
+        assert_eq!(db.hot_threshold(), 6);
+        // A deleted entry no longer takes room
+        db.delete(&[0]).unwrap();
+        db.get(&[2]).unwrap();
+        assert_eq!(db.hot_threshold(), 3);
+    }
+
+    #[test]
+    fn test_write_batch_is_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("batch.db");
+
+        let mut db = Database::new(&path, 3).unwrap();
+        db.set(b"key0", b"value0").unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"key2", b"value2")
+            .put(b"key1", b"value3")
+            .delete(b"key0")
+            .delete(b"missing");
+        let writes = db.metrics().writes();
+        db.write(&batch).unwrap();
+        // All entries fit in one page, written once
+        assert_eq!(db.metrics().writes() - writes, 1);
+        assert!(matches!(db.get(b"key0"), Err(DatabaseError::KeyNotFound)));
+        assert_eq!(db.get(b"key1").unwrap(), b"value3");
+
+        // Write the pages of a batch but crash before committing it
+        let records = vec![
+            WalRecord::Set {
+                seq: *db.next_seq.get_mut(),
+                key: b"key1".to_vec(),
+                value: b"value4".to_vec(),
+            },
+            WalRecord::Delete {
+                seq: *db.next_seq.get_mut() + 1,
+                key: b"key2".to_vec(),
+            },
+        ];
+        db.page_manager.defer_writes = true;
+        db.apply_batch(&records, &mut Vec::new()).unwrap();
+        db.page_manager.flush().unwrap();
+        std::mem::forget(db);
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(b"key1").unwrap(), b"value3");
+        assert_eq!(db.get(b"key2").unwrap(), b"value2");
+
+        // Later batches must not commit the dropped entries
+        let mut batch = WriteBatch::new();
+        batch.put(b"key3", b"value5");
+        db.write(&batch).unwrap();
+        drop(db);
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.get(b"key1").unwrap(), b"value3");
+        assert_eq!(db.get(b"key2").unwrap(), b"value2");
+        assert_eq!(db.get(b"key3").unwrap(), b"value5");
+    }
+
+    #[test]
+    fn test_failed_batch_is_reverted() {
+        let path = Path::new("failed-batch.db");
+        let memory = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let faults = FaultInjector::new();
+        let open = |truncate| {
+            let options = Options {
+                truncate,
+                ..Options::default()
+            };
+            let store = FaultyStore::new(memory.reopen(), faults.clone());
+            Database::open_with_store(path, Box::new(store), options).unwrap()
+        };
+
+        let mut db = open(true);
+        db.set(b"key0", &[0; 3000]).unwrap();
+        db.set(b"key1", &[1; 100]).unwrap();
+        // Three pages, the second one fails to be written
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"key0", &[2; 3000])
+            .put(b"key2", &[2; 3000])
+            .delete(b"key1")
+            .put(b"key3", &[3; 3000]);
+        faults.fail_write(2);
+        assert!(matches!(
+            db.write(&batch),
+            Err(DatabaseError::Storage(PageManagerError::Storage(
+                SsdError::Io(_)
+            )))
+        ));
+        assert_eq!(db.get(b"key0").unwrap(), [0; 3000]);
+        assert_eq!(db.get(b"key1").unwrap(), [1; 100]);
+        assert!(matches!(db.get(b"key2"), Err(DatabaseError::KeyNotFound)));
+        assert!(matches!(db.get(b"key3"), Err(DatabaseError::KeyNotFound)));
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.index.tombstone_count(), 0);
+        assert_eq!(db.page_manager.state_mut().dead_bytes, 0);
+
+        // A later batch commits past the sequence numbers of the failed one
+        let mut batch = WriteBatch::new();
+        batch.put(b"key4", b"value4");
+        db.write(&batch).unwrap();
+        drop(db);
+
+        let mut db = open(false);
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.get(b"key0").unwrap(), [0; 3000]);
+        assert_eq!(db.get(b"key1").unwrap(), [1; 100]);
+        assert_eq!(db.get(b"key4").unwrap(), b"value4");
+        assert!(matches!(db.get(b"key2"), Err(DatabaseError::KeyNotFound)));
+        assert!(matches!(db.get(b"key3"), Err(DatabaseError::KeyNotFound)));
+    }
+
+    #[test]
+    fn test_database_on_each_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let stores: Vec<Box<dyn PageStore>> = vec![
+            Box::new(SsdDevice::create(&path, DEFAULT_PAGE_SIZE).unwrap()),
+            Box::new(
+                SsdDevice::create_with_backend(&path, DEFAULT_PAGE_SIZE, IoBackend::IoUring)
+                    .unwrap(),
+            ),
+            Box::new(MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap()),
+        ];
+
+        for store in stores {
+            let options = Options {
+                truncate: true,
+                ..Options::default()
+            };
+            let mut db = Database::open_with_store(&path, store, options).unwrap();
+            // More pages than the cache holds
+            for i in 0..400u32 {
+                db.set(&i.to_le_bytes(), &[i as u8; 600]).unwrap();
+            }
+            // Leaves the pages first written full of dead entries
+            for i in 0..200u32 {
+                db.set(&i.to_le_bytes(), &[i as u8 + 1; 600]).unwrap();
+            }
+            db.delete(&0u32.to_le_bytes()).unwrap();
+            db.gc().unwrap();
+            db.flush().unwrap();
+
+            assert_eq!(db.len(), 399);
+            for i in 1..400u32 {
+                let value = if i < 200 { i as u8 + 1 } else { i as u8 };
+                assert_eq!(db.get(&i.to_le_bytes()).unwrap(), [value; 600]);
+            }
+            assert!(matches!(
+                db.get(&0u32.to_le_bytes()),
+                Err(DatabaseError::KeyNotFound)
+            ));
+            assert!(db.metrics().reads() > 0);
+            assert!(db.metrics().discards() > 0);
+        }
+    }
+
+    #[test]
+    fn test_faults_reach_the_caller_and_recovery() {
+        let path = Path::new("faulty.db");
+        let memory = MemoryStore::new(DEFAULT_PAGE_SIZE).unwrap();
+        let faults = FaultInjector::new();
+        let open = |truncate, durability| {
+            let options = Options {
+                truncate,
+                durability,
+                ..Options::default()
+            };
+            let store = FaultyStore::new(memory.reopen(), faults.clone());
+            Database::open_with_store(path, Box::new(store), options).unwrap()
+        };
+
+        let mut db = open(true, Durability::Fdatasync);
+        // More pages than the cache holds
+        for i in 0..400u32 {
+            db.set(&i.to_le_bytes(), &[i as u8; 600]).unwrap();
+        }
+
+        // Failed writes leave nothing behind for the next write of their
+        // page to persist
+        faults.fail_write(1);
+        assert!(matches!(
+            db.set(b"failed", b"value"),
+            Err(DatabaseError::Storage(PageManagerError::Storage(
+                SsdError::Io(_)
+            )))
+        ));
+        faults.fail_write(1);
+        assert!(db.delete(&399u32.to_le_bytes()).is_err());
+        assert_eq!(db.get(&399u32.to_le_bytes()).unwrap(), [143; 600]);
+        db.set(b"key", b"value").unwrap();
+
+        // Nothing is cached after reopening
+        drop(db);
+        let mut db = open(false, Durability::Fdatasync);
+        assert_eq!(db.len(), 401);
+        assert!(matches!(db.get(b"failed"), Err(DatabaseError::KeyNotFound)));
+        assert_eq!(db.get(&399u32.to_le_bytes()).unwrap(), [143; 600]);
+        faults.on_read(1, ReadFault::Error);
+        assert!(matches!(
+            db.get(&0u32.to_le_bytes()),
+            Err(DatabaseError::Storage(PageManagerError::Storage(
+                SsdError::Io(_)
+            )))
+        ));
+        // Inside the first entry, covered by the checksum
+        faults.on_read(1, ReadFault::FlipBit(100 * 8));
+        assert!(matches!(
+            db.get(&0u32.to_le_bytes()),
+            Err(DatabaseError::Corruption {
+                error: CorruptionError::BadCrc { .. },
+                ..
+            })
+        ));
+        assert_eq!(db.get(&0u32.to_le_bytes()).unwrap(), [0; 600]);
+
+        // Synced writes survive a crash
+        faults.crash();
+        drop(db);
+        let faults = FaultInjector::new();
+        let open = |durability| {
+            let options = Options {
+                durability,
+                ..Options::default()
+            };
+            let store = FaultyStore::new(memory.reopen(), faults.clone());
+            Database::open_with_store(path, Box::new(store), options).unwrap()
+        };
+        let mut db = open(Durability::None);
+        assert_eq!(db.len(), 401);
+        assert_eq!(db.get(b"key").unwrap(), b"value");
+
+        // Unsynced ones don't, the page they went to is as before
+        db.set(b"key", b"lost").unwrap();
+        db.set(b"lost", b"lost").unwrap();
+        faults.crash();
+        drop(db);
+        let faults = FaultInjector::new();
+        let store = FaultyStore::new(memory.reopen(), faults);
+        let mut db = Database::open_with_store(path, Box::new(store), Options::default()).unwrap();
+        assert_eq!(db.len(), 401);
+        assert_eq!(db.get(b"key").unwrap(), b"value");
+        assert!(matches!(db.get(b"lost"), Err(DatabaseError::KeyNotFound)));
+    }
+}
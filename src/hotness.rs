@@ -0,0 +1,390 @@
+// A hotness policy estimates how often each key is accessed, the database
+// places entries of keys whose estimate reaches the hot threshold on hot
+// pages. Policies are fed every get and set, so they can be compared on the
+// same trace. The database keeps the frequency and time of the last access
+// to each key in its index and hands them back to the policy, policies
+// needing more than that keep their own state:
+// - `ExponentialDecay`, a count decaying exponentially with time
+// - `SlidingWindow`, the accesses within a recent window of time
+// - `CountMinSketch`, approximate counts in fixed memory, halved periodically
+// - `LruK`, the access rate over the last K accesses
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hasher;
+use std::time::Duration;
+
+/// The last access to a key, as the database's index keeps it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LastAccess {
+    /// Frequency the policy returned for the access
+    pub freq: f64,
+    pub time: Duration,
+}
+
+/// Estimates the access frequency of keys
+pub trait HotnessPolicy: fmt::Debug + Send + Sync {
+    /// Short name for logs and metrics
+    fn name(&self) -> &'static str;
+
+    /// Count an access to `key` at `now`, `last` being the previous access
+    /// to it if any, returns its frequency including this access
+    fn record_access(&mut self, key: &[u8], last: Option<LastAccess>, now: Duration) -> f64;
+
+    /// Frequency of `key` at `now` without counting an access
+    fn frequency(&self, key: &[u8], last: Option<LastAccess>, now: Duration) -> f64;
+
+    /// Forget a deleted key
+    fn remove(&mut self, _key: &[u8]) {}
+}
+
+/// Built-in policies, selected in `Options`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HotnessConfig {
+    /// Every access adds one to a count decaying by `e^(-decay_rate * t)`
+    /// with `t` the time since the previous access in seconds
+    ExponentialDecay { decay_rate: f64 },
+    /// Number of accesses within the last `window`
+    SlidingWindow { window: Duration },
+    /// Counts kept approximately in `depth` rows of `width` counters,
+    /// halved every `10 * width` accesses so that old accesses fade
+    CountMinSketch { width: usize, depth: usize },
+    /// Accesses per second over the last `k` accesses, 0 until a key was
+    /// accessed `k` times
+    LruK { k: usize },
+}
+
+impl Default for HotnessConfig {
+    fn default() -> HotnessConfig {
+        HotnessConfig::ExponentialDecay { decay_rate: 0.2 }
+    }
+}
+
+impl HotnessConfig {
+    pub fn build(&self) -> Box<dyn HotnessPolicy> {
+        match *self {
+            HotnessConfig::ExponentialDecay { decay_rate } => {
+                Box::new(ExponentialDecay::new(decay_rate))
+            }
+            HotnessConfig::SlidingWindow { window } => Box::new(SlidingWindow::new(window)),
+            HotnessConfig::CountMinSketch { width, depth } => {
+                Box::new(CountMinSketch::new(width, depth))
+            }
+            HotnessConfig::LruK { k } => Box::new(LruK::new(k)),
+        }
+    }
+}
+
+/// Access count decaying exponentially with time, see
+/// `HotnessConfig::ExponentialDecay`. The count is the frequency of the last
+/// access, so the policy keeps no state of its own.
+#[derive(Debug)]
+pub struct ExponentialDecay {
+    decay_rate: f64,
+}
+
+impl ExponentialDecay {
+    pub fn new(decay_rate: f64) -> Self {
+        ExponentialDecay { decay_rate }
+    }
+
+    fn decayed(&self, last: Option<LastAccess>, now: Duration) -> f64 {
+        last.map_or(0.0, |last| {
+            let elapsed = now.saturating_sub(last.time).as_secs_f64();
+            last.freq * (-self.decay_rate * elapsed).exp()
+        })
+    }
+}
+
+impl HotnessPolicy for ExponentialDecay {
+    fn name(&self) -> &'static str {
+        "exponential_decay"
+    }
+
+    fn record_access(&mut self, _key: &[u8], last: Option<LastAccess>, now: Duration) -> f64 {
+        self.decayed(last, now) + 1.0
+    }
+
+    fn frequency(&self, _key: &[u8], last: Option<LastAccess>, now: Duration) -> f64 {
+        self.decayed(last, now)
+    }
+}
+
+/// Accesses within a recent window, see `HotnessConfig::SlidingWindow`
+#[derive(Debug)]
+pub struct SlidingWindow {
+    window: Duration,
+    /// Times of the accesses within the window per key, oldest first
+    keys: HashMap<Vec<u8>, VecDeque<Duration>>,
+}
+
+impl SlidingWindow {
+    pub fn new(window: Duration) -> Self {
+        SlidingWindow {
+            window,
+            keys: HashMap::new(),
+        }
+    }
+
+    fn window_start(&self, now: Duration) -> Duration {
+        now.saturating_sub(self.window)
+    }
+}
+
+impl HotnessPolicy for SlidingWindow {
+    fn name(&self) -> &'static str {
+        "sliding_window"
+    }
+
+    fn record_access(&mut self, key: &[u8], _last: Option<LastAccess>, now: Duration) -> f64 {
+        let start = self.window_start(now);
+        let accesses = self.keys.entry(key.to_vec()).or_default();
+        while accesses.front().is_some_and(|&time| time < start) {
+            accesses.pop_front();
+        }
+        accesses.push_back(now);
+        accesses.len() as f64
+    }
+
+    fn frequency(&self, key: &[u8], _last: Option<LastAccess>, now: Duration) -> f64 {
+        let start = self.window_start(now);
+        self.keys.get(key).map_or(0.0, |accesses| {
+            accesses.iter().filter(|&&time| time >= start).count() as f64
+        })
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.keys.remove(key);
+    }
+}
+
+/// Approximate access counts in fixed memory, see
+/// `HotnessConfig::CountMinSketch`. Counts are never underestimated before
+/// they are halved, deleted keys can't be forgotten.
+#[derive(Debug)]
+pub struct CountMinSketch {
+    width: usize,
+    /// One row of `width` counters per hash function
+    rows: Vec<Vec<u32>>,
+    /// Accesses counted since the counters were last halved
+    additions: u64,
+    reset_interval: u64,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        let width = width.max(1);
+        CountMinSketch {
+            width,
+            rows: vec![vec![0; width]; depth.max(1)],
+            additions: 0,
+            reset_interval: 10 * width as u64,
+        }
+    }
+
+    fn counter_index(&self, row: usize, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(row);
+        hasher.write(key);
+        (hasher.finish() % self.width as u64) as usize
+    }
+
+    fn estimate(&self, key: &[u8]) -> u32 {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(|(row, counters)| counters[self.counter_index(row, key)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+impl HotnessPolicy for CountMinSketch {
+    fn name(&self) -> &'static str {
+        "count_min_sketch"
+    }
+
+    fn record_access(&mut self, key: &[u8], _last: Option<LastAccess>, _now: Duration) -> f64 {
+        // Conservative update: only the counters at the minimum grow
+        let count = self.estimate(key).saturating_add(1);
+        for row in 0..self.rows.len() {
+            let index = self.counter_index(row, key);
+            let counter = &mut self.rows[row][index];
+            *counter = (*counter).max(count);
+        }
+
+        self.additions += 1;
+        if self.additions >= self.reset_interval {
+            for counter in self.rows.iter_mut().flatten() {
+                *counter /= 2;
+            }
+            self.additions = 0;
+        }
+        count as f64
+    }
+
+    fn frequency(&self, key: &[u8], _last: Option<LastAccess>, _now: Duration) -> f64 {
+        self.estimate(key) as f64
+    }
+}
+
+/// Access rate over the last K accesses, see `HotnessConfig::LruK`
+#[derive(Debug)]
+pub struct LruK {
+    k: usize,
+    /// Times of the last `k` accesses per key, oldest first
+    keys: HashMap<Vec<u8>, VecDeque<Duration>>,
+}
+
+impl LruK {
+    pub fn new(k: usize) -> Self {
+        LruK {
+            k: k.max(1),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Accesses per second from the backward K-distance, the time since
+    /// the K-th most recent access
+    fn rate(&self, history: &VecDeque<Duration>, now: Duration) -> f64 {
+        if history.len() < self.k {
+            return 0.0;
+        }
+        let distance = now.saturating_sub(history[0]).as_secs_f64();
+        self.k as f64 / distance.max(1.0)
+    }
+}
+
+impl HotnessPolicy for LruK {
+    fn name(&self) -> &'static str {
+        "lru_k"
+    }
+
+    fn record_access(&mut self, key: &[u8], _last: Option<LastAccess>, now: Duration) -> f64 {
+        let mut history = self.keys.remove(key).unwrap_or_default();
+        if history.len() == self.k {
+            history.pop_front();
+        }
+        history.push_back(now);
+        let rate = self.rate(&history, now);
+        self.keys.insert(key.to_vec(), history);
+        rate
+    }
+
+    fn frequency(&self, key: &[u8], _last: Option<LastAccess>, now: Duration) -> f64 {
+        self.keys
+            .get(key)
+            .map_or(0.0, |history| self.rate(history, now))
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.keys.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Feeds a policy the last access to each key, like the database does
+    struct Keys {
+        policy: Box<dyn HotnessPolicy>,
+        last: HashMap<Vec<u8>, LastAccess>,
+    }
+
+    impl Keys {
+        fn new(policy: Box<dyn HotnessPolicy>) -> Self {
+            Keys {
+                policy,
+                last: HashMap::new(),
+            }
+        }
+
+        fn record_access(&mut self, key: &[u8], now: Duration) -> f64 {
+            let last = self.last.get(key).copied();
+            let freq = self.policy.record_access(key, last, now);
+            self.last
+                .insert(key.to_vec(), LastAccess { freq, time: now });
+            freq
+        }
+
+        fn frequency(&self, key: &[u8], now: Duration) -> f64 {
+            self.policy.frequency(key, self.last.get(key).copied(), now)
+        }
+
+        fn remove(&mut self, key: &[u8]) {
+            self.last.remove(key);
+            self.policy.remove(key);
+        }
+    }
+
+    #[test]
+    fn test_policies_rank_frequent_keys_higher() {
+        let configs = [
+            HotnessConfig::default(),
+            HotnessConfig::SlidingWindow {
+                window: 10 * SECOND,
+            },
+            HotnessConfig::CountMinSketch {
+                width: 1024,
+                depth: 4,
+            },
+            HotnessConfig::LruK { k: 2 },
+        ];
+        for config in configs {
+            let mut keys = Keys::new(config.build());
+            let name = keys.policy.name();
+            let now = 100 * SECOND;
+            for _ in 0..5 {
+                keys.record_access(b"hot", now);
+            }
+            let once = keys.record_access(b"cold", now);
+            assert!(
+                keys.frequency(b"hot", now) > keys.frequency(b"cold", now),
+                "{}",
+                name
+            );
+            assert!(keys.frequency(b"hot", now) >= 2.0, "{}", name);
+            assert!(once <= 1.0, "{}", name);
+            assert_eq!(keys.frequency(b"unknown", now), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_old_accesses_fade() {
+        let start = 100 * SECOND;
+        let later = start + 60 * SECOND;
+
+        let mut decay = Keys::new(Box::new(ExponentialDecay::new(0.2)));
+        decay.record_access(b"key", start);
+        assert_eq!(decay.record_access(b"key", start), 2.0);
+        assert!(decay.frequency(b"key", later) < 0.01);
+
+        let mut window = Keys::new(Box::new(SlidingWindow::new(10 * SECOND)));
+        window.record_access(b"key", start);
+        window.record_access(b"key", start + 5 * SECOND);
+        assert_eq!(window.frequency(b"key", start + 12 * SECOND), 1.0);
+        assert_eq!(window.record_access(b"key", later), 1.0);
+
+        // Two accesses a second apart, then the rate drops with the
+        // distance to the older one
+        let mut lru_k = Keys::new(Box::new(LruK::new(2)));
+        lru_k.record_access(b"key", start);
+        assert_eq!(lru_k.record_access(b"key", start + SECOND), 2.0);
+        assert_eq!(lru_k.frequency(b"key", start + 4 * SECOND), 0.5);
+        lru_k.remove(b"key");
+        assert_eq!(lru_k.frequency(b"key", later), 0.0);
+
+        let mut sketch = Keys::new(Box::new(CountMinSketch::new(16, 2)));
+        for _ in 0..100 {
+            sketch.record_access(b"key", start);
+        }
+        // Halved every 160 accesses
+        for i in 0..200u32 {
+            sketch.record_access(&i.to_le_bytes(), start);
+        }
+        assert!(sketch.frequency(b"key", later) < 100.0);
+    }
+}
@@ -0,0 +1,235 @@
+// The index maps every key to the location of its newest version. It is
+// split into shards by key hash, each behind a lock of its own, so that
+// requests for different keys rarely wait for each other.
+// - Lookups lock the shard of their key in shared mode, updates of an
+//   entry lock it in exclusive mode, for as long as the update takes only.
+// - The tombstone of a deleted key lives in the shard of the key, a write
+//   replacing it changes both under one lock.
+// - Range scans merge the shards in key order. They borrow the whole index
+//   mutably and take no locks.
+use std::collections::btree_map::{self, BTreeMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::iter::Peekable;
+use std::ops::RangeBounds;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::database::{ObjectMetadata, Tombstone};
+
+/// Number of independently locked shards
+const SHARDS: usize = 16;
+
+/// Hash of a key choosing its shard, also used to stripe other per-key state
+pub(crate) fn key_hash(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+/// Entries and tombstones of the keys hashing to one shard
+#[derive(Debug, Default)]
+pub(crate) struct Shard {
+    pub entries: BTreeMap<Vec<u8>, ObjectMetadata>,
+    /// Newest tombstone of every deleted key with older versions left on disk
+    pub tombstones: BTreeMap<Vec<u8>, Tombstone>,
+}
+
+/// Index sharded by key hash, see the module comment
+#[derive(Debug)]
+pub(crate) struct Index {
+    shards: Box<[RwLock<Shard>]>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Index {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl Index {
+    /// Build an index holding `entries` and `tombstones`
+    pub fn with_entries(
+        entries: BTreeMap<Vec<u8>, ObjectMetadata>,
+        tombstones: BTreeMap<Vec<u8>, Tombstone>,
+    ) -> Self {
+        let mut index = Index::default();
+        for (key, metadata) in entries {
+            index.insert(key, metadata);
+        }
+        for (key, tombstone) in tombstones {
+            index.shard_mut(&key).tombstones.insert(key, tombstone);
+        }
+        index
+    }
+
+    fn shard(&self, key: &[u8]) -> &RwLock<Shard> {
+        &self.shards[key_hash(key) % self.shards.len()]
+    }
+
+    /// Lock the shard of key in shared mode
+    pub fn read(&self, key: &[u8]) -> RwLockReadGuard<'_, Shard> {
+        self.shard(key).read().unwrap()
+    }
+
+    /// Lock the shard of key in exclusive mode
+    pub fn write(&self, key: &[u8]) -> RwLockWriteGuard<'_, Shard> {
+        self.shard(key).write().unwrap()
+    }
+
+    /// The shard of key, without locking it
+    pub fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let shard = key_hash(key) % self.shards.len();
+        self.shards[shard].get_mut().unwrap()
+    }
+
+    /// Copy of the entry of key
+    pub fn get(&self, key: &[u8]) -> Option<ObjectMetadata> {
+        self.read(key).entries.get(key).copied()
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.read(key).entries.contains_key(key)
+    }
+
+    /// Copy of the tombstone of key
+    pub fn tombstone(&self, key: &[u8]) -> Option<Tombstone> {
+        self.read(key).tombstones.get(key).copied()
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut ObjectMetadata> {
+        self.shard_mut(key).entries.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, metadata: ObjectMetadata) -> Option<ObjectMetadata> {
+        self.shard_mut(&key).entries.insert(key, metadata)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<ObjectMetadata> {
+        self.shard_mut(key).entries.remove(key)
+    }
+
+    /// Number of keys, counted shard by shard
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of tombstones, counted shard by shard
+    pub fn tombstone_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().tombstones.len())
+            .sum()
+    }
+
+    /// All keys in order, collected shard by shard
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap();
+                shard.entries.keys().cloned().collect::<Vec<_>>()
+            })
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Iterate over the entries of the keys in `range` in key order
+    pub fn range<T, R>(&mut self, range: R) -> Range<'_>
+    where
+        T: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<T>,
+        R: RangeBounds<T>,
+    {
+        let bounds = (range.start_bound(), range.end_bound());
+        Range {
+            shards: self
+                .shards
+                .iter_mut()
+                .map(|shard| shard.get_mut().unwrap().entries.range(bounds).peekable())
+                .collect(),
+        }
+    }
+}
+
+/// Iterator over a range of the index returned by `Index::range`
+pub(crate) struct Range<'a> {
+    shards: Vec<Peekable<btree_map::Range<'a, Vec<u8>, ObjectMetadata>>>,
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (&'a Vec<u8>, &'a ObjectMetadata);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A key is in one shard only, the smallest next key of all comes next
+        let shard = self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(shard, range)| range.peek().map(|&(key, _)| (key, shard)))
+            .min()?
+            .1;
+        self.shards[shard].next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Location;
+
+    fn metadata(seq: u64) -> ObjectMetadata {
+        ObjectMetadata {
+            location: Location {
+                page_id: seq,
+                page_index: 0,
+            },
+            size: 8,
+            seq,
+            freq_accessed: 1.0,
+            last_access: 0,
+            stale_versions: 0,
+        }
+    }
+
+    #[test]
+    fn test_range_merges_shards() {
+        let mut index = Index::default();
+        for i in (0..200u64).rev() {
+            index.insert(format!("key{i:03}").into_bytes(), metadata(i));
+        }
+        // Keys are spread over the shards
+        let used = index
+            .shards
+            .iter()
+            .filter(|shard| !shard.read().unwrap().entries.is_empty())
+            .count();
+        assert!(used > 1);
+
+        assert_eq!(index.len(), 200);
+        assert_eq!(index.get(b"key007").unwrap().seq, 7);
+        let keys = index.keys();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let seqs: Vec<u64> = index
+            .range(b"key050".to_vec()..b"key060".to_vec())
+            .map(|(_, metadata)| metadata.seq)
+            .collect();
+        assert_eq!(seqs, (50..60).collect::<Vec<_>>());
+        assert_eq!(index.range::<Vec<u8>, _>(..).count(), 200);
+
+        index.remove(b"key007");
+        assert!(!index.contains_key(b"key007"));
+        assert_eq!(index.range(b"key000".to_vec()..).nth(7).unwrap().1.seq, 8);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod clock;
 pub mod database;
+pub mod hotness;
+mod index;
+pub mod shared;
 pub mod storage;
 pub mod utils;
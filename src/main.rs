@@ -1,17 +1,19 @@
-use blitzkv::database::{Database, DatabaseError};
+use blitzkv::clock::LogicalClock;
+use blitzkv::database::PageManagerError;
+use blitzkv::database::{AdaptiveThreshold, Database, DatabaseError, Options};
+use blitzkv::hotness::HotnessConfig;
+use blitzkv::storage::cache::CacheConfig;
+use blitzkv::storage::device::IoBackend;
+use blitzkv::storage::memory::{MemoryStore, SimulatedLatency};
 use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
 use std::hash::Hasher;
 use std::io::BufRead;
 use std::path::PathBuf;
-use std::time::Instant;
-use std::{
-    fs,
-    hash::Hash,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fs, hash::Hash};
 use tracing::{info, instrument};
-use tracing_subscriber;
 
 // Operation types from trace
 const GET_TEMP: u8 = 1;
@@ -20,13 +22,17 @@ const PUT_TEMP: u8 = 3;
 const PUT_PERM: u8 = 4;
 const GET_NOT_INIT: u8 = 5;
 const PUT_NOT_INIT: u8 = 6;
+#[allow(dead_code)]
 const UNKNOWN: u8 = 100;
 
+#[allow(dead_code)]
 const PUT_OPS: [u8; 3] = [PUT_PERM, PUT_TEMP, PUT_NOT_INIT];
+#[allow(dead_code)]
 const GET_OPS: [u8; 3] = [GET_PERM, GET_TEMP, GET_NOT_INIT];
 
 // Structure to store trace records
 #[derive(Debug)]
+#[allow(dead_code)]
 struct TraceRecord {
     block_id: u64,
     io_offset: u64,
@@ -40,6 +46,14 @@ struct TraceRecord {
     host_name: u64,
 }
 
+// Where a benchmark variant keeps its pages
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    File(IoBackend),
+    /// Runs anywhere, with the latency of a device instead of its variance
+    Memory(SimulatedLatency),
+}
+
 #[derive(Serialize, Deserialize)]
 struct BenchmarkResult {
     variant: String,
@@ -58,6 +72,7 @@ struct BenchmarkResult {
 #[derive(Serialize, Deserialize)]
 struct TestOperation {
     op_type: u8,    // Operation type from trace
+    op_time: u64,   // Seconds since the epoch the operation was recorded at
     key: Vec<u8>,   // Key derived from block_id
     value: Vec<u8>, // Value sized according to io_size
 }
@@ -99,8 +114,7 @@ impl TestData {
 
             // Generate value with random size
             let size: usize = record.io_size as usize % 100 + 1024;
-            let mut value = Vec::with_capacity(size);
-            value.extend(std::iter::repeat(0u8).take(size));
+            let value = vec![0u8; size];
 
             // Hash the block_id using DefaultHasher
             let mut hasher = std::collections::hash_map::DefaultHasher::new();
@@ -109,6 +123,7 @@ impl TestData {
 
             operations.push(TestOperation {
                 op_type: record.op_name,
+                op_time: record.op_time,
                 key: key.to_string().into_bytes(),
                 value,
             });
@@ -135,6 +150,11 @@ fn run_benchmark_with_params(
 
     let mut op_counts = [0u64; 7]; // Counts for each operation type (1-6 + unknown)
 
+    // Hotness decays with the recorded time of the operations, not with
+    // how long the replay takes
+    let clock = LogicalClock::default();
+    db.set_clock(Arc::new(clock.clone()));
+
     // Run benchmark with progress bar
     info!("Starting benchmark ({} operations)...", total_ops);
     let pb = ProgressBar::new(total_ops as u64);
@@ -147,6 +167,7 @@ fn run_benchmark_with_params(
 
     for op in test_data.operations.iter() {
         pb.inc(1);
+        clock.set(Duration::from_secs(op.op_time));
 
         if op.op_type <= 6 {
             op_counts[op.op_type as usize - 1] += 1;
@@ -183,12 +204,21 @@ fn run_benchmark_with_params(
     let hit_ratio = db.hit_ratio();
     let ssd_metrics = db.metrics();
     let freq_hist = db.freq_histogram();
+    info!("{}", ssd_metrics);
 
     info!("Access Frequency Statistics:");
     info!("  p50: {:.2}", freq_hist.value_at_percentile(50.0) as f64);
     info!("  p95: {:.2}", freq_hist.value_at_percentile(95.0) as f64);
     info!("  p99: {:.2}", freq_hist.value_at_percentile(99.0) as f64);
     info!("  max: {:.2}", freq_hist.max() as f64);
+    info!("Hot threshold at the end: {}", db.hot_threshold());
+    for stats in db.cache_stats() {
+        info!(
+            "Cache hit ratio with {}: {:.4}",
+            stats.policy,
+            stats.hit_ratio()
+        );
+    }
 
     Ok(BenchmarkResult {
         variant: variant.to_string(),
@@ -212,14 +242,121 @@ fn main() -> Result<(), DatabaseError> {
     let data_dir = PathBuf::from("data");
     std::fs::create_dir_all(&data_dir).unwrap();
 
-    let variants = vec![("baseline", 40000), ("optimized", 3)];
+    // The io_uring variants run the same trace as "optimized", so their
+    // device metrics compare syscalls against io_uring, with completions
+    // signalled by interrupts or polled. The memory variants compare
+    // hotness policies, and a threshold adapted to the workload instead of
+    // picked by hand, without the variance of a device.
+    let nvme_latency = SimulatedLatency {
+        read: Duration::from_micros(80),
+        write: Duration::from_micros(20),
+        sync: Duration::from_micros(500),
+    };
+    let optimized = Options {
+        hot_threshold: 3,
+        truncate: true,
+        ..Options::default()
+    };
+    let window = HotnessConfig::SlidingWindow {
+        window: Duration::from_secs(10),
+    };
+    let sketch = HotnessConfig::CountMinSketch {
+        width: 1 << 16,
+        depth: 4,
+    };
+    let lru_k = HotnessConfig::LruK { k: 2 };
+    let syscall = Storage::File(IoBackend::Syscall);
+    let memory = Storage::Memory(nvme_latency);
+    let variants = vec![
+        (
+            "baseline",
+            syscall,
+            Options {
+                hot_threshold: 40000,
+                ..optimized
+            },
+        ),
+        ("optimized", syscall, optimized),
+        (
+            "optimized_uring",
+            Storage::File(IoBackend::IoUring),
+            optimized,
+        ),
+        (
+            "optimized_uring_polled",
+            Storage::File(IoBackend::IoUringPolled),
+            optimized,
+        ),
+        ("optimized_memory", memory, optimized),
+        (
+            "window_memory",
+            memory,
+            Options {
+                hotness: window,
+                ..optimized
+            },
+        ),
+        (
+            "sketch_memory",
+            memory,
+            Options {
+                hotness: sketch,
+                ..optimized
+            },
+        ),
+        // LRU-K estimates accesses per second, at most K
+        (
+            "lru_k_memory",
+            memory,
+            Options {
+                hot_threshold: 2,
+                hotness: lru_k,
+                ..optimized
+            },
+        ),
+        (
+            "adaptive_memory",
+            memory,
+            Options {
+                adaptive_threshold: Some(AdaptiveThreshold::default()),
+                ..optimized
+            },
+        ),
+        // Reports the hit ratio the other eviction policies would have had
+        // on its page requests. Only this variant simulates them, so that
+        // the throughput of the others doesn't pay for it.
+        (
+            "shadow_memory",
+            memory,
+            Options {
+                cache: CacheConfig {
+                    shadow_policies: true,
+                    ..CacheConfig::default()
+                },
+                ..optimized
+            },
+        ),
+    ];
     let mut all_results = Vec::new();
 
     // Run benchmark for each variant
-    for &(variant_name, hot_threshold) in &variants {
+    for &(variant_name, storage, mut options) in &variants {
         let db_path = data_dir.join(format!("bench_{}.db", variant_name));
-        info!("Running {} (db: {:?})", variant_name, db_path);
-        let mut db = Database::new(db_path, hot_threshold)?;
+        info!(
+            "Running {} ({:?}, db: {:?})",
+            variant_name, storage, db_path
+        );
+        let mut db = match storage {
+            Storage::File(io_backend) => {
+                options.io_backend = io_backend;
+                Database::open_with_options(db_path, options)?
+            }
+            Storage::Memory(latency) => {
+                let store = MemoryStore::with_latency(4096, latency)
+                    .map_err(|e| DatabaseError::Storage(PageManagerError::from(e)))?;
+                Database::open_with_store(db_path, Box::new(store), options)?
+            }
+        };
         let result = run_benchmark_with_params(&mut db, variant_name)?;
         all_results.push(result);
     }
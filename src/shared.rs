@@ -0,0 +1,480 @@
+// A `SharedDatabase` is a handle to a database that can be cloned and used
+// from several threads.
+// - Reads and single writes take the database lock in shared mode, so they
+//   run in parallel. The index is sharded by key hash and locked one shard
+//   at a time, a write latches only the page it modifies and the page cache
+//   is sharded, so requests for different keys rarely wait for each other.
+//   Writes of the same key are serialized.
+// - Reads count towards the hotness of their key as they happen.
+// - Batches, flushes and scans take the database lock in exclusive mode, as
+//   do the GC, migrations and checkpoints single writes make due.
+// - The async API reads and writes pages through io_uring with the lock
+//   released, so a thread can have many requests in flight. Async writes are
+//   queued under the exclusive lock, so that page writes of later requests
+//   are ordered after the ones still in flight. Stores that are not a file
+//   have nothing to wait for, their requests run synchronously.
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+use crate::database::{Database, DatabaseError, Options, PageManagerError, PageMiss, WriteBatch};
+use crate::storage::device::{AlignedBuffer, Durability, SsdDevice, SsdError};
+use crate::storage::io_uring::Rio;
+use crate::storage::page::Page;
+
+/// Cloneable, thread-safe handle to a `Database`
+#[derive(Debug, Clone)]
+pub struct SharedDatabase {
+    inner: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    db: RwLock<Database>,
+    /// Set up on first use of the async API, None inside for stores that
+    /// are not a file
+    io: Mutex<Option<Option<Arc<AsyncIo>>>>,
+}
+
+/// io_uring and data file serving the async API
+#[derive(Debug)]
+struct AsyncIo {
+    rio: Rio,
+    file: File,
+    page_size: u32,
+}
+
+impl AsyncIo {
+    /// Read the page of `miss` from the data file, returning it with the
+    /// time the read took in nanoseconds
+    async fn read_page(&self, miss: &PageMiss) -> Result<(Page, u64), DatabaseError> {
+        let buffer = AlignedBuffer::new(self.page_size as usize).map_err(io_error)?;
+        let start = Instant::now();
+        let bytes_read = self
+            .rio
+            .read_at(&self.file, &buffer, miss.offset)
+            .await
+            .map_err(io_error)?;
+        let elapsed_nanos = start.elapsed().as_nanos() as u64;
+        if bytes_read != self.page_size as usize {
+            return Err(io_error(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short read of page {}: {} bytes", miss.page_id, bytes_read),
+            )));
+        }
+        let page = SsdDevice::decode_page(miss.page_id, buffer.as_ref())
+            .map_err(|e| DatabaseError::from(PageManagerError::from(e)))?;
+        Ok((page, elapsed_nanos))
+    }
+}
+
+fn io_error(error: io::Error) -> DatabaseError {
+    PageManagerError::from(SsdError::Io(error)).into()
+}
+
+impl From<Database> for SharedDatabase {
+    fn from(db: Database) -> Self {
+        SharedDatabase::new(db)
+    }
+}
+
+impl SharedDatabase {
+    pub fn new(db: Database) -> Self {
+        SharedDatabase {
+            inner: Arc::new(Shared {
+                db: RwLock::new(db),
+                io: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Open the database stored at `path` with the given options, see
+    /// `Database::open_with_options`
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: Options,
+    ) -> Result<Self, DatabaseError> {
+        Database::open_with_options(path, options).map(SharedDatabase::new)
+    }
+
+    /// Read value for key, concurrently with other reads
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let db = self.read();
+        let value = db.read_value(key)?;
+        db.record_access(key);
+        Ok(value)
+    }
+
+    /// Read value for key like `get`. A page that is not in memory is read
+    /// through io_uring without blocking the thread, so many reads can be
+    /// awaited at once.
+    pub async fn get_async(&self, key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let miss = {
+            let db = self.read();
+            match db.read_value_or_miss(key)? {
+                Ok(value) => {
+                    db.record_access(key);
+                    return Ok(value);
+                }
+                Err(miss) => miss,
+            }
+        };
+
+        let Some(io) = self.async_io()? else {
+            return self.get(key);
+        };
+        let (page, elapsed_nanos) = io.read_page(&miss).await?;
+        let db = self.read();
+        db.metrics()
+            .record_read(io.page_size as usize, elapsed_nanos);
+        let value = db.read_value_from(key, &miss, page)?;
+        db.record_access(key);
+        Ok(value)
+    }
+
+    /// Set key-value pair like `set`. The page the entry goes to is read
+    /// through io_uring if it is not in memory, and the modified pages are
+    /// written through io_uring, without blocking the thread.
+    pub async fn set_async(&self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        let Some(io) = self.async_io()? else {
+            return self.set(key, value);
+        };
+        let miss = self.read().write_page_miss(key, value);
+        if let Some(miss) = miss {
+            let (page, elapsed_nanos) = io.read_page(&miss).await?;
+            let db = self.read();
+            db.metrics()
+                .record_read(io.page_size as usize, elapsed_nanos);
+            db.install_page(&miss, page);
+        }
+
+        // Writes are queued under the lock, so that writes of the same page
+        // reach the ring in the order the page was modified
+        let writes;
+        let (completions, durability): (Vec<_>, _) = {
+            let mut db = self.lock();
+            writes = db.set_deferred(key, value)?;
+            let completions = writes
+                .iter()
+                .map(|write| {
+                    io.rio
+                        .write_at_ordered(&io.file, &write.buffer, write.offset, write.ordering)
+                })
+                .collect();
+            (completions, db.durability())
+        };
+
+        let start = Instant::now();
+        let mut result = Ok(());
+        for (write, completion) in writes.iter().zip(completions) {
+            match completion.await {
+                Ok(written) if written == write.buffer.as_ref().len() => {}
+                Ok(written) => {
+                    result = Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        format!("short write of page {}: {} bytes", write.page_id, written),
+                    ))
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        let elapsed_nanos = start.elapsed().as_nanos() as u64;
+        self.lock()
+            .finish_page_writes(&writes, result.is_ok(), elapsed_nanos);
+        result.map_err(io_error)?;
+
+        let sync = match durability {
+            Durability::Fsync => Some(io.rio.fsync(&io.file)),
+            Durability::Fdatasync => Some(io.rio.fdatasync(&io.file)),
+            Durability::None | Durability::Periodic(_) => None,
+        };
+        if let Some(sync) = sync {
+            let start = Instant::now();
+            sync.await.map_err(io_error)?;
+            self.read()
+                .metrics()
+                .record_sync(start.elapsed().as_nanos() as u64);
+        }
+        Ok(())
+    }
+
+    /// Set key-value pair, concurrently with other reads and writes
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        self.read().write_value(key, value)?;
+        self.maintain()
+    }
+
+    /// Delete key, returning `KeyNotFound` if it does not exist
+    pub fn delete(&self, key: &[u8]) -> Result<(), DatabaseError> {
+        self.read().delete_value(key)?;
+        self.maintain()
+    }
+
+    /// Run the GC, migrations and checkpoint a write made due, which need
+    /// exclusive access
+    fn maintain(&self) -> Result<(), DatabaseError> {
+        if !self.read().maintenance_due() {
+            return Ok(());
+        }
+        self.lock().maintain()
+    }
+
+    /// Apply all puts and deletes of `batch` atomically, see `Database::write`
+    pub fn write(&self, batch: &WriteBatch) -> Result<(), DatabaseError> {
+        self.lock().write(batch)
+    }
+
+    /// Make all writes so far durable, see `Database::flush`
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        self.lock().flush()
+    }
+
+    /// Number of keys in database
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Check if empty
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Lock the database in shared mode, e.g. to read its metrics. Reads
+    /// through this handle must not be mixed with holding the guard, a
+    /// waiting writer could deadlock them.
+    pub fn read(&self) -> RwLockReadGuard<'_, Database> {
+        self.inner.db.read().unwrap()
+    }
+
+    /// Lock the database for exclusive use, e.g. to scan it
+    pub fn lock(&self) -> RwLockWriteGuard<'_, Database> {
+        self.inner.db.write().unwrap()
+    }
+
+    /// Set up io_uring for the async API on first use. Returns None if the
+    /// store is not a file.
+    fn async_io(&self) -> Result<Option<Arc<AsyncIo>>, DatabaseError> {
+        let mut io = self.inner.io.lock().unwrap();
+        if let Some(io) = &*io {
+            return Ok(io.clone());
+        }
+
+        let mut db = self.inner.db.write().unwrap();
+        let Some(file) = db.try_clone_data_file().map_err(io_error)? else {
+            *io = Some(None);
+            return Ok(None);
+        };
+        // Share the ring of the store if it has one
+        let rio = match db.store_uring() {
+            Some(rio) => rio,
+            None => crate::storage::new().map_err(io_error)?,
+        };
+        let async_io = Arc::new(AsyncIo {
+            rio: rio.clone(),
+            file,
+            page_size: db.page_size(),
+        });
+        db.set_uring(rio);
+        *io = Some(Some(Arc::clone(&async_io)));
+        Ok(Some(async_io))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use tempfile::tempdir;
+
+    /// Run a future to completion on the current thread
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(thread::Thread);
+
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_database_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Database>();
+        assert_send_sync::<SharedDatabase>();
+
+        let dir = tempdir().unwrap();
+        let db = SharedDatabase::new(Database::new(dir.path().join("shared.db"), 3).unwrap());
+        for i in 0..100 {
+            db.set(format!("key{i}").as_bytes(), format!("value{i}").as_bytes())
+                .unwrap();
+        }
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        for i in 0..100 {
+                            let value = db.get(format!("key{i}").as_bytes()).unwrap();
+                            assert_eq!(value, format!("value{i}").as_bytes());
+                        }
+                    }
+                })
+            })
+            .collect();
+        let writer = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 100..200 {
+                    db.set(format!("key{i}").as_bytes(), b"new").unwrap();
+                }
+            })
+        };
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        writer.join().unwrap();
+
+        assert_eq!(db.len(), 200);
+        assert!(matches!(
+            db.get(b"missing"),
+            Err(DatabaseError::KeyNotFound)
+        ));
+
+        // Reads count towards hotness, overwriting records it
+        db.set(b"key7", b"value7").unwrap();
+        assert!(db.read().freq_histogram().max() > 2);
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("writers.db");
+        let db = SharedDatabase::new(Database::new(&path, 3).unwrap());
+
+        // Every writer overwrites and deletes keys of its own, so that many
+        // writes share pages, while readers check the keys already written
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let key = format!("key{t}-{i}");
+                        db.set(key.as_bytes(), b"first").unwrap();
+                        db.set(key.as_bytes(), format!("value{t}-{i}").as_bytes())
+                            .unwrap();
+                        assert_eq!(
+                            db.get(key.as_bytes()).unwrap(),
+                            format!("value{t}-{i}").as_bytes()
+                        );
+                        if i % 10 == 0 {
+                            db.delete(key.as_bytes()).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 0..800 {
+                        match db.get(format!("key{}-{}", i % 4, i / 4).as_bytes()) {
+                            Ok(value) => assert!(value.starts_with(b"value") || value == b"first"),
+                            Err(DatabaseError::KeyNotFound) => {}
+                            Err(e) => panic!("unexpected error {e:?}"),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+        assert_eq!(db.len(), 4 * 180);
+        drop(db);
+
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 4 * 180);
+        for t in 0..4 {
+            for i in 0..200 {
+                let key = format!("key{t}-{i}");
+                match db.get(key.as_bytes()) {
+                    Ok(value) => assert_eq!(value, format!("value{t}-{i}").as_bytes()),
+                    Err(DatabaseError::KeyNotFound) => assert_eq!(i % 10, 0),
+                    Err(e) => panic!("unexpected error {e:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_get_and_set() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("async.db");
+        let db = SharedDatabase::new(Database::new(&path, 3).unwrap());
+        assert_send(&db.get_async(b"key"));
+        assert_send(&db.set_async(b"key", b"value"));
+
+        // Enough entries that most pages fall out of the cache
+        let value = vec![7u8; 1000];
+        block_on(async {
+            for i in 0..400 {
+                db.set_async(format!("key{i}").as_bytes(), &value)
+                    .await
+                    .unwrap();
+            }
+            db.set_async(b"key0", b"updated").await.unwrap();
+        });
+        let reads = db.read().metrics().reads();
+
+        let readers: Vec<_> = (0..4)
+            .map(|t| {
+                let db = db.clone();
+                let value = value.clone();
+                thread::spawn(move || {
+                    block_on(async {
+                        for i in (t..400).step_by(4).skip(1) {
+                            let read = db.get_async(format!("key{i}").as_bytes()).await;
+                            assert_eq!(read.unwrap(), value);
+                        }
+                    })
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert!(db.read().metrics().reads() > reads);
+        assert_eq!(block_on(db.get_async(b"key0")).unwrap(), b"updated");
+        assert!(matches!(
+            block_on(db.get_async(b"missing")),
+            Err(DatabaseError::KeyNotFound)
+        ));
+        drop(db);
+
+        // The pages written through io_uring are on the device
+        let mut db = Database::open(&path, 3).unwrap();
+        assert_eq!(db.len(), 400);
+        assert_eq!(db.get(b"key0").unwrap(), b"updated");
+        assert_eq!(db.get(b"key399").unwrap(), value);
+    }
+}
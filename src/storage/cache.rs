@@ -0,0 +1,314 @@
+// The page cache keeps recently used pages in memory so that reads can be
+// served without going to the device.
+// - Pages are shared as `PageRef`, a page behind its own read/write latch.
+// - The cache is split into shards, each behind its own mutex with its own
+//   `EvictionPolicy`, so that threads reading different pages rarely contend.
+// - Its size is a memory budget, see `CacheConfig`.
+// - The other eviction policies can be simulated on the same page requests,
+//   to compare their hit ratios on a workload, see `PageCache::policy_stats`.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
+
+use super::eviction::{Eviction, EvictionPolicy};
+use super::page::Page;
+
+/// A page shared between the cache and its users, latched on access
+pub type PageRef = Arc<RwLock<Page>>;
+
+/// Number of independently locked shards of the cache
+const CACHE_SHARDS: usize = 8;
+
+/// Default memory budget of the cache, 50 pages of 4KB
+const DEFAULT_CACHE_BYTES: usize = 50 * 4096;
+
+/// Size and eviction policy of a `PageCache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Memory for cached pages in bytes, rounded down to whole pages
+    pub capacity_bytes: usize,
+    pub eviction: Eviction,
+    /// Simulate the other built-in policies on the same page requests. Each
+    /// keeps page ids only, in a single shard of the whole capacity.
+    pub shadow_policies: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            capacity_bytes: DEFAULT_CACHE_BYTES,
+            eviction: Eviction::Lru,
+            shadow_policies: false,
+        }
+    }
+}
+
+/// Page requests an eviction policy served from memory or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PolicyStats {
+    pub policy: &'static str,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl PolicyStats {
+    pub fn hit_ratio(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses) as f64
+    }
+}
+
+#[derive(Debug)]
+struct Shard {
+    pages: HashMap<u64, PageRef>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+/// A policy run on the page requests of the cache without holding pages
+#[derive(Debug)]
+struct Shadow {
+    policy: Box<dyn EvictionPolicy>,
+    cached: HashSet<u64>,
+    hits: usize,
+    misses: usize,
+}
+
+impl Shadow {
+    fn request(&mut self, page_id: u64) {
+        if self.cached.contains(&page_id) {
+            self.policy.touch(page_id);
+            self.hits += 1;
+        } else {
+            if let Some(victim) = self.policy.insert(page_id) {
+                self.cached.remove(&victim);
+            }
+            self.cached.insert(page_id);
+            self.misses += 1;
+        }
+    }
+}
+
+/// Thread-safe cache of pages, sharded by page id
+#[derive(Debug)]
+pub struct PageCache {
+    shards: Vec<Mutex<Shard>>,
+    capacity: usize,
+    policy: &'static str,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    /// The other policies, when `CacheConfig::shadow_policies` is set
+    shadows: Option<Mutex<Vec<Shadow>>>,
+}
+
+impl PageCache {
+    /// Create an LRU cache holding about `capacity` pages in total
+    pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, Eviction::Lru, false)
+    }
+
+    /// Create a cache of pages of `page_size` bytes as configured
+    pub fn with_config(config: &CacheConfig, page_size: u32) -> Self {
+        let capacity = config.capacity_bytes / page_size as usize;
+        Self::with_policy(capacity, config.eviction, config.shadow_policies)
+    }
+
+    fn with_policy(capacity: usize, eviction: Eviction, shadow_policies: bool) -> Self {
+        let shard_capacity = capacity.div_ceil(CACHE_SHARDS).max(1);
+        let shards: Vec<_> = (0..CACHE_SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    pages: HashMap::new(),
+                    policy: eviction.build(shard_capacity),
+                })
+            })
+            .collect();
+        let shadows = shadow_policies.then(|| {
+            let shadows = Eviction::ALL
+                .into_iter()
+                .filter(|&other| other != eviction)
+                .map(|other| Shadow {
+                    policy: other.build(capacity),
+                    cached: HashSet::new(),
+                    hits: 0,
+                    misses: 0,
+                })
+                .collect();
+            Mutex::new(shadows)
+        });
+        let policy = shards[0].lock().unwrap().policy.name();
+        PageCache {
+            policy,
+            shards,
+            capacity,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            shadows,
+        }
+    }
+
+    fn shard(&self, page_id: u64) -> &Mutex<Shard> {
+        &self.shards[page_id as usize % self.shards.len()]
+    }
+
+    /// Look a page up, counting it as used with the eviction policy
+    pub fn get(&self, page_id: u64) -> Option<PageRef> {
+        let mut shard = self.shard(page_id).lock().unwrap();
+        let page = shard.pages.get(&page_id).cloned()?;
+        shard.policy.touch(page_id);
+        Some(page)
+    }
+
+    /// Add a page or replace the cached copy, evicting a page of its shard
+    /// if full
+    pub fn insert(&self, page_id: u64, page: PageRef) {
+        let mut shard = self.shard(page_id).lock().unwrap();
+        if shard.pages.insert(page_id, page).is_some() {
+            shard.policy.touch(page_id);
+        } else if let Some(victim) = shard.policy.insert(page_id) {
+            shard.pages.remove(&victim);
+        }
+    }
+
+    /// Whether a page is cached, without counting it as used
+    pub fn contains(&self, page_id: u64) -> bool {
+        self.shard(page_id)
+            .lock()
+            .unwrap()
+            .pages
+            .contains_key(&page_id)
+    }
+
+    /// Number of pages the cache holds when full
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn remove(&self, page_id: u64) {
+        let mut shard = self.shard(page_id).lock().unwrap();
+        if shard.pages.remove(&page_id).is_some() {
+            shard.policy.remove(page_id);
+        }
+    }
+
+    /// Count a page request served from memory
+    pub fn record_hit(&self, page_id: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.record_shadow_request(page_id);
+    }
+
+    /// Count a page request that had to go to the device
+    pub fn record_miss(&self, page_id: u64) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.record_shadow_request(page_id);
+    }
+
+    fn record_shadow_request(&self, page_id: u64) {
+        if let Some(shadows) = &self.shadows {
+            for shadow in shadows.lock().unwrap().iter_mut() {
+                shadow.request(page_id);
+            }
+        }
+    }
+
+    /// Name of the eviction policy of the cache
+    pub fn policy(&self) -> &'static str {
+        self.policy
+    }
+
+    /// Hits and misses of the cache's policy, followed by those of the
+    /// simulated policies if enabled
+    pub fn policy_stats(&self) -> Vec<PolicyStats> {
+        let mut stats = vec![PolicyStats {
+            policy: self.policy,
+            hits: self.hits(),
+            misses: self.misses(),
+        }];
+        if let Some(shadows) = &self.shadows {
+            stats.extend(shadows.lock().unwrap().iter().map(|shadow| PolicyStats {
+                policy: shadow.policy.name(),
+                hits: shadow.hits,
+                misses: shadow.misses,
+            }));
+        }
+        stats
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_page_cache_shared_between_threads() {
+        let cache = Arc::new(PageCache::new(64));
+        let handles: Vec<_> = (0..4u64)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                thread::spawn(move || {
+                    for page_id in (t * 16)..(t * 16 + 16) {
+                        assert!(cache.get(page_id).is_none());
+                        cache.record_miss(page_id);
+                        let page = Arc::new(RwLock::new(Page::new(page_id, 4096)));
+                        cache.insert(page_id, page);
+                        let page = cache.get(page_id).unwrap();
+                        cache.record_hit(page_id);
+                        assert_eq!(page.read().unwrap().id(), page_id);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(cache.hits(), 64);
+        assert_eq!(cache.misses(), 64);
+        cache.remove(3);
+        assert!(cache.get(3).is_none());
+    }
+
+    #[test]
+    fn test_cache_config_and_policy_stats() {
+        let config = CacheConfig {
+            capacity_bytes: 16 * 4096 + 100,
+            eviction: Eviction::S3Fifo,
+            shadow_policies: true,
+        };
+        let cache = PageCache::with_config(&config, 4096);
+        assert_eq!(cache.capacity(), 16);
+        assert_eq!(cache.policy(), "s3fifo");
+
+        // Requests like the page manager makes them
+        let requests = (0..8).chain(0..8).chain(100..200).chain(0..8);
+        for page_id in requests {
+            if cache.get(page_id).is_some() {
+                cache.record_hit(page_id);
+            } else {
+                cache.record_miss(page_id);
+                let page = Arc::new(RwLock::new(Page::new(page_id, 4096)));
+                cache.insert(page_id, page);
+            }
+        }
+        assert!((0..200).filter(|&page_id| cache.contains(page_id)).count() <= 16);
+
+        // Every policy saw the same requests
+        let stats = cache.policy_stats();
+        let policies: HashSet<_> = stats.iter().map(|stats| stats.policy).collect();
+        assert_eq!(policies.len(), Eviction::ALL.len());
+        for stats in &stats {
+            assert_eq!(stats.hits + stats.misses, 124, "{}", stats.policy);
+            assert!(stats.hits >= 8, "{}", stats.policy);
+        }
+        assert_eq!(stats[0].hits, cache.hits());
+    }
+}
@@ -5,6 +5,7 @@ use std::{
     pin::Pin,
     sync::{Arc, Condvar, Mutex},
     task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
 use crate::storage::metrics::{Measure, M};
@@ -14,23 +15,13 @@ use super::{
     FromCqe,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct CompletionState {
     done: bool,
     item: Option<io::Result<io_uring_cqe>>,
     waker: Option<Waker>,
 }
 
-impl Default for CompletionState {
-    fn default() -> CompletionState {
-        CompletionState {
-            done: false,
-            item: None,
-            waker: None,
-        }
-    }
-}
-
 /// A Future value which may or may not be filled
 ///
 /// # Safety
@@ -47,6 +38,10 @@ pub struct Completion<'a, C: FromCqe> {
     cv: Arc<Condvar>,
     uring: &'a Uring,
     pub(crate) sqe_id: u64,
+    /// Identifies the operation to the kernel, e.g. to cancel it
+    pub(crate) user_data: u64,
+    /// Timeout linked to the operation, waited for along with it
+    pub(crate) linked_timeout: Option<Box<Completion<'a, ()>>>,
 }
 
 /// The completer side of the Future
@@ -54,6 +49,8 @@ pub struct Completion<'a, C: FromCqe> {
 pub struct Filler {
     mu: Arc<Mutex<CompletionState>>,
     cv: Arc<Condvar>,
+    /// Completes a timeout, for which expiring is success
+    pub(crate) timeout: bool,
 }
 
 /// Create a new `Filler` and the `Completion`
@@ -66,9 +63,15 @@ pub fn pair<'a, C: FromCqe>(uring: &'a Uring) -> (Completion<'a, C>, Filler) {
         mu: mu.clone(),
         cv: cv.clone(),
         sqe_id: 0,
+        user_data: 0,
+        linked_timeout: None,
         uring,
     };
-    let filler = Filler { mu, cv };
+    let filler = Filler {
+        mu,
+        cv,
+        timeout: false,
+    };
 
     (future, filler)
 }
@@ -83,10 +86,45 @@ impl<'a, C: FromCqe> Completion<'a, C> {
         self.wait_inner().unwrap()
     }
 
-    fn wait_inner(&self) -> Option<io::Result<C>>
-    where
-        C: FromCqe,
-    {
+    /// Block on the `Completion`'s completion for
+    /// at most `timeout`. The `Completion` is handed
+    /// back if it did not complete in time, so it
+    /// can be waited for again or cancelled.
+    ///
+    /// This only bounds the wait. To bound the
+    /// operation itself, link a timeout to it, e.g.
+    /// with `Uring::read_at_timeout`.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<io::Result<C>, Self> {
+        self.submit();
+
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.mu.lock().unwrap();
+        while !inner.done {
+            let now = Instant::now();
+            if now >= deadline {
+                drop(inner);
+                return Err(self);
+            }
+            inner = self.cv.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+        let item = inner.item.take();
+        drop(inner);
+        Ok(self.finish(item).unwrap())
+    }
+
+    /// Cancel the operation and block until it
+    /// completes. Returns the result of the
+    /// operation, an error of `ECANCELED` if it was
+    /// cancelled before it completed.
+    pub fn cancel(self) -> io::Result<C> {
+        // Fails with ENOENT or EALREADY if the
+        // operation completed or can't be
+        // interrupted anymore
+        let _ = self.uring.cancel(&self).wait();
+        self.wait()
+    }
+
+    fn submit(&self) {
         debug_assert_ne!(
             self.sqe_id, 0,
             "sqe_id was never filled-in for this Completion",
@@ -95,6 +133,13 @@ impl<'a, C: FromCqe> Completion<'a, C> {
         self.uring
             .ensure_submitted(self.sqe_id)
             .expect("failed to submit SQE from wait_inner");
+    }
+
+    fn wait_inner(&self) -> Option<io::Result<C>>
+    where
+        C: FromCqe,
+    {
+        self.submit();
 
         let _ = Measure::new(&M.wait);
 
@@ -104,10 +149,30 @@ impl<'a, C: FromCqe> Completion<'a, C> {
             inner = self.cv.wait(inner).unwrap();
         }
 
-        inner
-            .item
-            .take()
-            .map(|io_result| io_result.map(FromCqe::from_cqe))
+        let item = inner.item.take();
+        drop(inner);
+        self.finish(item)
+    }
+
+    /// Convert the result of the operation, reporting
+    /// an operation cancelled by its linked timeout as
+    /// timed out
+    fn finish(&self, item: Option<io::Result<io_uring_cqe>>) -> Option<io::Result<C>> {
+        let item = match (item, &self.linked_timeout) {
+            (Some(Err(e)), Some(linked_timeout)) if e.raw_os_error() == Some(libc::ECANCELED) => {
+                // The timeout completes right after the
+                // operation it is linked to
+                match linked_timeout.wait_inner() {
+                    Some(Ok(())) => Some(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "operation timed out",
+                    ))),
+                    _ => Some(Err(e)),
+                }
+            }
+            (item, _) => item,
+        };
+        item.map(|io_result| io_result.map(FromCqe::from_cqe))
     }
 }
 
@@ -127,7 +192,9 @@ impl<'a, C: FromCqe> Future for Completion<'a, C> {
 
         let mut state = self.mu.lock().unwrap();
         if state.item.is_some() {
-            Poll::Ready(state.item.take().unwrap().map(FromCqe::from_cqe))
+            let item = state.item.take();
+            drop(state);
+            Poll::Ready(self.finish(item).unwrap())
         } else {
             if !state.done {
                 state.waker = Some(cx.waker().clone());
@@ -2,33 +2,59 @@ use hdrhistogram::Histogram;
 use std::alloc::{alloc, dealloc, Layout};
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::OpenOptionsExt;
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::ptr::NonNull;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, instrument, warn};
 
-use super::page::Page;
+use super::completion::Completion;
+use super::io_uring::{Config, FixedFile, Rio};
+use super::manifest::{Manifest, ManifestError, MANIFEST_REGION_SIZE};
+use super::page::{CorruptionError, Page, FORMAT_VERSION};
+use super::AsIoVec;
 
 const O_DIRECT: i32 = 0o0040000;
+/// Most pages transferred by one vectored syscall or one batch of io_uring requests
+const MAX_BATCH_PAGES: usize = 64;
+/// Page buffers registered with the ring of the io_uring backend, enough for a batch
+const FIXED_BUFFERS: usize = MAX_BATCH_PAGES;
 
-struct AlignedBuffer {
+/// Heap buffer aligned to its own size, as `O_DIRECT` I/O requires
+pub(crate) struct AlignedBuffer {
     ptr: NonNull<u8>,
     size: usize,
     layout: Layout,
 }
 
+// SAFETY: the buffer owns its allocation, like a `Vec<u8>`
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
 impl AlignedBuffer {
     /// 创建指定大小的对齐内存缓冲区
-    fn new(size: usize) -> io::Result<Self> {
+    pub(crate) fn new(size: usize) -> io::Result<Self> {
         let layout = Layout::from_size_align(size, size)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
         // SAFETY: 布局大小不为零
         let ptr = unsafe { alloc(layout) };
         if ptr.is_null() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Allocation failed"));
+            return Err(io::Error::other("Allocation failed"));
         }
 
         Ok(Self {
@@ -38,48 +64,251 @@ impl AlignedBuffer {
         })
     }
 
-    fn as_mut_slice(&mut self) -> &mut [u8] {
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
         unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.size) }
     }
 }
 
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.size) }
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
 impl Drop for AlignedBuffer {
     fn drop(&mut self) {
         unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
     }
 }
 
+/// Page buffers and the data file registered with the ring of the
+/// io_uring backends, so that page I/O neither pins a buffer nor
+/// looks the file up on every request
+#[derive(Debug)]
+struct FixedIo {
+    uring: Rio,
+    file: FixedFile,
+    buffers: Vec<AlignedBuffer>,
+    /// Indexes of the buffers not handed out as a `PageBuffer`
+    free: Mutex<Vec<u16>>,
+}
+
+impl FixedIo {
+    fn register(uring: &Rio, file: &File, page_size: usize) -> io::Result<FixedIo> {
+        let buffers = (0..FIXED_BUFFERS)
+            .map(|_| AlignedBuffer::new(page_size))
+            .collect::<io::Result<Vec<_>>>()?;
+        // SAFETY: the buffers live on the heap, owned by the returned value,
+        // until its `drop` unregisters them. If registering the file fails
+        // they are unregistered before they are dropped.
+        unsafe { uring.register_buffers(&buffers)? };
+        let file = match uring.register_files(&[file]) {
+            Ok(files) => files[0],
+            Err(e) => {
+                let _ = uring.unregister_buffers();
+                return Err(e);
+            }
+        };
+        Ok(FixedIo {
+            uring: uring.clone(),
+            file,
+            buffers,
+            free: Mutex::new((0..FIXED_BUFFERS as u16).collect()),
+        })
+    }
+}
+
+impl Drop for FixedIo {
+    fn drop(&mut self) {
+        // The ring may outlive the device, e.g. shared with `SharedDatabase`
+        if let Err(e) = self.uring.unregister_files() {
+            error!("Failed to unregister the data file: {:?}", e);
+        }
+        if let Err(e) = self.uring.unregister_buffers() {
+            error!("Failed to unregister page buffers: {:?}", e);
+        }
+    }
+}
+
+/// Starts a ring polling for the completions of requests on `file`, None if
+/// its device can't be polled
+fn start_polled_ring(file: &File) -> io::Result<Option<Rio>> {
+    let uring = Config {
+        io_poll: true,
+        ..Config::default()
+    }
+    .start()?;
+    // Support for polling is only checked once a request reaches the
+    // device, e.g. a read of the manifest
+    let buffer = AlignedBuffer::new(MANIFEST_REGION_SIZE as usize)?;
+    match uring.read_at(file, &buffer, 0).wait() {
+        Ok(_) => Ok(Some(uring)),
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Buffer of one page, one of the registered buffers while any is free
+enum PageBuffer<'a> {
+    Fixed { fixed: &'a FixedIo, index: u16 },
+    Owned(AlignedBuffer),
+}
+
+impl PageBuffer<'_> {
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            PageBuffer::Fixed { fixed, index } => {
+                let buffer = &fixed.buffers[*index as usize];
+                // SAFETY: the index was taken off the free list, nothing
+                // else accesses the buffer until it is returned
+                unsafe { std::slice::from_raw_parts_mut(buffer.ptr.as_ptr(), buffer.size) }
+            }
+            PageBuffer::Owned(buffer) => buffer.as_mut_slice(),
+        }
+    }
+}
+
+impl AsRef<[u8]> for PageBuffer<'_> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            PageBuffer::Fixed { fixed, index } => fixed.buffers[*index as usize].as_ref(),
+            PageBuffer::Owned(buffer) => buffer.as_ref(),
+        }
+    }
+}
+
+impl AsMut<[u8]> for PageBuffer<'_> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl Drop for PageBuffer<'_> {
+    fn drop(&mut self) {
+        if let PageBuffer::Fixed { fixed, index } = self {
+            fixed.free.lock().unwrap().push(*index);
+        }
+    }
+}
+
+/// Copies an error reported for several pages
+fn clone_error(error: &io::Error) -> io::Error {
+    match error.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(error.kind(), error.to_string()),
+    }
+}
+
+/// When page writes are synced to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never sync, leave it to the OS. Fastest, meant for benchmarks.
+    #[default]
+    None,
+    /// `fsync` after every page write
+    Fsync,
+    /// `fdatasync` after every page write
+    Fdatasync,
+    /// `fdatasync` from a background thread at the given interval,
+    /// skipped when nothing was written since the last sync
+    Periodic(Duration),
+}
+
+/// How the device issues page reads, writes and syncs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoBackend {
+    /// One blocking syscall per request
+    #[default]
+    Syscall,
+    /// Requests submitted to an io_uring owned by the device, waited for
+    /// one at a time like the syscalls
+    IoUring,
+    /// Like `IoUring`, with a ring polling the device for completions
+    /// instead of waiting for its interrupts (`IORING_SETUP_IOPOLL`). Such a
+    /// ring can't sync or time requests out, so syncs go through syscalls
+    /// and reads are not bounded. Devices that can't be polled get an
+    /// `IoUring` ring instead.
+    IoUringPolled,
+}
+
+/// Background thread syncing the device for `Durability::Periodic`
+#[derive(Debug)]
+struct PeriodicSync {
+    // Dropping the sender stops the thread
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for PeriodicSync {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SsdDevice {
     file: File,
     page_size: u32,
+    /// Locked while it is written, so that the last write is the one kept
+    manifest: Mutex<Manifest>,
     metrics: SsdMetrics,
+    durability: Durability,
+    /// Set by writes, cleared by the periodic sync thread
+    unsynced: Arc<AtomicBool>,
+    periodic_sync: Option<PeriodicSync>,
+    /// Ring of the io_uring backends
+    uring: Option<Rio>,
+    /// `uring` polls for completions, see `IoBackend::IoUringPolled`
+    io_poll: bool,
+    /// Buffers and file registered with `uring`, None if registering failed
+    fixed: Option<FixedIo>,
+    /// Longest a page read through `uring` may take
+    read_timeout: Option<Duration>,
 }
 
+/// Device counters, updated through shared references so that pages can be
+/// read from several threads at once
 pub struct SsdMetrics {
-    reads: u64,
-    writes: u64,
-    read_bytes: u64,
-    write_bytes: u64,
-    read_latency_hist: Histogram<u64>,
-    write_latency_hist: Histogram<u64>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    discards: AtomicU64,
+    read_latency_hist: Mutex<Histogram<u64>>,
+    write_latency_hist: Mutex<Histogram<u64>>,
+    // Shared with the periodic sync thread
+    sync_latency_hist: Arc<Mutex<Histogram<u64>>>,
 }
 
 impl Default for SsdMetrics {
     fn default() -> Self {
         Self {
-            reads: 0,
-            writes: 0,
-            read_bytes: 0,
-            write_bytes: 0,
-            read_latency_hist: Histogram::<u64>::new(3).unwrap(), // 3 significant figures
-            write_latency_hist: Histogram::<u64>::new(3).unwrap(),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            write_bytes: AtomicU64::new(0),
+            discards: AtomicU64::new(0),
+            read_latency_hist: Mutex::new(Histogram::<u64>::new(3).unwrap()), // 3 significant figures
+            write_latency_hist: Mutex::new(Histogram::<u64>::new(3).unwrap()),
+            sync_latency_hist: Arc::new(Mutex::new(Histogram::<u64>::new(3).unwrap())),
         }
     }
 }
 
 impl fmt::Display for SsdMetrics {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let read_latency_hist = self.read_latency_hist.lock().unwrap();
+        let write_latency_hist = self.write_latency_hist.lock().unwrap();
+        let sync_latency_hist = self.sync_latency_hist.lock().unwrap();
         write!(
             f,
             "SsdMetrics:
@@ -87,55 +316,82 @@ impl fmt::Display for SsdMetrics {
   Writes: {}
   Read Bytes: {}
   Write Bytes: {}
+  Discards: {}
   Read Latency (μs):
     p50: {:.2}
     p95: {:.2}
     p99: {:.2}
     max: {:.2}
   Write Latency (μs):
+    p50: {:.2}
+    p95: {:.2}
+    p99: {:.2}
+    max: {:.2}
+  Syncs: {}
+  Sync Latency (μs):
     p50: {:.2}
     p95: {:.2}
     p99: {:.2}
     max: {:.2}",
-            self.reads,
-            self.writes,
-            self.read_bytes,
-            self.write_bytes,
-            self.read_latency_hist.value_at_percentile(50.0) as f64 / 1000.0,
-            self.read_latency_hist.value_at_percentile(95.0) as f64 / 1000.0,
-            self.read_latency_hist.value_at_percentile(99.0) as f64 / 1000.0,
-            self.read_latency_hist.max() as f64 / 1000.0,
-            self.write_latency_hist.value_at_percentile(50.0) as f64 / 1000.0,
-            self.write_latency_hist.value_at_percentile(95.0) as f64 / 1000.0,
-            self.write_latency_hist.value_at_percentile(99.0) as f64 / 1000.0,
-            self.write_latency_hist.max() as f64 / 1000.0,
+            self.reads(),
+            self.writes(),
+            self.read_bytes(),
+            self.write_bytes(),
+            self.discards(),
+            read_latency_hist.value_at_percentile(50.0) as f64 / 1000.0,
+            read_latency_hist.value_at_percentile(95.0) as f64 / 1000.0,
+            read_latency_hist.value_at_percentile(99.0) as f64 / 1000.0,
+            read_latency_hist.max() as f64 / 1000.0,
+            write_latency_hist.value_at_percentile(50.0) as f64 / 1000.0,
+            write_latency_hist.value_at_percentile(95.0) as f64 / 1000.0,
+            write_latency_hist.value_at_percentile(99.0) as f64 / 1000.0,
+            write_latency_hist.max() as f64 / 1000.0,
+            sync_latency_hist.len(),
+            sync_latency_hist.value_at_percentile(50.0) as f64 / 1000.0,
+            sync_latency_hist.value_at_percentile(95.0) as f64 / 1000.0,
+            sync_latency_hist.value_at_percentile(99.0) as f64 / 1000.0,
+            sync_latency_hist.max() as f64 / 1000.0,
         )
     }
 }
 
 impl fmt::Debug for SsdMetrics {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let read_latency_hist = self.read_latency_hist.lock().unwrap();
+        let write_latency_hist = self.write_latency_hist.lock().unwrap();
+        let sync_latency_hist = self.sync_latency_hist.lock().unwrap();
         f.debug_struct("SsdMetrics")
-            .field("reads", &self.reads)
-            .field("writes", &self.writes)
-            .field("read_bytes", &self.read_bytes)
-            .field("write_bytes", &self.write_bytes)
+            .field("reads", &self.reads())
+            .field("writes", &self.writes())
+            .field("read_bytes", &self.read_bytes())
+            .field("write_bytes", &self.write_bytes())
+            .field("discards", &self.discards())
             .field(
                 "read_latency_hist (p50, p95, p99, max)",
                 &(
-                    self.read_latency_hist.value_at_percentile(50.0),
-                    self.read_latency_hist.value_at_percentile(95.0),
-                    self.read_latency_hist.value_at_percentile(99.0),
-                    self.read_latency_hist.max(),
+                    read_latency_hist.value_at_percentile(50.0),
+                    read_latency_hist.value_at_percentile(95.0),
+                    read_latency_hist.value_at_percentile(99.0),
+                    read_latency_hist.max(),
                 ),
             )
             .field(
                 "write_latency_hist (p50, p95, p99, max)",
                 &(
-                    self.write_latency_hist.value_at_percentile(50.0),
-                    self.write_latency_hist.value_at_percentile(95.0),
-                    self.write_latency_hist.value_at_percentile(99.0),
-                    self.write_latency_hist.max(),
+                    write_latency_hist.value_at_percentile(50.0),
+                    write_latency_hist.value_at_percentile(95.0),
+                    write_latency_hist.value_at_percentile(99.0),
+                    write_latency_hist.max(),
+                ),
+            )
+            .field("syncs", &sync_latency_hist.len())
+            .field(
+                "sync_latency_hist (p50, p95, p99, max)",
+                &(
+                    sync_latency_hist.value_at_percentile(50.0),
+                    sync_latency_hist.value_at_percentile(95.0),
+                    sync_latency_hist.value_at_percentile(99.0),
+                    sync_latency_hist.max(),
                 ),
             )
             .finish()
@@ -144,27 +400,83 @@ impl fmt::Debug for SsdMetrics {
 
 impl SsdMetrics {
     pub fn reads(&self) -> u64 {
-        self.reads
+        self.reads.load(Ordering::Relaxed)
     }
 
     pub fn writes(&self) -> u64 {
-        self.writes
+        self.writes.load(Ordering::Relaxed)
     }
 
     pub fn read_bytes(&self) -> u64 {
-        self.read_bytes
+        self.read_bytes.load(Ordering::Relaxed)
     }
 
     pub fn write_bytes(&self) -> u64 {
-        self.write_bytes
+        self.write_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn discards(&self) -> u64 {
+        self.discards.load(Ordering::Relaxed)
     }
 
     pub fn read_latency_percentile(&self, percentile: f64) -> f64 {
-        self.read_latency_hist.value_at_percentile(percentile) as f64 / 1000.0
+        self.read_latency_hist
+            .lock()
+            .unwrap()
+            .value_at_percentile(percentile) as f64
+            / 1000.0
     }
 
     pub fn write_latency_percentile(&self, percentile: f64) -> f64 {
-        self.write_latency_hist.value_at_percentile(percentile) as f64 / 1000.0
+        self.write_latency_hist
+            .lock()
+            .unwrap()
+            .value_at_percentile(percentile) as f64
+            / 1000.0
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.sync_latency_hist.lock().unwrap().len()
+    }
+
+    pub fn sync_latency_percentile(&self, percentile: f64) -> f64 {
+        self.sync_latency_hist
+            .lock()
+            .unwrap()
+            .value_at_percentile(percentile) as f64
+            / 1000.0
+    }
+
+    pub(crate) fn record_read(&self, bytes: usize, elapsed_nanos: u64) {
+        self.read_latency_hist
+            .lock()
+            .unwrap()
+            .record(elapsed_nanos)
+            .unwrap();
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write(&self, bytes: usize, elapsed_nanos: u64) {
+        self.write_latency_hist
+            .lock()
+            .unwrap()
+            .record(elapsed_nanos)
+            .unwrap();
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_discard(&self) {
+        self.discards.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sync(&self, elapsed_nanos: u64) {
+        self.sync_latency_hist
+            .lock()
+            .unwrap()
+            .record(elapsed_nanos)
+            .unwrap();
     }
 }
 
@@ -173,6 +485,19 @@ pub enum SsdError {
     Io(io::Error),
     InvalidPageSize,
     InvalidPageId,
+    InvalidManifest(ManifestError),
+    IncompatibleFormat {
+        expected: u32,
+        found: u32,
+    },
+    PageSizeMismatch {
+        expected: u32,
+        found: u32,
+    },
+    Corruption {
+        page_id: u64,
+        error: CorruptionError,
+    },
 }
 
 impl From<io::Error> for SsdError {
@@ -182,75 +507,295 @@ impl From<io::Error> for SsdError {
 }
 
 impl SsdDevice {
-    /// Creates a new SSD device with the specified page size
+    /// Opens the SSD device at `path` with the specified page size, creating
+    /// the backing file if it does not exist. Existing pages are kept.
     #[instrument(skip(path))]
     pub fn new<P: AsRef<Path>>(path: P, page_size: u32) -> Result<Self, SsdError> {
+        Self::open_with(path, page_size, false, IoBackend::Syscall)
+    }
+
+    /// Creates an empty SSD device at `path`, discarding any existing pages
+    #[instrument(skip(path))]
+    pub fn create<P: AsRef<Path>>(path: P, page_size: u32) -> Result<Self, SsdError> {
+        Self::open_with(path, page_size, true, IoBackend::Syscall)
+    }
+
+    /// Like `new`, issuing page I/O through `backend`
+    #[instrument(skip(path))]
+    pub fn new_with_backend<P: AsRef<Path>>(
+        path: P,
+        page_size: u32,
+        backend: IoBackend,
+    ) -> Result<Self, SsdError> {
+        Self::open_with(path, page_size, false, backend)
+    }
+
+    /// Like `create`, issuing page I/O through `backend`
+    #[instrument(skip(path))]
+    pub fn create_with_backend<P: AsRef<Path>>(
+        path: P,
+        page_size: u32,
+        backend: IoBackend,
+    ) -> Result<Self, SsdError> {
+        Self::open_with(path, page_size, true, backend)
+    }
+
+    fn open_with<P: AsRef<Path>>(
+        path: P,
+        page_size: u32,
+        truncate: bool,
+        backend: IoBackend,
+    ) -> Result<Self, SsdError> {
         if page_size == 0 {
             error!("Attempted to create SsdDevice with invalid page size: 0");
             return Err(SsdError::InvalidPageSize);
         }
-        info!("Creating new SsdDevice with page_size: {}", page_size);
+        info!(
+            "Creating new SsdDevice with page_size: {}, backend: {:?}",
+            page_size, backend
+        );
 
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .custom_flags(O_DIRECT)
             .create(true)
+            .truncate(truncate)
             .open(path)?;
-
-        Ok(SsdDevice {
+        let mut device = SsdDevice {
             file,
             page_size,
+            manifest: Mutex::new(Manifest::new(page_size)),
             metrics: SsdMetrics::default(),
+            durability: Durability::None,
+            unsynced: Arc::new(AtomicBool::new(false)),
+            periodic_sync: None,
+            uring: None,
+            io_poll: false,
+            fixed: None,
+            read_timeout: None,
+        };
+
+        if device.file.metadata()?.len() == 0 {
+            info!("Writing manifest for new device");
+            device.write_manifest(Manifest::new(page_size))?;
+        } else {
+            let manifest = device.read_manifest()?;
+            device.validate_manifest(&manifest)?;
+            *device.manifest.get_mut().unwrap() = manifest;
+        }
+
+        // Started once the manifest is there, for a polled ring to be probed
+        let (uring, io_poll) = match backend {
+            IoBackend::Syscall => (None, false),
+            IoBackend::IoUring => (Some(super::new()?), false),
+            IoBackend::IoUringPolled => match start_polled_ring(&device.file)? {
+                Some(uring) => (Some(uring), true),
+                None => {
+                    warn!("Device can't be polled, completing io_uring requests on interrupts");
+                    (Some(super::new()?), false)
+                }
+            },
+        };
+        let fixed = match &uring {
+            Some(uring) => match FixedIo::register(uring, &device.file, page_size as usize) {
+                Ok(fixed) => Some(fixed),
+                Err(e) => {
+                    // Page I/O still works with buffers pinned per request
+                    warn!("Failed to register page buffers with io_uring: {:?}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        device.uring = uring;
+        device.io_poll = io_poll;
+        device.fixed = fixed;
+
+        Ok(device)
+    }
+
+    /// Changes when page writes are synced, starting or stopping the
+    /// background sync thread as needed
+    pub fn set_durability(&mut self, durability: Durability) -> Result<(), SsdError> {
+        info!("Setting device durability to {:?}", durability);
+        // Stop the previous background thread, if any
+        self.periodic_sync = None;
+
+        if let Durability::Periodic(interval) = durability {
+            let file = self.file.try_clone()?;
+            let unsynced = Arc::clone(&self.unsynced);
+            let sync_latency_hist = Arc::clone(&self.metrics.sync_latency_hist);
+            let (stop, stopped) = mpsc::channel::<()>();
+
+            let handle = thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if !unsynced.swap(false, Ordering::AcqRel) {
+                        continue;
+                    }
+                    let start = Instant::now();
+                    if let Err(e) = file.sync_data() {
+                        error!("Periodic sync failed: {:?}", e);
+                        unsynced.store(true, Ordering::Release);
+                        continue;
+                    }
+                    let elapsed_nanos = start.elapsed().as_nanos() as u64;
+                    sync_latency_hist
+                        .lock()
+                        .unwrap()
+                        .record(elapsed_nanos)
+                        .unwrap();
+                }
+            });
+
+            self.periodic_sync = Some(PeriodicSync {
+                stop: Some(stop),
+                handle: Some(handle),
+            });
+        }
+
+        self.durability = durability;
+        Ok(())
+    }
+
+    /// Returns the current durability policy
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Returns how the device issues page I/O
+    pub fn io_backend(&self) -> IoBackend {
+        match (&self.uring, self.io_poll) {
+            (Some(_), true) => IoBackend::IoUringPolled,
+            (Some(_), false) => IoBackend::IoUring,
+            (None, _) => IoBackend::Syscall,
+        }
+    }
+
+    /// Bounds how long a page read may take with the `IoBackend::IoUring`
+    /// backend, reads still running then are cancelled and fail with
+    /// `io::ErrorKind::TimedOut`. Syscall reads and reads through a polled
+    /// ring can't be bounded.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Returns the ring of the `IoBackend::IoUring` backend. A polled ring
+    /// isn't shared, it only takes the device's page reads and writes.
+    pub(crate) fn uring(&self) -> Option<&Rio> {
+        self.uring.as_ref().filter(|_| !self.io_poll)
+    }
+
+    /// Returns a copy of the manifest stored at the start of the device
+    pub fn manifest(&self) -> Manifest {
+        self.manifest.lock().unwrap().clone()
+    }
+
+    /// Persists `manifest` to the reserved region at the start of the device
+    #[instrument(skip(self))]
+    pub fn write_manifest(&self, manifest: Manifest) -> Result<(), SsdError> {
+        let mut buffer = AlignedBuffer::new(MANIFEST_REGION_SIZE as usize)?;
+        buffer.as_mut_slice().fill(0);
+        manifest.write_to_buffer(buffer.as_mut_slice());
+
+        let mut current = self.manifest.lock().unwrap();
+        self.file.write_all_at(buffer.as_ref(), 0)?;
+        *current = manifest;
+        Ok(())
+    }
+
+    fn read_manifest(&self) -> Result<Manifest, SsdError> {
+        let mut buffer = AlignedBuffer::new(MANIFEST_REGION_SIZE as usize)?;
+        self.file.read_exact_at(buffer.as_mut_slice(), 0)?;
+        Manifest::read_from_buffer(buffer.as_mut_slice()).map_err(|e| {
+            error!("Failed to decode manifest: {:?}", e);
+            SsdError::InvalidManifest(e)
         })
     }
 
-    /// Reads a page from the device
+    fn validate_manifest(&self, manifest: &Manifest) -> Result<(), SsdError> {
+        if manifest.format_version != FORMAT_VERSION {
+            error!(
+                "Incompatible format version: expected {}, found {}",
+                FORMAT_VERSION, manifest.format_version
+            );
+            return Err(SsdError::IncompatibleFormat {
+                expected: FORMAT_VERSION,
+                found: manifest.format_version,
+            });
+        }
+        if manifest.page_size != self.page_size {
+            error!(
+                "Page size mismatch: expected {}, found {}",
+                self.page_size, manifest.page_size
+            );
+            return Err(SsdError::PageSizeMismatch {
+                expected: self.page_size,
+                found: manifest.page_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads a page from the device. Reads use positioned I/O and may be
+    /// issued from several threads at once.
     #[instrument(skip(self))]
-    pub fn read_page(&mut self, page_id: u64) -> Result<Page, SsdError> {
+    pub fn read_page(&self, page_id: u64) -> Result<Page, SsdError> {
         debug!("Reading page {} from device", page_id);
 
-        let mut buffer =
-            AlignedBuffer::new(self.page_size as usize).map_err(|e| SsdError::Io(e))?;
+        let mut buffer = self.page_buffer().map_err(SsdError::Io)?;
 
         let offset = self.calculate_offset(page_id);
-        self.file.seek(SeekFrom::Start(offset))?;
         let start = Instant::now();
-        let bytes_read = self
-            .file
-            .read(buffer.as_mut_slice())
-            .map_err(SsdError::Io)?;
-        assert_eq!(bytes_read, self.page_size as usize);
+        let bytes_read = match &self.uring {
+            Some(uring) => self.submit(uring, &buffer, offset, false).wait(),
+            None => self.file.read_at(buffer.as_mut_slice(), offset),
+        }
+        .map_err(SsdError::Io)?;
         // Record latency in nanoseconds
         let elapsed_nanos = start.elapsed().as_nanos() as u64;
-        self.metrics
-            .read_latency_hist
-            .record(elapsed_nanos)
-            .unwrap();
-
-        // Update metrics
-        self.metrics.reads += 1;
-        self.metrics.read_bytes += bytes_read as u64;
+        self.metrics.record_read(bytes_read, elapsed_nanos);
 
-        if bytes_read == 0 {
-            // Create a new empty page if we're reading beyond the file
-            warn!(
-                "Reading beyond file end for page {}, creating empty page",
-                page_id
-            );
-            Ok(Page::new(page_id, self.page_size))
-        } else {
-            debug!(
-                "Successfully read {} bytes for page {}",
-                bytes_read, page_id
-            );
-            Ok(Page::read_from_buffer(&buffer.as_mut_slice()))
+        if bytes_read != self.page_size as usize {
+            // The data file is shorter than the page, e.g. truncated
+            return Err(SsdError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short read of page {}: {} bytes", page_id, bytes_read),
+            )));
         }
+        debug!(
+            "Successfully read {} bytes for page {}",
+            bytes_read, page_id
+        );
+        Self::decode_page(page_id, buffer.as_ref())
+    }
+
+    /// Decodes page `page_id` from a buffer read from the device
+    pub(crate) fn decode_page(page_id: u64, buffer: &[u8]) -> Result<Page, SsdError> {
+        Page::read_from_buffer(buffer)
+            .and_then(|page| {
+                if page.id() == page_id {
+                    Ok(page)
+                } else {
+                    Err(CorruptionError::PageIdMismatch {
+                        expected: page_id,
+                        found: page.id(),
+                    })
+                }
+            })
+            .map_err(|error| {
+                error!("Page {} is corrupted: {:?}", page_id, error);
+                SsdError::Corruption { page_id, error }
+            })
     }
 
     /// Writes a page to the device
     #[instrument(skip(self, page))]
-    pub fn write_page(&mut self, page: &mut Page) -> Result<(), SsdError> {
+    pub fn write_page(&self, page: &mut Page) -> Result<(), SsdError> {
         if page.capacity() as u32 != self.page_size {
             error!(
                 "Page size mismatch: expected {}, got {}",
@@ -261,49 +806,328 @@ impl SsdDevice {
         }
         debug!("Writing page {} to device", page.id());
 
-        let offset = self.calculate_offset(page.id());
-        self.file.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buffer = self.page_buffer()?;
+        buffer.as_mut_slice().fill(0);
+        page.write_to_buffer(buffer.as_mut_slice());
+        self.write_buffer(page.id(), buffer)
+    }
 
-        let size = self.page_size as usize;
-        let layout = Layout::from_size_align(size, size).unwrap();
-        let ptr = unsafe { alloc(layout) as *mut u8 };
-        let mut buffer = unsafe { Vec::from_raw_parts(ptr, size, size) };
-        page.write_to_buffer(&mut buffer);
+    /// Writes a buffer of a whole page to the place of page `page_id`
+    fn write_buffer(&self, page_id: u64, buffer: PageBuffer) -> Result<(), SsdError> {
+        let offset = self.calculate_offset(page_id);
         let start = Instant::now();
-        let bytes_written = self.file.write(&buffer).unwrap();
+        let bytes_written = match &self.uring {
+            Some(uring) => self.submit(uring, &buffer, offset, true).wait()?,
+            None => self.file.write_at(buffer.as_ref(), offset)?,
+        };
+        drop(buffer);
         // Record latency in nanoseconds
         let elapsed_nanos = start.elapsed().as_nanos() as u64;
-        self.metrics
-            .write_latency_hist
-            .record(elapsed_nanos)
-            .unwrap();
+        self.metrics.record_write(bytes_written, elapsed_nanos);
+        self.unsynced.store(true, Ordering::Release);
+        if bytes_written != self.page_size as usize {
+            return Err(SsdError::Io(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short write of page {}: {} bytes", page_id, bytes_written),
+            )));
+        }
 
-        // Update metrics
-        self.metrics.writes += 1;
-        self.metrics.write_bytes += bytes_written as u64;
-        // self.sync().unwrap();
-
-        // Manually deallocate the memory
-        unsafe {
-            let ptr = buffer.as_mut_ptr();
-            let capacity = buffer.capacity();
-            std::mem::forget(buffer); // Prevent double-free
-            dealloc(
-                ptr as *mut u8,
-                Layout::from_size_align(capacity, capacity).unwrap(),
-            );
+        self.sync_for_durability()
+    }
+
+    /// Reads several pages, in the order of `page_ids`. Pages adjacent on
+    /// the device are read with one `preadv` each, or with the io_uring
+    /// backend all pages are submitted as one batch. Every page counts as a
+    /// read taking as long as its batch.
+    #[instrument(skip(self, page_ids))]
+    pub fn read_pages(&self, page_ids: &[u64]) -> Vec<Result<Page, SsdError>> {
+        debug!("Reading {} pages from device", page_ids.len());
+        let page_size = self.page_size as usize;
+        let mut results: Vec<Option<Result<Page, SsdError>>> =
+            page_ids.iter().map(|_| None).collect();
+
+        for batch in self.batches(page_ids) {
+            let buffers = match batch
+                .iter()
+                .map(|_| self.page_buffer())
+                .collect::<io::Result<Vec<_>>>()
+            {
+                Ok(buffers) => buffers,
+                Err(e) => {
+                    return page_ids
+                        .iter()
+                        .map(|_| Err(SsdError::Io(clone_error(&e))))
+                        .collect()
+                }
+            };
+
+            let start = Instant::now();
+            let read = self.transfer(&batch, &buffers, false);
+            let elapsed_nanos = start.elapsed().as_nanos() as u64;
+            for ((index, page_id), (buffer, read)) in batch.iter().zip(buffers.iter().zip(read)) {
+                results[*index] = Some(read.map_err(SsdError::Io).and_then(|bytes_read| {
+                    self.metrics.record_read(bytes_read, elapsed_nanos);
+                    if bytes_read != page_size {
+                        return Err(SsdError::Io(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("short read of page {}: {} bytes", page_id, bytes_read),
+                        )));
+                    }
+                    Self::decode_page(*page_id, buffer.as_ref())
+                }));
+            }
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Writes several pages like `read_pages` reads them, syncing once at
+    /// the end if the durability asks for it
+    #[instrument(skip(self, pages))]
+    pub fn write_pages(&self, pages: &mut [&mut Page]) -> Result<(), SsdError> {
+        debug!("Writing {} pages to device", pages.len());
+        let page_size = self.page_size as usize;
+        if pages.iter().any(|page| page.capacity() != page_size) {
+            error!("Page size mismatch: expected {}", self.page_size);
+            return Err(SsdError::InvalidPageSize);
+        }
+
+        let page_ids: Vec<u64> = pages.iter().map(|page| page.id()).collect();
+        for batch in self.batches(&page_ids) {
+            let mut buffers = Vec::with_capacity(batch.len());
+            for (index, _) in &batch {
+                let mut buffer = self.page_buffer()?;
+                buffer.as_mut_slice().fill(0);
+                pages[*index].write_to_buffer(buffer.as_mut_slice());
+                buffers.push(buffer);
+            }
+
+            let start = Instant::now();
+            let written = self.transfer(&batch, &buffers, true);
+            let elapsed_nanos = start.elapsed().as_nanos() as u64;
+            self.unsynced.store(true, Ordering::Release);
+            for ((_, page_id), written) in batch.iter().zip(written) {
+                let bytes_written = written?;
+                self.metrics.record_write(bytes_written, elapsed_nanos);
+                if bytes_written != page_size {
+                    return Err(SsdError::Io(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        format!("short write of page {}: {} bytes", page_id, bytes_written),
+                    )));
+                }
+            }
+        }
+
+        self.sync_for_durability()
+    }
+
+    /// Takes a buffer for one page, a registered one when any is free
+    fn page_buffer(&self) -> io::Result<PageBuffer<'_>> {
+        if let Some(fixed) = &self.fixed {
+            if let Some(index) = fixed.free.lock().unwrap().pop() {
+                return Ok(PageBuffer::Fixed { fixed, index });
+            }
+        }
+        AlignedBuffer::new(self.page_size as usize).map(PageBuffer::Owned)
+    }
+
+    /// Queues a page read or write on the ring, as a fixed request when the
+    /// buffer is registered
+    fn submit<'a>(
+        &'a self,
+        uring: &'a Rio,
+        buffer: &'a PageBuffer,
+        offset: u64,
+        write: bool,
+    ) -> Completion<'a, usize> {
+        // A polled ring rejects timeouts
+        let read_timeout = self.read_timeout.filter(|_| !self.io_poll);
+        match (buffer, read_timeout) {
+            (PageBuffer::Fixed { fixed, index }, _) if write => {
+                uring.write_fixed(fixed.file, buffer, *index, offset)
+            }
+            (PageBuffer::Fixed { fixed, index }, Some(timeout)) => {
+                uring.read_fixed_timeout(fixed.file, buffer, *index, offset, timeout)
+            }
+            (PageBuffer::Fixed { fixed, index }, None) => {
+                uring.read_fixed(fixed.file, buffer, *index, offset)
+            }
+            (PageBuffer::Owned(_), _) if write => uring.write_at(&self.file, buffer, offset),
+            (PageBuffer::Owned(_), Some(timeout)) => {
+                uring.read_at_timeout(&self.file, buffer, offset, timeout)
+            }
+            (PageBuffer::Owned(_), None) => uring.read_at(&self.file, buffer, offset),
+        }
+    }
+
+    /// Splits pages into batches of at most `MAX_BATCH_PAGES`, each sorted by
+    /// page id. Pages are paired with their index in `page_ids`.
+    fn batches(&self, page_ids: &[u64]) -> Vec<Vec<(usize, u64)>> {
+        let mut sorted: Vec<(usize, u64)> = page_ids.iter().copied().enumerate().collect();
+        sorted.sort_by_key(|&(_, page_id)| page_id);
+        sorted
+            .chunks(MAX_BATCH_PAGES)
+            .map(|batch| batch.to_vec())
+            .collect()
+    }
+
+    /// Reads or writes a batch of pages, returning the bytes transferred
+    /// per page
+    fn transfer(
+        &self,
+        batch: &[(usize, u64)],
+        buffers: &[PageBuffer],
+        write: bool,
+    ) -> Vec<io::Result<usize>> {
+        if let Some(uring) = &self.uring {
+            let completions: Vec<_> = batch
+                .iter()
+                .zip(buffers)
+                .map(|(&(_, page_id), buffer)| {
+                    self.submit(uring, buffer, self.calculate_offset(page_id), write)
+                })
+                .collect();
+            return completions.into_iter().map(|c| c.wait()).collect();
+        }
+
+        // One vectored syscall per run of adjacent pages
+        let page_size = self.page_size as usize;
+        let mut results = Vec::with_capacity(batch.len());
+        let mut start = 0;
+        while start < batch.len() {
+            let mut end = start + 1;
+            while end < batch.len() && batch[end].1 == batch[end - 1].1 + 1 {
+                end += 1;
+            }
+            let iovecs: Vec<libc::iovec> = buffers[start..end]
+                .iter()
+                .map(AsIoVec::into_new_iovec)
+                .collect();
+            let offset = self.calculate_offset(batch[start].1) as libc::off_t;
+            // SAFETY: the iovecs point to buffers of `page_size` bytes that
+            // outlive the call
+            let ret = unsafe {
+                if write {
+                    libc::pwritev(
+                        self.file.as_raw_fd(),
+                        iovecs.as_ptr(),
+                        iovecs.len() as i32,
+                        offset,
+                    )
+                } else {
+                    libc::preadv(
+                        self.file.as_raw_fd(),
+                        iovecs.as_ptr(),
+                        iovecs.len() as i32,
+                        offset,
+                    )
+                }
+            };
+            if ret < 0 {
+                let error = io::Error::last_os_error();
+                results.extend((start..end).map(|_| Err(clone_error(&error))));
+            } else {
+                // A short transfer covers a prefix of the run
+                let mut remaining = ret as usize;
+                for _ in start..end {
+                    let bytes = remaining.min(page_size);
+                    remaining -= bytes;
+                    results.push(Ok(bytes));
+                }
+            }
+            start = end;
+        }
+        results
+    }
+
+    /// Returns the space of a page to the filesystem by punching a hole over
+    /// it, after which the page reads back as never written. Filesystems
+    /// without hole punching get the page overwritten with zeroes instead,
+    /// which reads back the same.
+    #[instrument(skip(self))]
+    pub fn discard_page(&self, page_id: u64) -> Result<(), SsdError> {
+        debug!("Discarding page {} on device", page_id);
+
+        let offset = self.calculate_offset(page_id);
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                self.page_size as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(SsdError::Io(error));
+            }
+            warn!("Hole punching not supported, zeroing page {}", page_id);
+            return self.zero_page(page_id);
+        }
+
+        self.metrics.record_discard();
+        self.unsynced.store(true, Ordering::Release);
+        self.sync_for_durability()
+    }
+
+    /// Overwrites a page with zeroes, it reads back as never written like a
+    /// hole does
+    fn zero_page(&self, page_id: u64) -> Result<(), SsdError> {
+        let mut buffer = self.page_buffer()?;
+        buffer.as_mut_slice().fill(0);
+        self.write_buffer(page_id, buffer)
+    }
+
+    // Sync after a write when the durability mode asks for it
+    fn sync_for_durability(&self) -> Result<(), SsdError> {
+        match self.durability {
+            Durability::Fsync => self.sync()?,
+            Durability::Fdatasync => self.sync_data()?,
+            Durability::None | Durability::Periodic(_) => {}
         }
         Ok(())
     }
 
     /// Ensures all changes are written to disk
     #[instrument(skip(self))]
-    fn sync(&mut self) -> Result<(), SsdError> {
+    pub fn sync(&self) -> Result<(), SsdError> {
         debug!("Syncing device to disk");
-        self.file.sync_all()?;
+        let start = Instant::now();
+        self.unsynced.store(false, Ordering::Release);
+        // A polled ring can't sync
+        match self.uring() {
+            Some(uring) => uring.fsync(&self.file).wait()?,
+            None => self.file.sync_all()?,
+        }
+        self.metrics.record_sync(start.elapsed().as_nanos() as u64);
         Ok(())
     }
 
+    /// Ensures all written data is on disk, skipping metadata not needed to read it back
+    #[instrument(skip(self))]
+    pub fn sync_data(&self) -> Result<(), SsdError> {
+        debug!("Syncing device data to disk");
+        let start = Instant::now();
+        self.unsynced.store(false, Ordering::Release);
+        match self.uring() {
+            Some(uring) => uring.fdatasync(&self.file).wait()?,
+            None => self.file.sync_data()?,
+        }
+        self.metrics.record_sync(start.elapsed().as_nanos() as u64);
+        Ok(())
+    }
+
+    /// Accounts a page write issued around the device, e.g. through io_uring
+    pub(crate) fn record_external_write(&self, bytes: usize, elapsed_nanos: u64) {
+        self.metrics.record_write(bytes, elapsed_nanos);
+        self.unsynced.store(true, Ordering::Release);
+    }
+
+    /// Returns the data file, for I/O issued around the device
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
     /// Returns the current metrics
     pub fn metrics(&self) -> &SsdMetrics {
         &self.metrics
@@ -314,9 +1138,15 @@ impl SsdDevice {
         self.page_size
     }
 
-    // Calculate the offset for a given page ID
-    fn calculate_offset(&self, page_id: u64) -> u64 {
-        page_id * self.page_size as u64
+    /// Returns the number of whole pages stored on the device
+    pub fn page_count(&self) -> Result<u64, SsdError> {
+        let len = self.file.metadata()?.len();
+        Ok(len.saturating_sub(MANIFEST_REGION_SIZE) / self.page_size as u64)
+    }
+
+    // Calculate the offset for a given page ID, pages start after the manifest region
+    pub(crate) fn calculate_offset(&self, page_id: u64) -> u64 {
+        MANIFEST_REGION_SIZE + page_id * self.page_size as u64
     }
 }
 
@@ -332,11 +1162,11 @@ mod tests {
         let file_path = dir.path().join("test.ssd");
 
         // Create device
-        let mut device = SsdDevice::new(&file_path, 4096).unwrap();
+        let device = SsdDevice::new(&file_path, 4096).unwrap();
 
         // Create and write a page
         let mut page = Page::new(0, 4096);
-        page.push_entry(b"key1", b"value1").unwrap();
+        page.push_entry(b"key1", b"value1", 0).unwrap();
         device.write_page(&mut page).unwrap();
 
         // Read the page back
@@ -350,11 +1180,205 @@ mod tests {
         assert!(metrics.write_bytes() > 0);
 
         println!("Metrcis is {metrics}");
+        assert_eq!(metrics.syncs(), 0);
 
         // Cleanup
         fs::remove_file(file_path).unwrap();
     }
 
+    #[test]
+    fn test_io_uring_backend() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("uring.ssd");
+        let mut device =
+            SsdDevice::create_with_backend(&file_path, 4096, IoBackend::IoUring).unwrap();
+        assert_eq!(device.io_backend(), IoBackend::IoUring);
+        device.set_durability(Durability::Fdatasync).unwrap();
+
+        for page_id in 0..4 {
+            let mut page = Page::new(page_id, 4096);
+            page.push_entry(b"key", &page_id.to_le_bytes(), page_id)
+                .unwrap();
+            device.write_page(&mut page).unwrap();
+        }
+        for page_id in 0..4 {
+            let page = device.read_page(page_id).unwrap();
+            assert_eq!(page.get(0, b"key").unwrap(), page_id.to_le_bytes());
+        }
+
+        // Once the registered buffers are all taken, pages are read through
+        // buffers pinned per request
+        assert!(device.fixed.is_some());
+        let taken: Vec<_> = (0..FIXED_BUFFERS)
+            .map(|_| device.page_buffer().unwrap())
+            .collect();
+        assert!(matches!(taken[0], PageBuffer::Fixed { .. }));
+        assert!(matches!(
+            device.page_buffer().unwrap(),
+            PageBuffer::Owned(_)
+        ));
+        let page = device.read_page(2).unwrap();
+        assert_eq!(page.get(0, b"key").unwrap(), 2u64.to_le_bytes());
+        drop(taken);
+
+        // A generous timeout doesn't get in the way of reads
+        device.set_read_timeout(Some(Duration::from_secs(5)));
+        let page = device.read_page(1).unwrap();
+        assert_eq!(page.get(0, b"key").unwrap(), 1u64.to_le_bytes());
+        device.set_read_timeout(None);
+
+        // Counted like the syscall backend, so both can be compared
+        let metrics = device.metrics();
+        assert_eq!(metrics.reads(), 6);
+        assert_eq!(metrics.writes(), 4);
+        assert_eq!(metrics.write_bytes(), 4 * 4096);
+        assert_eq!(metrics.syncs(), 4);
+        drop(device);
+
+        // Pages written through the ring read back through syscalls
+        let device = SsdDevice::new(&file_path, 4096).unwrap();
+        assert_eq!(device.io_backend(), IoBackend::Syscall);
+        assert_eq!(device.page_count().unwrap(), 4);
+        let page = device.read_page(3).unwrap();
+        assert_eq!(page.get(0, b"key").unwrap(), 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_io_uring_polled_backend() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("polled.ssd");
+        let mut device =
+            SsdDevice::create_with_backend(&file_path, 4096, IoBackend::IoUringPolled).unwrap();
+        // Falls back to interrupts where the device can't be polled
+        let polled = device.io_backend() == IoBackend::IoUringPolled;
+        assert!(polled || device.io_backend() == IoBackend::IoUring);
+        assert_eq!(device.uring().is_some(), !polled);
+
+        // Syncs go through syscalls and read timeouts are ignored on a
+        // polled ring
+        device.set_durability(Durability::Fdatasync).unwrap();
+        device.set_read_timeout(Some(Duration::from_secs(5)));
+        for page_id in 0..4 {
+            let mut page = Page::new(page_id, 4096);
+            page.push_entry(b"key", &page_id.to_le_bytes(), page_id)
+                .unwrap();
+            device.write_page(&mut page).unwrap();
+        }
+        device.sync().unwrap();
+        for page_id in 0..4 {
+            let page = device.read_page(page_id).unwrap();
+            assert_eq!(page.get(0, b"key").unwrap(), page_id.to_le_bytes());
+        }
+        assert_eq!(device.metrics().syncs(), 5);
+    }
+
+    #[test]
+    fn test_batched_page_io() {
+        for backend in [
+            IoBackend::Syscall,
+            IoBackend::IoUring,
+            IoBackend::IoUringPolled,
+        ] {
+            let dir = tempdir().unwrap();
+            let file_path = dir.path().join("batched.ssd");
+            let device = SsdDevice::create_with_backend(&file_path, 4096, backend).unwrap();
+
+            // More pages than fit a batch, out of order
+            let mut pages: Vec<Page> = (0..70u64)
+                .rev()
+                .map(|page_id| {
+                    let mut page = Page::new(page_id, 4096);
+                    page.push_entry(b"key", &page_id.to_le_bytes(), page_id)
+                        .unwrap();
+                    page
+                })
+                .collect();
+            let mut refs: Vec<&mut Page> = pages.iter_mut().collect();
+            device.write_pages(&mut refs).unwrap();
+            assert_eq!(device.page_count().unwrap(), 70);
+            assert_eq!(device.metrics().writes(), 70);
+
+            // Results come back in the order asked, pages past the end of
+            // the file fail on their own
+            let page_ids = [69, 3, 68, 70, 4, 0];
+            let reads = device.read_pages(&page_ids);
+            for (page_id, read) in page_ids.iter().zip(reads) {
+                if *page_id == 70 {
+                    assert!(
+                        matches!(read, Err(SsdError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof)
+                    );
+                } else {
+                    let page = read.unwrap();
+                    assert_eq!(page.get(0, b"key").unwrap(), page_id.to_le_bytes());
+                }
+            }
+            assert_eq!(device.metrics().reads(), 6);
+        }
+    }
+
+    #[test]
+    fn test_discard_page() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("discard.ssd");
+        let device = SsdDevice::create(&file_path, 4096).unwrap();
+
+        for page_id in 0..2 {
+            let mut page = Page::new(page_id, 4096);
+            page.push_entry(b"key1", b"value1", page_id).unwrap();
+            device.write_page(&mut page).unwrap();
+        }
+        device.discard_page(0).unwrap();
+
+        // The file keeps its size, the discarded page reads as never written
+        assert_eq!(device.page_count().unwrap(), 2);
+        assert!(matches!(
+            device.read_page(0),
+            Err(SsdError::Corruption {
+                page_id: 0,
+                error: CorruptionError::Unwritten
+            })
+        ));
+        assert_eq!(device.read_page(1).unwrap().id(), 1);
+
+        // So does a page zeroed where holes can't be punched
+        device.zero_page(1).unwrap();
+        assert!(matches!(
+            device.read_page(1),
+            Err(SsdError::Corruption {
+                page_id: 1,
+                error: CorruptionError::Unwritten
+            })
+        ));
+    }
+
+    #[test]
+    fn test_read_from_truncated_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("truncated.ssd");
+        let device = SsdDevice::create(&file_path, 4096).unwrap();
+        for page_id in 0..2 {
+            let mut page = Page::new(page_id, 4096);
+            page.push_entry(b"key1", b"value1", page_id).unwrap();
+            device.write_page(&mut page).unwrap();
+        }
+        drop(device);
+
+        // Cut the second page short
+        let file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.set_len(MANIFEST_REGION_SIZE + 4096 + 100).unwrap();
+        drop(file);
+
+        let device = SsdDevice::new(&file_path, 4096).unwrap();
+        assert_eq!(device.read_page(0).unwrap().id(), 0);
+        // Short reads and reads past the end fail instead of panicking
+        for page_id in 1..3 {
+            assert!(matches!(
+                device.read_page(page_id),
+                Err(SsdError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
     #[test]
     fn test_invalid_page_size() {
         let dir = tempdir().unwrap();
@@ -363,4 +1387,59 @@ mod tests {
         let result = SsdDevice::new(&file_path, 0);
         assert!(matches!(result, Err(SsdError::InvalidPageSize)));
     }
+
+    #[test]
+    fn test_durability_modes() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("durability.ssd");
+        let mut device = SsdDevice::create(&file_path, 4096).unwrap();
+
+        let mut page = Page::new(0, 4096);
+        page.push_entry(b"key1", b"value1", 0).unwrap();
+
+        device.set_durability(Durability::Fdatasync).unwrap();
+        device.write_page(&mut page).unwrap();
+        device.write_page(&mut page).unwrap();
+        assert_eq!(device.metrics().syncs(), 2);
+
+        device
+            .set_durability(Durability::Periodic(Duration::from_millis(5)))
+            .unwrap();
+        device.write_page(&mut page).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while device.metrics().syncs() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(device.metrics().syncs(), 3);
+
+        // Switching back stops the background thread
+        device.set_durability(Durability::None).unwrap();
+        device.write_page(&mut page).unwrap();
+        assert_eq!(device.metrics().syncs(), 3);
+    }
+
+    #[test]
+    fn test_reopen_with_mismatched_page_size() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("mismatch.ssd");
+
+        let device = SsdDevice::create(&file_path, 4096).unwrap();
+        let mut page = Page::new(0, 4096);
+        page.push_entry(b"key1", b"value1", 0).unwrap();
+        device.write_page(&mut page).unwrap();
+        drop(device);
+
+        let result = SsdDevice::new(&file_path, 8192);
+        assert!(matches!(
+            result,
+            Err(SsdError::PageSizeMismatch {
+                expected: 8192,
+                found: 4096
+            })
+        ));
+
+        let device = SsdDevice::new(&file_path, 4096).unwrap();
+        assert_eq!(device.page_count().unwrap(), 1);
+        assert_eq!(device.manifest().page_size, 4096);
+    }
 }
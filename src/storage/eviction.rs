@@ -0,0 +1,521 @@
+// An eviction policy decides which page leaves a full page cache. Policies
+// only track page ids, the cache keeps the pages, so the same policies can
+// be simulated next to the cache on its requests, see `PageCache`:
+// - `Lru`, the least recently used page
+// - `Clock`, a second chance FIFO, approximating LRU without moving pages
+//   on hits
+// - `TwoQ`, new pages go through a FIFO, pages seen again while remembered
+//   after leaving it enter an LRU
+// - `AdaptiveReplacement` (ARC), balances recency and frequency LRUs by the
+//   hits on the pages they evicted
+// - `S3Fifo`, a small FIFO filtering one-hit pages in front of a main FIFO
+//   with reinsertion
+use std::collections::HashMap;
+use std::fmt;
+
+use hashlink::{LinkedHashMap, LinkedHashSet};
+
+/// Picks the page to evict from a cache of a fixed number of pages.
+/// Pages are ordered front (next to evict) to back in the lists below.
+pub trait EvictionPolicy: fmt::Debug + Send {
+    /// Short name for logs and metrics
+    fn name(&self) -> &'static str;
+
+    /// A cached page was requested
+    fn touch(&mut self, page_id: u64);
+
+    /// Admit a page that is not cached. Returns the page to evict to make
+    /// room, if the cache was full.
+    fn insert(&mut self, page_id: u64) -> Option<u64>;
+
+    /// Forget a page dropped from the cache
+    fn remove(&mut self, page_id: u64);
+}
+
+/// Built-in policies, selected in `CacheConfig`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    #[default]
+    Lru,
+    Clock,
+    TwoQ,
+    Arc,
+    S3Fifo,
+}
+
+impl Eviction {
+    pub const ALL: [Eviction; 5] = [
+        Eviction::Lru,
+        Eviction::Clock,
+        Eviction::TwoQ,
+        Eviction::Arc,
+        Eviction::S3Fifo,
+    ];
+
+    /// A policy for a cache of `capacity` pages, at least one
+    pub fn build(self, capacity: usize) -> Box<dyn EvictionPolicy> {
+        let capacity = capacity.max(1);
+        match self {
+            Eviction::Lru => Box::new(Lru::new(capacity)),
+            Eviction::Clock => Box::new(Clock::new(capacity)),
+            Eviction::TwoQ => Box::new(TwoQ::new(capacity)),
+            Eviction::Arc => Box::new(AdaptiveReplacement::new(capacity)),
+            Eviction::S3Fifo => Box::new(S3Fifo::new(capacity)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lru {
+    capacity: usize,
+    pages: LinkedHashSet<u64>,
+}
+
+impl Lru {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            pages: LinkedHashSet::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn touch(&mut self, page_id: u64) {
+        self.pages.to_back(&page_id);
+    }
+
+    fn insert(&mut self, page_id: u64) -> Option<u64> {
+        let victim = if self.pages.len() >= self.capacity {
+            self.pages.pop_front()
+        } else {
+            None
+        };
+        self.pages.insert(page_id);
+        victim
+    }
+
+    fn remove(&mut self, page_id: u64) {
+        self.pages.remove(&page_id);
+    }
+}
+
+#[derive(Debug)]
+pub struct Clock {
+    capacity: usize,
+    /// Pages in the order of the hand, with their referenced bit
+    pages: LinkedHashMap<u64, bool>,
+}
+
+impl Clock {
+    pub fn new(capacity: usize) -> Self {
+        Clock {
+            capacity,
+            pages: LinkedHashMap::new(),
+        }
+    }
+}
+
+impl EvictionPolicy for Clock {
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn touch(&mut self, page_id: u64) {
+        if let Some(referenced) = self.pages.get_mut(&page_id) {
+            *referenced = true;
+        }
+    }
+
+    fn insert(&mut self, page_id: u64) -> Option<u64> {
+        let mut victim = None;
+        if self.pages.len() >= self.capacity {
+            // Referenced pages get a second chance behind the hand
+            while let Some((candidate, referenced)) = self.pages.pop_front() {
+                if !referenced {
+                    victim = Some(candidate);
+                    break;
+                }
+                self.pages.insert(candidate, false);
+            }
+        }
+        self.pages.insert(page_id, false);
+        victim
+    }
+
+    fn remove(&mut self, page_id: u64) {
+        self.pages.remove(&page_id);
+    }
+}
+
+/// Full 2Q, with the sizes suggested in the paper: a FIFO of new pages of a
+/// quarter of the cache, remembering the pages it evicted up to half the
+/// cache
+#[derive(Debug)]
+pub struct TwoQ {
+    capacity: usize,
+    /// Pages cached once
+    recent: LinkedHashSet<u64>,
+    /// Pages evicted from `recent`, not cached
+    evicted: LinkedHashSet<u64>,
+    /// Pages requested again after leaving `recent`, in LRU order
+    frequent: LinkedHashSet<u64>,
+}
+
+impl TwoQ {
+    pub fn new(capacity: usize) -> Self {
+        TwoQ {
+            capacity,
+            recent: LinkedHashSet::new(),
+            evicted: LinkedHashSet::new(),
+            frequent: LinkedHashSet::new(),
+        }
+    }
+
+    fn recent_capacity(&self) -> usize {
+        (self.capacity / 4).max(1)
+    }
+
+    fn evicted_capacity(&self) -> usize {
+        (self.capacity / 2).max(1)
+    }
+}
+
+impl EvictionPolicy for TwoQ {
+    fn name(&self) -> &'static str {
+        "2q"
+    }
+
+    fn touch(&mut self, page_id: u64) {
+        // Hits in `recent` are likely correlated, they don't promote
+        self.frequent.to_back(&page_id);
+    }
+
+    fn insert(&mut self, page_id: u64) -> Option<u64> {
+        let mut victim = None;
+        if self.recent.len() + self.frequent.len() >= self.capacity {
+            if self.recent.len() >= self.recent_capacity() || self.frequent.is_empty() {
+                victim = self.recent.pop_front();
+                if let Some(victim) = victim {
+                    self.evicted.insert(victim);
+                    if self.evicted.len() > self.evicted_capacity() {
+                        self.evicted.pop_front();
+                    }
+                }
+            } else {
+                victim = self.frequent.pop_front();
+            }
+        }
+
+        if self.evicted.remove(&page_id) {
+            self.frequent.insert(page_id);
+        } else {
+            self.recent.insert(page_id);
+        }
+        victim
+    }
+
+    fn remove(&mut self, page_id: u64) {
+        self.recent.remove(&page_id);
+        self.frequent.remove(&page_id);
+    }
+}
+
+/// Adaptive Replacement Cache, ARC (Megiddo and Modha)
+#[derive(Debug)]
+pub struct AdaptiveReplacement {
+    capacity: usize,
+    /// Target size of `recent`, grown by hits in `recent_ghosts` and shrunk
+    /// by hits in `frequent_ghosts`
+    target: usize,
+    /// Pages requested once, in LRU order
+    recent: LinkedHashSet<u64>,
+    /// Pages requested at least twice, in LRU order
+    frequent: LinkedHashSet<u64>,
+    /// Pages evicted from `recent` and `frequent`, not cached
+    recent_ghosts: LinkedHashSet<u64>,
+    frequent_ghosts: LinkedHashSet<u64>,
+}
+
+impl AdaptiveReplacement {
+    pub fn new(capacity: usize) -> Self {
+        AdaptiveReplacement {
+            capacity,
+            target: 0,
+            recent: LinkedHashSet::new(),
+            frequent: LinkedHashSet::new(),
+            recent_ghosts: LinkedHashSet::new(),
+            frequent_ghosts: LinkedHashSet::new(),
+        }
+    }
+
+    /// Evict from `recent` or `frequent` depending on the target, keeping
+    /// the page as a ghost
+    fn replace(&mut self, in_frequent_ghosts: bool) -> Option<u64> {
+        let recent = self.recent.len();
+        let from_recent = recent > 0
+            && (recent > self.target
+                || (in_frequent_ghosts && recent == self.target)
+                || self.frequent.is_empty());
+        if from_recent {
+            let victim = self.recent.pop_front()?;
+            self.recent_ghosts.insert(victim);
+            Some(victim)
+        } else {
+            let victim = self.frequent.pop_front()?;
+            self.frequent_ghosts.insert(victim);
+            Some(victim)
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.recent.len() + self.frequent.len() >= self.capacity
+    }
+}
+
+impl EvictionPolicy for AdaptiveReplacement {
+    fn name(&self) -> &'static str {
+        "arc"
+    }
+
+    fn touch(&mut self, page_id: u64) {
+        if self.recent.remove(&page_id) {
+            self.frequent.insert(page_id);
+        } else {
+            self.frequent.to_back(&page_id);
+        }
+    }
+
+    fn insert(&mut self, page_id: u64) -> Option<u64> {
+        let mut victim = None;
+        if self.recent_ghosts.contains(&page_id) {
+            let delta = (self.frequent_ghosts.len() / self.recent_ghosts.len()).max(1);
+            self.target = (self.target + delta).min(self.capacity);
+            if self.is_full() {
+                victim = self.replace(false);
+            }
+            self.recent_ghosts.remove(&page_id);
+            self.frequent.insert(page_id);
+        } else if self.frequent_ghosts.contains(&page_id) {
+            let delta = (self.recent_ghosts.len() / self.frequent_ghosts.len()).max(1);
+            self.target = self.target.saturating_sub(delta);
+            if self.is_full() {
+                victim = self.replace(true);
+            }
+            self.frequent_ghosts.remove(&page_id);
+            self.frequent.insert(page_id);
+        } else {
+            let recent_side = self.recent.len() + self.recent_ghosts.len();
+            let total = recent_side + self.frequent.len() + self.frequent_ghosts.len();
+            if recent_side >= self.capacity {
+                if self.recent.len() < self.capacity {
+                    self.recent_ghosts.pop_front();
+                    if self.is_full() {
+                        victim = self.replace(false);
+                    }
+                } else {
+                    victim = self.recent.pop_front();
+                }
+            } else if total >= self.capacity {
+                if total >= 2 * self.capacity {
+                    self.frequent_ghosts.pop_front();
+                }
+                if self.is_full() {
+                    victim = self.replace(false);
+                }
+            }
+            self.recent.insert(page_id);
+        }
+        victim
+    }
+
+    fn remove(&mut self, page_id: u64) {
+        self.recent.remove(&page_id);
+        self.frequent.remove(&page_id);
+    }
+}
+
+/// Most hits a page counts in `S3Fifo`
+const S3FIFO_MAX_FREQ: u8 = 3;
+
+/// S3-FIFO (Yang et al.): a small FIFO of a tenth of the cache where pages
+/// not requested again are dropped early, remembered in a ghost FIFO the
+/// size of the main FIFO
+#[derive(Debug)]
+pub struct S3Fifo {
+    capacity: usize,
+    small: LinkedHashSet<u64>,
+    main: LinkedHashSet<u64>,
+    /// Pages evicted from `small`, not cached
+    ghosts: LinkedHashSet<u64>,
+    /// Hits of cached pages, capped at `S3FIFO_MAX_FREQ`
+    freq: HashMap<u64, u8>,
+}
+
+impl S3Fifo {
+    pub fn new(capacity: usize) -> Self {
+        S3Fifo {
+            capacity,
+            small: LinkedHashSet::new(),
+            main: LinkedHashSet::new(),
+            ghosts: LinkedHashSet::new(),
+            freq: HashMap::new(),
+        }
+    }
+
+    fn small_capacity(&self) -> usize {
+        (self.capacity / 10).max(1)
+    }
+
+    fn main_capacity(&self) -> usize {
+        (self.capacity - self.small_capacity()).max(1)
+    }
+
+    /// Drop the first page of `small` not requested again, moving those
+    /// that were to `main`
+    fn evict_small(&mut self) -> Option<u64> {
+        while let Some(page_id) = self.small.pop_front() {
+            if self.freq[&page_id] > 0 {
+                self.freq.insert(page_id, 0);
+                self.main.insert(page_id);
+            } else {
+                self.freq.remove(&page_id);
+                self.ghosts.insert(page_id);
+                if self.ghosts.len() > self.main_capacity() {
+                    self.ghosts.pop_front();
+                }
+                return Some(page_id);
+            }
+        }
+        None
+    }
+
+    /// Drop the first page of `main` without hits, reinserting the others
+    /// with one hit less
+    fn evict_main(&mut self) -> Option<u64> {
+        while let Some(page_id) = self.main.pop_front() {
+            let freq = self.freq.get_mut(&page_id).unwrap();
+            if *freq > 0 {
+                *freq -= 1;
+                self.main.insert(page_id);
+            } else {
+                self.freq.remove(&page_id);
+                return Some(page_id);
+            }
+        }
+        None
+    }
+}
+
+impl EvictionPolicy for S3Fifo {
+    fn name(&self) -> &'static str {
+        "s3fifo"
+    }
+
+    fn touch(&mut self, page_id: u64) {
+        if let Some(freq) = self.freq.get_mut(&page_id) {
+            *freq = (*freq + 1).min(S3FIFO_MAX_FREQ);
+        }
+    }
+
+    fn insert(&mut self, page_id: u64) -> Option<u64> {
+        let remembered = self.ghosts.remove(&page_id);
+        let mut victim = None;
+        if self.small.len() + self.main.len() >= self.capacity {
+            victim = if self.small.len() >= self.small_capacity() {
+                self.evict_small().or_else(|| self.evict_main())
+            } else {
+                self.evict_main().or_else(|| self.evict_small())
+            };
+        }
+
+        self.freq.insert(page_id, 0);
+        if remembered {
+            self.main.insert(page_id);
+        } else {
+            self.small.insert(page_id);
+        }
+        victim
+    }
+
+    fn remove(&mut self, page_id: u64) {
+        if self.freq.remove(&page_id).is_some() {
+            self.small.remove(&page_id);
+            self.main.remove(&page_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Run requests through a policy like the cache does, returns the hits
+    fn simulate(policy: &mut dyn EvictionPolicy, capacity: usize, requests: &[u64]) -> usize {
+        let mut cached = HashSet::new();
+        let mut hits = 0;
+        for &page_id in requests {
+            if cached.contains(&page_id) {
+                policy.touch(page_id);
+                hits += 1;
+                continue;
+            }
+            if let Some(victim) = policy.insert(page_id) {
+                assert!(
+                    cached.remove(&victim),
+                    "{} evicted {}",
+                    policy.name(),
+                    victim
+                );
+            }
+            cached.insert(page_id);
+            assert!(cached.len() <= capacity, "{}", policy.name());
+        }
+        hits
+    }
+
+    #[test]
+    fn test_policies_stay_within_capacity() {
+        // Pseudo-random requests skewed towards low page ids
+        let mut state = 1u64;
+        let requests: Vec<u64> = (0..5000)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let r = (state >> 33) % 1000;
+                r * r / 10_000
+            })
+            .collect();
+        for eviction in Eviction::ALL {
+            let mut policy = eviction.build(10);
+            let hits = simulate(policy.as_mut(), 10, &requests);
+            assert!(hits > 500, "{} hits {}", policy.name(), hits);
+
+            // Removed pages are forgotten, a full cache evicts once per insert
+            policy.remove(requests[0]);
+            for page_id in 1000..1010 {
+                policy.insert(page_id);
+            }
+            assert!(policy.insert(1010).is_some(), "{}", policy.name());
+        }
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        // A hot set requested twice, then a scan of pages requested once.
+        // 2Q only keeps pages requested again after leaving its FIFO.
+        let hot: Vec<u64> = (0..4).chain(0..4).collect();
+        let scan: Vec<u64> = (100..120).collect();
+        let requests: Vec<u64> = [&hot[..], &scan[..], &hot[..]].concat();
+
+        let lru_hits = simulate(&mut Lru::new(8), 8, &requests);
+        for mut policy in [Eviction::Arc, Eviction::S3Fifo].map(|e| e.build(8)) {
+            let hits = simulate(policy.as_mut(), 8, &requests);
+            assert!(hits > lru_hits, "{} hits {}", policy.name(), hits);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+// `FaultyStore` wraps a page store and fails its requests on command, to
+// test error handling and recovery.
+// - Faults are programmed through a `FaultInjector`, a handle that stays
+//   with the test while the store is owned by the database.
+// - Reads can fail, come back short or with a flipped bit. Short and
+//   flipped reads are decoded like a buffer read from the device, so they
+//   are caught by the page checks.
+// - Writes are kept by the wrapper until the next sync, as if they were
+//   still in the OS cache. A simulated crash loses them, the inner store
+//   then holds what a real device would hold after a power loss.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tracing::{debug, warn};
+
+use super::device::{Durability, SsdDevice, SsdError, SsdMetrics};
+use super::manifest::Manifest;
+use super::page::Page;
+use super::store::PageStore;
+
+/// How a read programmed with `FaultInjector::on_read` goes wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFault {
+    /// The read fails with an I/O error
+    Error,
+    /// Only the given number of bytes is read, the rest of the page is zero
+    Short(usize),
+    /// The bit at the given offset of the page is flipped
+    FlipBit(usize),
+}
+
+/// Handle programming the faults of a `FaultyStore`
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    reads: u64,
+    writes: u64,
+    /// Faults by the number of the read they hit
+    read_faults: BTreeMap<u64, ReadFault>,
+    /// Numbers of the writes that fail
+    write_faults: BTreeSet<u64>,
+    crashed: bool,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the `n`th read from now go wrong, counting from 1
+    pub fn on_read(&self, n: u64, fault: ReadFault) {
+        let mut state = self.state.lock().unwrap();
+        let read = state.reads + n;
+        state.read_faults.insert(read, fault);
+    }
+
+    /// Make the `n`th page write from now fail with an I/O error, counting
+    /// from 1. The page is left as it was.
+    pub fn fail_write(&self, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        let write = state.writes + n;
+        state.write_faults.insert(write);
+    }
+
+    /// Simulate a crash: writes not synced yet are lost and every later
+    /// request fails, until the inner store is opened again
+    pub fn crash(&self) {
+        warn!("Simulating a crash");
+        self.state.lock().unwrap().crashed = true;
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    fn check_crashed(&self) -> Result<(), SsdError> {
+        if self.is_crashed() {
+            return Err(injected("simulated crash"));
+        }
+        Ok(())
+    }
+
+    /// Count a read, returning the fault programmed for it
+    fn next_read(&self) -> Result<Option<ReadFault>, SsdError> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(injected("simulated crash"));
+        }
+        state.reads += 1;
+        let read = state.reads;
+        Ok(state.read_faults.remove(&read))
+    }
+
+    /// Count a page write, failing it if programmed to
+    fn next_write(&self) -> Result<(), SsdError> {
+        let mut state = self.state.lock().unwrap();
+        if state.crashed {
+            return Err(injected("simulated crash"));
+        }
+        state.writes += 1;
+        let write = state.writes;
+        if state.write_faults.remove(&write) {
+            return Err(injected("injected write fault"));
+        }
+        Ok(())
+    }
+}
+
+fn injected(message: &str) -> SsdError {
+    debug!("Injecting fault: {}", message);
+    SsdError::Io(io::Error::other(message))
+}
+
+/// Page store failing on command, see `FaultInjector`
+#[derive(Debug)]
+pub struct FaultyStore<S> {
+    inner: S,
+    faults: FaultInjector,
+    unsynced: Mutex<Unsynced>,
+    metrics: SsdMetrics,
+    durability: Durability,
+}
+
+/// Writes a `FaultyStore` keeps until they reach the inner store with the
+/// next sync
+#[derive(Debug, Default)]
+struct Unsynced {
+    /// Pages written since the last sync, None for discarded pages
+    pages: BTreeMap<u64, Option<Box<[u8]>>>,
+    manifest: Option<Manifest>,
+}
+
+impl<S: PageStore> FaultyStore<S> {
+    /// Wrap `inner`, which must not sync by itself, i.e. it has
+    /// `Durability::None`. The durability is handled by the wrapper.
+    pub fn new(inner: S, faults: FaultInjector) -> Self {
+        FaultyStore {
+            inner,
+            faults,
+            unsynced: Mutex::new(Unsynced::default()),
+            metrics: SsdMetrics::default(),
+            durability: Durability::None,
+        }
+    }
+
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    /// The wrapped store, without the writes that are not synced yet
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Serialized page as the device would return it
+    fn read_buffer(&self, page_id: u64) -> Result<Vec<u8>, SsdError> {
+        let page_size = self.inner.page_size() as usize;
+        match self.unsynced.lock().unwrap().pages.get(&page_id) {
+            Some(Some(buffer)) => Ok(buffer.to_vec()),
+            Some(None) => Ok(vec![0; page_size]),
+            None => {
+                let mut page = self.inner.read_page(page_id)?;
+                let mut buffer = vec![0; page_size];
+                page.write_to_buffer(&mut buffer);
+                Ok(buffer)
+            }
+        }
+    }
+
+    // Sync after a write when the durability mode asks for it
+    fn sync_for_durability(&self) -> Result<(), SsdError> {
+        match self.durability {
+            Durability::Fsync => self.sync(),
+            Durability::Fdatasync => self.sync_data(),
+            // The background sync of `Periodic` is not simulated, its
+            // writes may all be lost
+            Durability::None | Durability::Periodic(_) => Ok(()),
+        }
+    }
+
+    /// Hand the unsynced writes to the inner store and sync it
+    fn sync_inner(&self, data_only: bool) -> Result<(), SsdError> {
+        self.faults.check_crashed()?;
+        let start = Instant::now();
+        let mut unsynced = self.unsynced.lock().unwrap();
+        while let Some((page_id, buffer)) = unsynced.pages.pop_first() {
+            match buffer {
+                Some(buffer) => {
+                    let mut page = SsdDevice::decode_page(page_id, &buffer)?;
+                    self.inner.write_page(&mut page)?;
+                }
+                None => self.inner.discard_page(page_id)?,
+            }
+        }
+        if let Some(manifest) = unsynced.manifest.take() {
+            self.inner.write_manifest(manifest)?;
+        }
+        if data_only {
+            self.inner.sync_data()?;
+        } else {
+            self.inner.sync()?;
+        }
+        self.metrics.record_sync(start.elapsed().as_nanos() as u64);
+        Ok(())
+    }
+}
+
+impl<S: PageStore> PageStore for FaultyStore<S> {
+    fn page_size(&self) -> u32 {
+        self.inner.page_size()
+    }
+
+    fn read_page(&self, page_id: u64) -> Result<Page, SsdError> {
+        let fault = self.faults.next_read()?;
+        let start = Instant::now();
+        let mut buffer = self.read_buffer(page_id)?;
+        match fault {
+            None => {}
+            Some(ReadFault::Error) => return Err(injected("injected read fault")),
+            Some(ReadFault::Short(bytes)) => {
+                let bytes = bytes.min(buffer.len());
+                buffer[bytes..].fill(0);
+            }
+            Some(ReadFault::FlipBit(bit)) => buffer[bit / 8] ^= 1 << (bit % 8),
+        }
+        self.metrics
+            .record_read(buffer.len(), start.elapsed().as_nanos() as u64);
+        SsdDevice::decode_page(page_id, &buffer)
+    }
+
+    fn write_page(&self, page: &mut Page) -> Result<(), SsdError> {
+        if page.capacity() as u32 != self.inner.page_size() {
+            return Err(SsdError::InvalidPageSize);
+        }
+        self.faults.next_write()?;
+        let start = Instant::now();
+        let page_size = self.inner.page_size() as usize;
+        let mut buffer = vec![0; page_size].into_boxed_slice();
+        page.write_to_buffer(&mut buffer);
+        self.unsynced
+            .lock()
+            .unwrap()
+            .pages
+            .insert(page.id(), Some(buffer));
+        self.metrics
+            .record_write(page_size, start.elapsed().as_nanos() as u64);
+        self.sync_for_durability()
+    }
+
+    fn discard_page(&self, page_id: u64) -> Result<(), SsdError> {
+        self.faults.check_crashed()?;
+        self.unsynced.lock().unwrap().pages.insert(page_id, None);
+        self.metrics.record_discard();
+        self.sync_for_durability()
+    }
+
+    fn sync(&self) -> Result<(), SsdError> {
+        self.sync_inner(false)
+    }
+
+    fn sync_data(&self) -> Result<(), SsdError> {
+        self.sync_inner(true)
+    }
+
+    fn set_durability(&mut self, durability: Durability) -> Result<(), SsdError> {
+        self.durability = durability;
+        Ok(())
+    }
+
+    fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    fn page_count(&self) -> Result<u64, SsdError> {
+        let unsynced = self.unsynced.lock().unwrap();
+        let unsynced = unsynced.pages.keys().next_back().map_or(0, |id| id + 1);
+        Ok(self.inner.page_count()?.max(unsynced))
+    }
+
+    fn manifest(&self) -> Manifest {
+        match &self.unsynced.lock().unwrap().manifest {
+            Some(manifest) => manifest.clone(),
+            None => self.inner.manifest(),
+        }
+    }
+
+    fn write_manifest(&self, manifest: Manifest) -> Result<(), SsdError> {
+        self.faults.check_crashed()?;
+        self.unsynced.lock().unwrap().manifest = Some(manifest);
+        Ok(())
+    }
+
+    fn metrics(&self) -> &SsdMetrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStore;
+    use crate::storage::page::CorruptionError;
+
+    fn page(page_id: u64, value: &[u8]) -> Page {
+        let mut page = Page::new(page_id, 4096);
+        page.push_entry(b"key", value, page_id).unwrap();
+        page
+    }
+
+    #[test]
+    fn test_injected_read_and_write_faults() {
+        let faults = FaultInjector::new();
+        let store = FaultyStore::new(MemoryStore::new(4096).unwrap(), faults.clone());
+        store.write_page(&mut page(0, b"value")).unwrap();
+
+        faults.fail_write(2);
+        store.write_page(&mut page(1, b"value")).unwrap();
+        assert!(matches!(
+            store.write_page(&mut page(2, b"value")),
+            Err(SsdError::Io(_))
+        ));
+        store.write_page(&mut page(2, b"value")).unwrap();
+
+        faults.on_read(1, ReadFault::Error);
+        faults.on_read(2, ReadFault::Short(16));
+        // Inside the value of the entry, covered by the checksum
+        faults.on_read(3, ReadFault::FlipBit(4000 * 8));
+        assert!(matches!(store.read_page(0), Err(SsdError::Io(_))));
+        assert!(matches!(
+            store.read_page(0),
+            Err(SsdError::Corruption {
+                page_id: 0,
+                error: CorruptionError::TruncatedEntry { .. } | CorruptionError::BadCrc { .. }
+            })
+        ));
+        let mut large = Page::new(3, 4096);
+        large.push_entry(b"key", &[7; 4000], 3).unwrap();
+        store.write_page(&mut large).unwrap();
+        assert!(matches!(
+            store.read_page(3),
+            Err(SsdError::Corruption {
+                page_id: 3,
+                error: CorruptionError::BadCrc { .. }
+            })
+        ));
+        // Faults hit once
+        assert_eq!(
+            store.read_page(3).unwrap().get(0, b"key").unwrap(),
+            [7; 4000]
+        );
+    }
+
+    #[test]
+    fn test_crash_loses_unsynced_writes() {
+        let memory = MemoryStore::new(4096).unwrap();
+        let faults = FaultInjector::new();
+        let store = FaultyStore::new(memory.reopen(), faults.clone());
+        store.write_page(&mut page(0, b"synced")).unwrap();
+        let mut manifest = store.manifest();
+        manifest.page_count = 1;
+        store.write_manifest(manifest.clone()).unwrap();
+        store.sync().unwrap();
+
+        store.write_page(&mut page(0, b"lost")).unwrap();
+        store.write_page(&mut page(1, b"lost")).unwrap();
+        manifest.page_count = 2;
+        store.write_manifest(manifest).unwrap();
+        // Reads see the unsynced writes until the crash
+        assert_eq!(store.read_page(0).unwrap().get(0, b"key").unwrap(), b"lost");
+        assert_eq!(store.page_count().unwrap(), 2);
+
+        faults.crash();
+        assert!(matches!(store.read_page(0), Err(SsdError::Io(_))));
+        assert!(matches!(store.sync(), Err(SsdError::Io(_))));
+        drop(store);
+
+        let memory = memory.reopen();
+        assert_eq!(memory.page_count().unwrap(), 1);
+        assert_eq!(memory.manifest().page_count, 1);
+        assert_eq!(
+            memory.read_page(0).unwrap().get(0, b"key").unwrap(),
+            b"synced"
+        );
+    }
+}
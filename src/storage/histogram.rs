@@ -67,7 +67,7 @@ impl Debug for Histogram {
         for p in &PS {
             let res = self.percentile(*p).round();
             let line = format!("({} -> {}) ", p, res);
-            f.write_str(&*line)?;
+            f.write_str(&line)?;
         }
 
         f.write_str("]")
@@ -103,7 +103,7 @@ impl Histogram {
             let count = self.count.load(Ordering::Acquire);
 
             if count == 0 {
-                return std::f64::NAN;
+                return f64::NAN;
             }
 
             let mut target = count as f64 * (p / 100.);
@@ -123,7 +123,7 @@ impl Histogram {
             }
         }
 
-        std::f64::NAN
+        f64::NAN
     }
 
     /// Dump out some common percentiles.
@@ -155,7 +155,7 @@ fn compress<T: Into<f64>>(input_value: T) -> u16 {
     let boosted = 1. + abs;
     let ln = boosted.ln();
     let compressed = PRECISION.mul_add(ln, 0.5);
-    assert!(compressed <= f64::from(u16::max_value()));
+    assert!(compressed <= f64::from(u16::MAX));
 
     compressed as u16
 }
@@ -22,11 +22,19 @@ pub struct Config {
     /// Specify a particular CPU to pin the
     /// `SQPOLL` thread onto.
     pub sq_poll_affinity: u32,
-    /// Specify that the user will directly
-    /// poll the hardware for operation completion
-    /// rather than using the completion queue.
+    /// Enable `IOPOLL` mode, in which completions
+    /// are busy-polled from the device instead of
+    /// being signalled by interrupts. This cuts the
+    /// latency of small reads on fast devices like
+    /// NVMe, at the cost of a core spinning in the
+    /// completion reaper while anything is in flight.
     ///
-    /// CURRENTLY UNSUPPORTED
+    /// Only reads and writes of files opened with
+    /// `O_DIRECT`, on devices supporting polling, work
+    /// in this mode. Other operations, like `fsync`
+    /// and timeouts, fail with `EINVAL`, and I/O on
+    /// devices without polling support fails with
+    /// `EOPNOTSUPP`.
     pub io_poll: bool,
     /// Print a profile table on drop, showing where
     /// time was spent.
@@ -51,61 +59,56 @@ impl Default for Config {
 impl Config {
     /// Start the `Rio` system.
     pub fn start(mut self) -> io::Result<Rio> {
-        let mut params =
-            if let Some(params) = self.raw_params.take() {
-                params
-            } else {
-                let mut params = io_uring_params::default();
+        let mut params = if let Some(params) = self.raw_params.take() {
+            params
+        } else {
+            let mut params = io_uring_params::default();
+
+            if self.sq_poll {
+                // set SQPOLL mode to avoid needing wakeup
+                params.flags = IORING_SETUP_SQPOLL;
+                params.sq_thread_cpu = self.sq_poll_affinity;
+            }
 
-                if self.sq_poll {
-                    // set SQPOLL mode to avoid needing wakeup
-                    params.flags = IORING_SETUP_SQPOLL;
-                    params.sq_thread_cpu =
-                        self.sq_poll_affinity;
-                }
+            if self.io_poll {
+                params.flags |= IORING_SETUP_IOPOLL;
+            }
 
-                params
-            };
+            params
+        };
 
         let params_ptr: *mut io_uring_params = &mut params;
 
-        let ring_fd = setup(
-            u32::try_from(self.depth).unwrap(),
-            params_ptr,
-        )?;
+        let ring_fd = setup(u32::try_from(self.depth).unwrap(), params_ptr)?;
 
         if ring_fd < 0 {
             let mut err = io::Error::last_os_error();
             if let Some(12) = err.raw_os_error() {
-                err = io::Error::new(
-                io::ErrorKind::Other,
-                "Not enough lockable memory. You probably \
+                err = io::Error::other(
+                    "Not enough lockable memory. You probably \
                  need to raise the memlock rlimit, which \
                  often defaults to a pretty low number.",
-            );
+                );
             }
             return Err(err);
         }
 
-        let in_flight = Arc::new(InFlight::new(
-            params.cq_entries as usize,
-        ));
+        #[allow(clippy::arc_with_non_send_sync)]
+        let in_flight = Arc::new(InFlight::new(params.cq_entries as usize));
 
-        let ticket_queue = Arc::new(TicketQueue::new(
-            params.cq_entries as usize,
-        ));
+        let ticket_queue = Arc::new(TicketQueue::new(params.cq_entries as usize));
 
         let sq = Sq::new(&params, ring_fd)?;
-        let cq = Cq::new(
-            &params,
-            ring_fd,
-            in_flight.clone(),
-            ticket_queue.clone(),
-        )?;
+        let cq = Cq::new(&params, ring_fd, in_flight.clone(), ticket_queue.clone())?;
 
+        let flags = params.flags;
         std::thread::spawn(move || {
             let mut cq = cq;
-            cq.reaper(ring_fd)
+            if flags & IORING_SETUP_IOPOLL == 0 {
+                cq.reaper(ring_fd)
+            } else {
+                cq.polling_reaper(ring_fd)
+            }
         });
 
         Ok(Rio(Arc::new(Uring::new(
@@ -1,11 +1,19 @@
 #![allow(unsafe_code)]
 
-use std::slice::from_raw_parts_mut;
+use std::ptr::slice_from_raw_parts_mut;
 
 use crate::storage::metrics::{Measure, M};
 
 use super::*;
 
+/// Empty polls of a ring with nothing in flight
+/// after which the `IOPOLL` reaper starts sleeping
+const IOPOLL_SPINS: u32 = 100_000;
+
+/// How long the idle `IOPOLL` reaper sleeps
+/// between polls
+const IOPOLL_IDLE_SLEEP: std::time::Duration = std::time::Duration::from_micros(50);
+
 /// Consumes uring completions.
 #[derive(Debug)]
 pub struct Cq {
@@ -54,7 +62,7 @@ impl Cq {
                 ktail: cq_ring_ptr.add(params.cq_off.tail as usize) as *mut AtomicU32,
                 kring_mask: cq_ring_ptr.add(params.cq_off.ring_mask as usize) as *mut u32,
                 koverflow: cq_ring_ptr.add(params.cq_off.overflow as usize) as *mut AtomicU32,
-                cqes: from_raw_parts_mut(
+                cqes: slice_from_raw_parts_mut(
                     cq_ring_ptr.add(params.cq_off.cqes as usize) as _,
                     params.cq_entries as usize,
                 ),
@@ -90,6 +98,38 @@ impl Cq {
         }
     }
 
+    /// Reaper of rings set up with `IORING_SETUP_IOPOLL`,
+    /// whose completions are only found by polling the
+    /// device. Spins while operations are in flight,
+    /// and backs off to sleeping once the ring has been
+    /// idle for a while.
+    pub(crate) fn polling_reaper(&mut self, ring_fd: i32) {
+        let mut idle_polls = 0_u32;
+        loop {
+            // with min_complete 0 this polls the device
+            // once without blocking
+            let _ = Measure::new(&M.enter_cqe);
+            if let Err(e) = enter(ring_fd, 0, 0, IORING_ENTER_GETEVENTS, std::ptr::null_mut()) {
+                panic!("error in cqe reaper: {:?}", e);
+            }
+            assert_eq!(unsafe { (*self.koverflow).load(Relaxed) }, 0);
+            match self.reap_ready_cqes() {
+                // poison pill detected, time to shut down
+                None => return,
+                Some(0) if !self.ticket_queue.any_in_use() => {
+                    idle_polls = idle_polls.saturating_add(1);
+                    if idle_polls < IOPOLL_SPINS {
+                        std::hint::spin_loop();
+                    } else {
+                        std::thread::sleep(IOPOLL_IDLE_SLEEP);
+                    }
+                }
+                Some(0) => std::hint::spin_loop(),
+                Some(_) => idle_polls = 0,
+            }
+        }
+    }
+
     fn reap_ready_cqes(&mut self) -> Option<usize> {
         let _ = Measure::new(&M.reap_ready);
         let mut head = unsafe { &*self.khead }.load(Acquire);
@@ -112,18 +152,21 @@ impl Cq {
             // will tend not to be. if it's not a
             // poison pill, it will be up to as large
             // as the completion queue length.
-            let (ticket, poisoned) = if cqe.user_data > u64::max_value() / 2 {
-                (cqe.user_data ^ u64::max_value(), true)
+            let (user_data, poisoned) = if cqe.user_data > u64::MAX / 2 {
+                (cqe.user_data ^ u64::MAX, true)
             } else {
                 (cqe.user_data, false)
             };
+            let ticket = user_data & TICKET_MASK;
 
             let res = cqe.res;
 
             let completion_filler = cq.in_flight.take_filler(ticket as usize);
             to_push.push(ticket as usize);
 
-            let result = if res < 0 {
+            // an expired timeout is a successful one
+            let expired = completion_filler.timeout && res == -libc::ETIME;
+            let result = if res < 0 && !expired {
                 Err(io::Error::from_raw_os_error(res.neg()))
             } else {
                 Ok(*cqe)
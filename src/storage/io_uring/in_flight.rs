@@ -7,6 +7,7 @@ use super::*;
 pub(crate) struct InFlight {
     iovecs: UnsafeCell<Vec<libc::iovec>>,
     msghdrs: UnsafeCell<Vec<libc::msghdr>>,
+    timespecs: UnsafeCell<Vec<__kernel_timespec>>,
     fillers: UnsafeCell<Vec<Option<Filler>>>,
 }
 
@@ -33,6 +34,8 @@ impl InFlight {
             size
         ]);
 
+        let timespecs = UnsafeCell::new(vec![__kernel_timespec::default(); size]);
+
         let mut filler_vec = Vec::with_capacity(size);
         for _ in 0..size {
             filler_vec.push(None);
@@ -41,30 +44,41 @@ impl InFlight {
         InFlight {
             iovecs,
             msghdrs,
+            timespecs,
             fillers,
         }
     }
 
+    /// Keeps what the kernel reads through the `addr` of an SQE
+    /// alive until the operation completes, returning the address
+    /// to use. A timespec is stored for timeouts.
     pub(crate) fn insert(
         &self,
         ticket: usize,
         iovec: Option<libc::iovec>,
         msghdr: bool,
+        timespec: Option<__kernel_timespec>,
         filler: Filler,
     ) -> u64 {
         #[allow(unsafe_code)]
         unsafe {
+            if let Some(timespec) = timespec {
+                let timespec_ptr = self.timespecs.get();
+                (&mut *timespec_ptr)[ticket] = timespec;
+                (&mut *self.fillers.get())[ticket] = Some(filler);
+                return (*timespec_ptr).as_mut_ptr().add(ticket) as u64;
+            }
             let iovec_ptr = self.iovecs.get();
             let msghdr_ptr = self.msghdrs.get();
             if let Some(iovec) = iovec {
-                (*iovec_ptr)[ticket] = iovec;
+                (&mut *iovec_ptr)[ticket] = iovec;
 
                 if msghdr {
-                    (*msghdr_ptr)[ticket].msg_iov = (*iovec_ptr).as_mut_ptr().add(ticket);
-                    (*msghdr_ptr)[ticket].msg_iovlen = 1;
+                    (&mut *msghdr_ptr)[ticket].msg_iov = (*iovec_ptr).as_mut_ptr().add(ticket);
+                    (&mut *msghdr_ptr)[ticket].msg_iovlen = 1;
                 }
             }
-            (*self.fillers.get())[ticket] = Some(filler);
+            (&mut *self.fillers.get())[ticket] = Some(filler);
             if iovec.is_some() {
                 if msghdr {
                     (*msghdr_ptr).as_mut_ptr().add(ticket) as u64
@@ -80,7 +94,7 @@ impl InFlight {
     pub(crate) fn take_filler(&self, ticket: usize) -> Filler {
         #[allow(unsafe_code)]
         unsafe {
-            (*self.fillers.get())[ticket].take().unwrap()
+            (&mut *self.fillers.get())[ticket].take().unwrap()
         }
     }
 }
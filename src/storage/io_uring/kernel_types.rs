@@ -30,6 +30,22 @@ pub struct io_uring_params {
 
 pub type __kernel_rwf_t = ::std::os::raw::c_int;
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct __kernel_timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl From<std::time::Duration> for __kernel_timespec {
+    fn from(duration: std::time::Duration) -> __kernel_timespec {
+        __kernel_timespec {
+            tv_sec: i64::try_from(duration.as_secs()).unwrap_or(i64::MAX),
+            tv_nsec: i64::from(duration.subsec_nanos()),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct io_uring_sqe {
@@ -70,15 +86,34 @@ impl io_uring_sqe {
         self.apply_order(ordering);
     }
 
+    /// Prepares a read or write of a registered
+    /// buffer from or to a registered file
+    pub(crate) fn prep_fixed(
+        &mut self,
+        opcode: u8,
+        file_index: u32,
+        iov: libc::iovec,
+        buf_index: u16,
+        off: u64,
+        ordering: Ordering,
+    ) {
+        self.prep_rw(
+            opcode,
+            i32::try_from(file_index).unwrap(),
+            iov.iov_len,
+            off,
+            ordering,
+        );
+        self.flags |= IOSQE_FIXED_FILE;
+        self.addr = iov.iov_base as u64;
+        self.__bindgen_anon_2.buf_index = buf_index;
+    }
+
     fn apply_order(&mut self, ordering: Ordering) {
         match ordering {
             Ordering::None => {}
-            Ordering::Link => {
-                self.flags |= IOSQE_IO_LINK
-            }
-            Ordering::Drain => {
-                self.flags |= IOSQE_IO_DRAIN
-            }
+            Ordering::Link => self.flags |= IOSQE_IO_LINK,
+            Ordering::Drain => self.flags |= IOSQE_IO_DRAIN,
         }
     }
 }
@@ -104,10 +139,7 @@ impl Default for io_uring_sqe__bindgen_ty_1 {
 }
 
 impl fmt::Debug for io_uring_sqe__bindgen_ty_1 {
-    fn fmt(
-        &self,
-        f: &mut fmt::Formatter<'_>,
-    ) -> fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "io_uring_sqe__bindgen_ty_1")
     }
 }
@@ -121,10 +153,7 @@ pub union io_uring_sqe__bindgen_ty_2 {
 }
 
 impl fmt::Debug for io_uring_sqe__bindgen_ty_2 {
-    fn fmt(
-        &self,
-        f: &mut fmt::Formatter<'_>,
-    ) -> fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "io_uring_sqe__bindgen_ty_2")
     }
 }
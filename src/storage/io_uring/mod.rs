@@ -9,7 +9,7 @@ use std::{
     os::unix::io::{AsRawFd, FromRawFd},
     sync::{
         atomic::{
-            AtomicU32, AtomicU64,
+            AtomicU32, AtomicU64, AtomicUsize,
             Ordering::{Acquire, Relaxed, Release},
         },
         Arc, Condvar, Mutex,
@@ -32,17 +32,23 @@ pub(crate) use {
     constants::*,
     cq::Cq,
     in_flight::InFlight,
-    kernel_types::{io_uring_cqe, io_uring_params, io_uring_sqe},
+    kernel_types::{__kernel_timespec, io_uring_cqe, io_uring_params, io_uring_sqe},
     sq::Sq,
-    syscall::{enter, setup},
+    syscall::{enter, register, setup},
     ticket_queue::TicketQueue,
 };
 
 pub use {
     config::Config,
-    uring::{Rio, Uring},
+    uring::{FixedFile, Rio, Uring},
 };
 
+/// Bits of an SQE's `user_data` holding its ticket. The bits above
+/// hold the SQE's id, so that an operation can be told apart from
+/// later ones reusing its ticket, e.g. when cancelling it.
+const TICKET_BITS: u32 = 16;
+const TICKET_MASK: u64 = (1 << TICKET_BITS) - 1;
+
 /// Specify whether `io_uring` should
 /// run operations in a specific order.
 /// By default, it will run independent
@@ -85,8 +91,7 @@ fn uring_mmap(size: usize, ring_fd: i32, offset: i64) -> io::Result<*mut libc::c
     if ptr.is_null() || ptr == libc::MAP_FAILED {
         let mut err = io::Error::last_os_error();
         if let Some(12) = err.raw_os_error() {
-            err = io::Error::new(
-                io::ErrorKind::Other,
+            err = io::Error::other(
                 "Not enough lockable memory. You probably \
                  need to raise the memlock rlimit, which \
                  often defaults to a pretty low number.",
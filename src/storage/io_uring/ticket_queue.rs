@@ -11,6 +11,9 @@ use super::*;
 pub(crate) struct TicketQueue {
     tickets: Mutex<Vec<usize>>,
     cv: Condvar,
+    /// Tickets popped and not pushed back yet, readable
+    /// without taking the lock
+    in_use: AtomicUsize,
 }
 
 impl TicketQueue {
@@ -19,12 +22,14 @@ impl TicketQueue {
         TicketQueue {
             tickets,
             cv: Condvar::new(),
+            in_use: AtomicUsize::new(0),
         }
     }
 
     pub(crate) fn push_multi(&self, mut new_tickets: Vec<usize>) {
         let _ = Measure::new(&M.ticket_queue_push);
         let mut tickets = self.tickets.lock().unwrap();
+        self.in_use.fetch_sub(new_tickets.len(), Release);
         tickets.append(&mut new_tickets);
         self.cv.notify_one();
     }
@@ -35,6 +40,13 @@ impl TicketQueue {
         while tickets.is_empty() {
             tickets = self.cv.wait(tickets).unwrap();
         }
+        self.in_use.fetch_add(1, Release);
         tickets.pop().unwrap()
     }
+
+    /// Whether any operation holds a ticket, i.e. is
+    /// being prepared, submitted or in flight
+    pub(crate) fn any_in_use(&self) -> bool {
+        self.in_use.load(Acquire) > 0
+    }
 }
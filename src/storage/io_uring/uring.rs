@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::storage::{
     completion::{pair, Completion},
     metrics::{Measure, M},
@@ -6,6 +8,19 @@ use crate::storage::{
 
 use super::*;
 
+/// A file registered with `Uring::register_files`, addressed by its index
+/// in the registered set so the kernel skips the descriptor lookup of
+/// every operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedFile(u32);
+
+impl FixedFile {
+    /// Index of the file in the registered set
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+}
+
 /// Nice bindings for the shiny new linux IO system
 #[derive(Debug, Clone)]
 pub struct Rio(pub(crate) Arc<Uring>);
@@ -42,7 +57,7 @@ impl Drop for Uring {
         let poison_pill_res = self.with_sqe::<_, ()>(None, false, |sqe| {
             sqe.prep_rw(IORING_OP_NOP, 0, 1, 0, Ordering::Drain);
             // set the poison pill
-            sqe.user_data ^= u64::max_value();
+            sqe.user_data ^= u64::MAX;
         });
 
         // this waits for the NOP event to complete.
@@ -363,7 +378,7 @@ impl Uring {
     ) -> Completion<'a, ()> {
         self.with_sqe(None, false, |sqe| {
             sqe.prep_rw(IORING_OP_FSYNC, file.as_raw_fd(), 0, 0, ordering);
-            sqe.flags |= IORING_FSYNC_DATASYNC;
+            sqe.__bindgen_anon_1.fsync_flags = IORING_FSYNC_DATASYNC;
         })
     }
 
@@ -540,6 +555,272 @@ impl Uring {
         })
     }
 
+    /// Registers buffers with the kernel, which
+    /// pins their memory once instead of on every
+    /// operation. Registered buffers are addressed
+    /// by their index in `buffers` with `read_fixed`
+    /// and `write_fixed`.
+    ///
+    /// Only one set of buffers may be registered
+    /// at a time.
+    ///
+    /// # Safety
+    ///
+    /// The kernel keeps the addresses of the
+    /// buffers, so their memory must stay
+    /// allocated and in place until
+    /// `unregister_buffers` is called or the
+    /// ring is dropped.
+    #[allow(unsafe_code)]
+    pub unsafe fn register_buffers<B>(&self, buffers: &[B]) -> io::Result<()>
+    where
+        B: AsIoVec + AsIoVecMut,
+    {
+        let iovecs: Vec<libc::iovec> = buffers.iter().map(AsIoVec::into_new_iovec).collect();
+        register(
+            self.ring_fd,
+            IORING_REGISTER_BUFFERS,
+            iovecs.as_ptr().cast(),
+            u32::try_from(iovecs.len()).unwrap(),
+        )?;
+        Ok(())
+    }
+
+    /// Unregisters the buffers registered with
+    /// `register_buffers`.
+    pub fn unregister_buffers(&self) -> io::Result<()> {
+        register(self.ring_fd, IORING_UNREGISTER_BUFFERS, std::ptr::null(), 0)?;
+        Ok(())
+    }
+
+    /// Registers files with the kernel, returning
+    /// the handles `read_fixed` and `write_fixed`
+    /// address them with. The kernel holds its own
+    /// reference to the files until they are
+    /// unregistered.
+    ///
+    /// Only one set of files may be registered
+    /// at a time.
+    pub fn register_files<F>(&self, files: &[&F]) -> io::Result<Vec<FixedFile>>
+    where
+        F: AsRawFd,
+    {
+        let fds: Vec<i32> = files.iter().map(|file| file.as_raw_fd()).collect();
+        let count = u32::try_from(fds.len()).unwrap();
+        register(
+            self.ring_fd,
+            IORING_REGISTER_FILES,
+            fds.as_ptr().cast(),
+            count,
+        )?;
+        Ok((0..count).map(FixedFile).collect())
+    }
+
+    /// Unregisters the files registered with
+    /// `register_files`.
+    pub fn unregister_files(&self) -> io::Result<()> {
+        register(self.ring_fd, IORING_UNREGISTER_FILES, std::ptr::null(), 0)?;
+        Ok(())
+    }
+
+    /// Reads data into a buffer registered with
+    /// `register_buffers` from a registered file.
+    /// `buf` must lie within the registered buffer
+    /// number `buf_index`, e.g. be the buffer itself.
+    /// Be sure to check the returned length to see
+    /// if a short read happened.
+    pub fn read_fixed<'a, B>(
+        &'a self,
+        file: FixedFile,
+        buf: &'a B,
+        buf_index: u16,
+        at: u64,
+    ) -> Completion<'a, usize>
+    where
+        B: AsIoVec + AsIoVecMut,
+    {
+        self.read_fixed_ordered(file, buf, buf_index, at, Ordering::None)
+    }
+
+    /// Reads data into a registered buffer from a
+    /// registered file, like `read_fixed`.
+    ///
+    /// Accepts an `Ordering` specification.
+    pub fn read_fixed_ordered<'a, B>(
+        &'a self,
+        file: FixedFile,
+        buf: &'a B,
+        buf_index: u16,
+        at: u64,
+        ordering: Ordering,
+    ) -> Completion<'a, usize>
+    where
+        B: AsIoVec + AsIoVecMut,
+    {
+        let iov = buf.into_new_iovec();
+        self.with_sqe(None, false, |sqe| {
+            sqe.prep_fixed(IORING_OP_READ_FIXED, file.0, iov, buf_index, at, ordering)
+        })
+    }
+
+    /// Writes data from a buffer registered with
+    /// `register_buffers` to a registered file.
+    /// `buf` must lie within the registered buffer
+    /// number `buf_index`, e.g. be the buffer itself.
+    /// Be sure to check the returned length to see
+    /// if a short write happened.
+    pub fn write_fixed<'a, B>(
+        &'a self,
+        file: FixedFile,
+        buf: &'a B,
+        buf_index: u16,
+        at: u64,
+    ) -> Completion<'a, usize>
+    where
+        B: 'a + AsIoVec,
+    {
+        self.write_fixed_ordered(file, buf, buf_index, at, Ordering::None)
+    }
+
+    /// Writes data from a registered buffer to a
+    /// registered file, like `write_fixed`.
+    ///
+    /// Accepts an `Ordering` specification.
+    pub fn write_fixed_ordered<'a, B>(
+        &'a self,
+        file: FixedFile,
+        buf: &'a B,
+        buf_index: u16,
+        at: u64,
+        ordering: Ordering,
+    ) -> Completion<'a, usize>
+    where
+        B: 'a + AsIoVec,
+    {
+        let iov = buf.into_new_iovec();
+        self.with_sqe(None, false, |sqe| {
+            sqe.prep_fixed(IORING_OP_WRITE_FIXED, file.0, iov, buf_index, at, ordering)
+        })
+    }
+
+    /// Reads data into the provided buffer like
+    /// `read_at`, giving up after `timeout`. A read
+    /// that did not complete in time is cancelled
+    /// and fails with `io::ErrorKind::TimedOut`.
+    pub fn read_at_timeout<'a, F, B>(
+        &'a self,
+        file: &'a F,
+        iov: &'a B,
+        at: u64,
+        timeout: Duration,
+    ) -> Completion<'a, usize>
+    where
+        F: AsRawFd,
+        B: AsIoVec + AsIoVecMut,
+    {
+        self.with_sqe_and_timeout(Some(iov.into_new_iovec()), timeout, |sqe| {
+            sqe.prep_rw(IORING_OP_READV, file.as_raw_fd(), 1, at, Ordering::None)
+        })
+    }
+
+    /// Reads data into a registered buffer like
+    /// `read_fixed`, giving up after `timeout`. A read
+    /// that did not complete in time is cancelled
+    /// and fails with `io::ErrorKind::TimedOut`.
+    pub fn read_fixed_timeout<'a, B>(
+        &'a self,
+        file: FixedFile,
+        buf: &'a B,
+        buf_index: u16,
+        at: u64,
+        timeout: Duration,
+    ) -> Completion<'a, usize>
+    where
+        B: AsIoVec + AsIoVecMut,
+    {
+        let iov = buf.into_new_iovec();
+        self.with_sqe_and_timeout(None, timeout, |sqe| {
+            sqe.prep_fixed(
+                IORING_OP_READ_FIXED,
+                file.0,
+                iov,
+                buf_index,
+                at,
+                Ordering::None,
+            )
+        })
+    }
+
+    /// Writes data from the provided buffer like
+    /// `write_at`, giving up after `timeout`. A write
+    /// that did not complete in time is cancelled
+    /// and fails with `io::ErrorKind::TimedOut`,
+    /// though part of it may have reached the file.
+    pub fn write_at_timeout<'a, F, B>(
+        &'a self,
+        file: &'a F,
+        iov: &'a B,
+        at: u64,
+        timeout: Duration,
+    ) -> Completion<'a, usize>
+    where
+        F: AsRawFd,
+        B: 'a + AsIoVec,
+    {
+        self.with_sqe_and_timeout(Some(iov.into_new_iovec()), timeout, |sqe| {
+            sqe.prep_rw(IORING_OP_WRITEV, file.as_raw_fd(), 1, at, Ordering::None)
+        })
+    }
+
+    /// Completes once `timeout` elapsed, successfully.
+    /// Fails with `ECANCELED` if it was cancelled
+    /// first.
+    pub fn timeout<'a>(&'a self, timeout: Duration) -> Completion<'a, ()> {
+        self.timeout_ordered(timeout, Ordering::None)
+    }
+
+    /// Completes once `timeout` elapsed, like `timeout`.
+    ///
+    /// Accepts an `Ordering` specification.
+    pub fn timeout_ordered<'a>(
+        &'a self,
+        timeout: Duration,
+        ordering: Ordering,
+    ) -> Completion<'a, ()> {
+        let ticket = self.ticket_queue.pop();
+        let mut sq = self.lock_sq();
+        self.fill_sqe(&mut sq, ticket, None, false, Some(timeout.into()), |sqe| {
+            sqe.prep_rw(IORING_OP_TIMEOUT, -1, 1, 0, ordering)
+        })
+    }
+
+    /// Asks the kernel to cancel an operation
+    /// submitted earlier, which then fails with
+    /// `ECANCELED`. Cancelling fails with `ENOENT`
+    /// if the operation already completed, and with
+    /// `EALREADY` if it is running and can't be
+    /// interrupted anymore.
+    ///
+    /// # Warning
+    ///
+    /// This only becomes usable on linux kernels
+    /// 5.5 and up.
+    pub fn cancel<'a, C>(&'a self, completion: &Completion<'a, C>) -> Completion<'a, ()>
+    where
+        C: FromCqe,
+    {
+        // the operation must have reached the
+        // kernel to be found there
+        self.ensure_submitted(completion.sqe_id)
+            .expect("failed to submit SQE to cancel");
+
+        let target = completion.user_data;
+        self.with_sqe(None, false, |sqe| {
+            sqe.prep_rw(IORING_OP_ASYNC_CANCEL, -1, 0, 0, Ordering::None);
+            sqe.addr = target;
+        })
+    }
+
     /// Don't do anything. This is
     /// mostly for debugging and tuning.
     pub fn nop<'a>(&'a self) -> Completion<'a, ()> {
@@ -592,17 +873,72 @@ impl Uring {
         C: FromCqe,
     {
         let ticket = self.ticket_queue.pop();
-        let (mut completion, filler) = pair(self);
+        let mut sq = self.lock_sq();
+        self.fill_sqe(&mut sq, ticket, iovec, msghdr, None, f)
+    }
 
-        let data_ptr = self.in_flight.insert(ticket, iovec, msghdr, filler);
+    /// Queues an operation followed by a timeout
+    /// linked to it, which cancels the operation if
+    /// it did not complete in time. Both are queued
+    /// under one lock so nothing gets between them.
+    fn with_sqe_and_timeout<'a, F, C>(
+        &'a self,
+        iovec: Option<libc::iovec>,
+        timeout: Duration,
+        f: F,
+    ) -> Completion<'a, C>
+    where
+        F: FnOnce(&mut io_uring_sqe),
+        C: FromCqe,
+    {
+        let ticket = self.ticket_queue.pop();
+        let timeout_ticket = self.ticket_queue.pop();
+        let mut sq = self.lock_sq();
+        let mut completion = self.fill_sqe(&mut sq, ticket, iovec, false, None, |sqe| {
+            f(sqe);
+            sqe.flags |= IOSQE_IO_LINK;
+        });
+        let linked_timeout = self.fill_sqe(
+            &mut sq,
+            timeout_ticket,
+            None,
+            false,
+            Some(timeout.into()),
+            |sqe| sqe.prep_rw(IORING_OP_LINK_TIMEOUT, -1, 1, 0, Ordering::None),
+        );
+        completion.linked_timeout = Some(Box::new(linked_timeout));
+        completion
+    }
+
+    fn lock_sq(&self) -> std::sync::MutexGuard<'_, Sq> {
+        let _get_sq_mu = Measure::new(&M.sq_mu_wait);
+        self.sq.lock().unwrap()
+    }
+
+    fn fill_sqe<'a, F, C>(
+        &'a self,
+        sq: &mut Sq,
+        ticket: usize,
+        iovec: Option<libc::iovec>,
+        msghdr: bool,
+        timespec: Option<__kernel_timespec>,
+        f: F,
+    ) -> Completion<'a, C>
+    where
+        F: FnOnce(&mut io_uring_sqe),
+        C: FromCqe,
+    {
+        let (mut completion, mut filler) = pair(self);
+        filler.timeout = timespec.is_some();
+
+        let data_ptr = self
+            .in_flight
+            .insert(ticket, iovec, msghdr, timespec, filler);
 
-        let mut sq = {
-            let _get_sq_mu = Measure::new(&M.sq_mu_wait);
-            self.sq.lock().unwrap()
-        };
         let _hold_sq_mu = Measure::new(&M.sq_mu_hold);
 
         completion.sqe_id = self.loaded.fetch_add(1, Release) + 1;
+        completion.user_data = ticket as u64 | (completion.sqe_id << TICKET_BITS);
 
         let sqe = {
             let _get_sqe = Measure::new(&M.get_sqe);
@@ -616,7 +952,7 @@ impl Uring {
             }
         };
 
-        sqe.user_data = ticket as u64;
+        sqe.user_data = completion.user_data;
         sqe.addr = data_ptr;
         f(sqe);
 
@@ -636,3 +972,127 @@ fn addr2raw(addr: &std::net::SocketAddr) -> (*const libc::sockaddr, libc::sockle
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::storage::device::AlignedBuffer;
+
+    /// A pipe nothing is ever written to, reads from it never complete
+    fn idle_pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        #[allow(unsafe_code)]
+        unsafe {
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+        }
+    }
+
+    #[test]
+    fn test_timeouts_and_cancellation() {
+        let ring = Config::default().start().unwrap();
+        let (reader, _writer) = idle_pipe();
+        let buffer = vec![0u8; 16];
+
+        let start = Instant::now();
+        ring.timeout(Duration::from_millis(20)).wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // A read bounded by a linked timeout is cancelled by it
+        let err = ring
+            .read_at_timeout(&reader, &buffer, 0, Duration::from_millis(20))
+            .wait()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Waiting gives up without the read, which can then be cancelled
+        let read = ring.read_at(&reader, &buffer, 0);
+        let read = read.wait_timeout(Duration::from_millis(10)).unwrap_err();
+        let err = read.cancel().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ECANCELED));
+
+        // Operations that already completed are not found
+        let nop = ring.nop();
+        let cancel = ring.cancel(&nop);
+        nop.wait().unwrap();
+        assert!(cancel.wait().is_err());
+        let nop = ring.nop();
+        assert!(matches!(
+            nop.wait_timeout(Duration::from_secs(1)),
+            Ok(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn test_registered_buffers_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.path().join("fixed"))
+            .unwrap();
+        let ring = Config::default().start().unwrap();
+        let mut buffers = vec![
+            AlignedBuffer::new(4096).unwrap(),
+            AlignedBuffer::new(4096).unwrap(),
+        ];
+        buffers[0].as_mut_slice().fill(0xab);
+        buffers[1].as_mut_slice().fill(0);
+
+        // SAFETY: the buffers are unregistered before they are dropped
+        #[allow(unsafe_code)]
+        unsafe {
+            ring.register_buffers(&buffers).unwrap();
+        }
+        let files = ring.register_files(&[&file]).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].index(), 0);
+
+        let written = ring.write_fixed(files[0], &buffers[0], 0, 4096).wait();
+        assert_eq!(written.unwrap(), 4096);
+        let read = ring.read_fixed(files[0], &buffers[1], 1, 4096).wait();
+        assert_eq!(read.unwrap(), 4096);
+        assert_eq!(buffers[1].as_ref(), buffers[0].as_ref());
+        assert_eq!(file.metadata().unwrap().len(), 8192);
+
+        // The buffer must lie within the registered one it names
+        let err = ring
+            .read_fixed(files[0], &buffers[0], 1, 0)
+            .wait()
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFAULT));
+
+        ring.unregister_files().unwrap();
+        ring.unregister_buffers().unwrap();
+        // Nothing is registered anymore
+        assert!(ring.unregister_buffers().is_err());
+    }
+
+    #[test]
+    fn test_io_poll() {
+        let config = Config {
+            io_poll: true,
+            ..Config::default()
+        };
+        let ring = config.start().unwrap();
+        let (reader, _writer) = idle_pipe();
+
+        // Completions are found by the polling reaper, also after it
+        // went idle
+        ring.nop().wait().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        ring.nop().wait().unwrap();
+
+        // Only direct I/O can be polled, whether the device supports
+        // polling depends on the machine
+        let err = ring.fsync(&reader).wait().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let err = ring.timeout(Duration::from_millis(1)).wait().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+}
@@ -0,0 +1,207 @@
+// The manifest is a fixed-size region at the start of the data file that
+// describes how the rest of the file must be interpreted.
+// - **Layout**: [Magic] + [Format version] + [Page size] + [Hot threshold] + [Page count]
+//   + [Batch watermark] + [Free page count] + [Free page ids] + [CRC32]
+// - The free pages listed are not read on recovery. A page must be removed
+//   from the list on the device before it is written again.
+// - Pages start right after the region, so page `i` lives at
+//   `MANIFEST_REGION_SIZE + i * page_size`.
+use std::convert::TryInto;
+
+use super::page::FORMAT_VERSION;
+
+const MANIFEST_MAGIC: &[u8; 8] = b"blitzkvm";
+
+/// Size of the region reserved for the manifest, kept at 4 KiB so that the
+/// pages after it stay aligned for `O_DIRECT`.
+pub const MANIFEST_REGION_SIZE: u64 = 4096;
+
+// Constants for fixed sizes
+const MAGIC_SIZE: usize = MANIFEST_MAGIC.len();
+const VERSION_SIZE: usize = std::mem::size_of::<u32>();
+const PAGE_SIZE_SIZE: usize = std::mem::size_of::<u32>();
+const HOT_THRESHOLD_SIZE: usize = std::mem::size_of::<u32>();
+const PAGE_COUNT_SIZE: usize = std::mem::size_of::<u64>();
+const WATERMARK_SIZE: usize = std::mem::size_of::<u64>();
+const FREE_COUNT_SIZE: usize = std::mem::size_of::<u32>();
+const PAGE_ID_SIZE: usize = std::mem::size_of::<u64>();
+const CRC32_SIZE: usize = std::mem::size_of::<u32>();
+// Fields before the free page ids
+const HEADER_SIZE: usize = MAGIC_SIZE
+    + VERSION_SIZE
+    + PAGE_SIZE_SIZE
+    + HOT_THRESHOLD_SIZE
+    + PAGE_COUNT_SIZE
+    + WATERMARK_SIZE
+    + FREE_COUNT_SIZE;
+
+/// Most free pages the manifest region has room for
+pub const MAX_LISTED_FREE_PAGES: usize =
+    (MANIFEST_REGION_SIZE as usize - HEADER_SIZE - CRC32_SIZE) / PAGE_ID_SIZE;
+
+/// Database wide parameters persisted at the start of the data file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub hot_threshold: u32,
+    /// Number of pages allocated when the manifest was last written
+    pub page_count: u64,
+    /// Entries written by write batches are committed if their sequence
+    /// number is below this, later ones belong to an interrupted batch
+    pub batch_watermark: u64,
+    /// Pages that hold no entries, at most `MAX_LISTED_FREE_PAGES`. Free
+    /// pages that are not listed are found by reading them on recovery.
+    pub free_pages: Vec<u64>,
+}
+
+/// Reasons a manifest region could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    BadMagic,
+    BadChecksum,
+}
+
+impl Manifest {
+    pub fn new(page_size: u32) -> Self {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            page_size,
+            hot_threshold: 0,
+            page_count: 0,
+            batch_watermark: 0,
+            free_pages: Vec::new(),
+        }
+    }
+
+    // Size of the serialized manifest
+    fn size(&self) -> usize {
+        HEADER_SIZE + self.free_pages.len() * PAGE_ID_SIZE + CRC32_SIZE
+    }
+
+    // Serialize the manifest into a mutable buffer
+    pub fn write_to_buffer(&self, buf: &mut [u8]) -> usize {
+        assert!(self.free_pages.len() <= MAX_LISTED_FREE_PAGES);
+        assert!(buf.len() >= self.size());
+        let mut offset = 0;
+        buf[offset..offset + MAGIC_SIZE].copy_from_slice(MANIFEST_MAGIC);
+        offset += MAGIC_SIZE;
+        buf[offset..offset + VERSION_SIZE].copy_from_slice(&self.format_version.to_le_bytes());
+        offset += VERSION_SIZE;
+        buf[offset..offset + PAGE_SIZE_SIZE].copy_from_slice(&self.page_size.to_le_bytes());
+        offset += PAGE_SIZE_SIZE;
+        buf[offset..offset + HOT_THRESHOLD_SIZE].copy_from_slice(&self.hot_threshold.to_le_bytes());
+        offset += HOT_THRESHOLD_SIZE;
+        buf[offset..offset + PAGE_COUNT_SIZE].copy_from_slice(&self.page_count.to_le_bytes());
+        offset += PAGE_COUNT_SIZE;
+        buf[offset..offset + WATERMARK_SIZE].copy_from_slice(&self.batch_watermark.to_le_bytes());
+        offset += WATERMARK_SIZE;
+        buf[offset..offset + FREE_COUNT_SIZE]
+            .copy_from_slice(&(self.free_pages.len() as u32).to_le_bytes());
+        offset += FREE_COUNT_SIZE;
+        for page_id in &self.free_pages {
+            buf[offset..offset + PAGE_ID_SIZE].copy_from_slice(&page_id.to_le_bytes());
+            offset += PAGE_ID_SIZE;
+        }
+
+        let crc32 = crc32fast::hash(&buf[0..offset]);
+        buf[offset..offset + CRC32_SIZE].copy_from_slice(&crc32.to_le_bytes());
+        offset + CRC32_SIZE
+    }
+
+    // Deserialize the manifest from a buffer
+    pub fn read_from_buffer(buf: &[u8]) -> Result<Self, ManifestError> {
+        assert!(buf.len() >= MANIFEST_REGION_SIZE as usize);
+        if &buf[0..MAGIC_SIZE] != MANIFEST_MAGIC {
+            return Err(ManifestError::BadMagic);
+        }
+
+        // The checksum follows the free page ids, don't trust their count
+        // before it is verified
+        let free_count = u32::from_le_bytes(
+            buf[HEADER_SIZE - FREE_COUNT_SIZE..HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        if free_count > MAX_LISTED_FREE_PAGES {
+            return Err(ManifestError::BadChecksum);
+        }
+        let body_size = HEADER_SIZE + free_count * PAGE_ID_SIZE;
+        let stored_crc32 =
+            u32::from_le_bytes(buf[body_size..body_size + CRC32_SIZE].try_into().unwrap());
+        if crc32fast::hash(&buf[0..body_size]) != stored_crc32 {
+            return Err(ManifestError::BadChecksum);
+        }
+
+        let mut offset = MAGIC_SIZE;
+        let format_version =
+            u32::from_le_bytes(buf[offset..offset + VERSION_SIZE].try_into().unwrap());
+        offset += VERSION_SIZE;
+        let page_size =
+            u32::from_le_bytes(buf[offset..offset + PAGE_SIZE_SIZE].try_into().unwrap());
+        offset += PAGE_SIZE_SIZE;
+        let hot_threshold =
+            u32::from_le_bytes(buf[offset..offset + HOT_THRESHOLD_SIZE].try_into().unwrap());
+        offset += HOT_THRESHOLD_SIZE;
+        let page_count =
+            u64::from_le_bytes(buf[offset..offset + PAGE_COUNT_SIZE].try_into().unwrap());
+        offset += PAGE_COUNT_SIZE;
+        let batch_watermark =
+            u64::from_le_bytes(buf[offset..offset + WATERMARK_SIZE].try_into().unwrap());
+        offset += WATERMARK_SIZE + FREE_COUNT_SIZE;
+        let free_pages = buf[offset..body_size]
+            .chunks_exact(PAGE_ID_SIZE)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+
+        Ok(Manifest {
+            format_version,
+            page_size,
+            hot_threshold,
+            page_count,
+            batch_watermark,
+            free_pages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            page_size: 4096,
+            hot_threshold: 3,
+            page_count: 42,
+            batch_watermark: 7,
+            free_pages: vec![3, 12],
+        };
+        let mut buf = vec![0u8; MANIFEST_REGION_SIZE as usize];
+        manifest.write_to_buffer(&mut buf);
+        assert_eq!(Manifest::read_from_buffer(&buf), Ok(manifest.clone()));
+
+        // A full list still fits the region
+        let full = Manifest {
+            free_pages: (0..MAX_LISTED_FREE_PAGES as u64).collect(),
+            ..manifest
+        };
+        let mut full_buf = vec![0u8; MANIFEST_REGION_SIZE as usize];
+        assert!(full.write_to_buffer(&mut full_buf) <= MANIFEST_REGION_SIZE as usize);
+        assert_eq!(Manifest::read_from_buffer(&full_buf), Ok(full));
+
+        buf[MAGIC_SIZE] ^= 0xff;
+        assert_eq!(
+            Manifest::read_from_buffer(&buf),
+            Err(ManifestError::BadChecksum)
+        );
+
+        let zeroed = vec![0u8; MANIFEST_REGION_SIZE as usize];
+        assert_eq!(
+            Manifest::read_from_buffer(&zeroed),
+            Err(ManifestError::BadMagic)
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// `MemoryStore` keeps pages in memory, serialized the same way they are on
+// the device, so that pages are checked and copied on every read and write
+// like with a real device.
+// - Works anywhere, e.g. on tmpfs where `O_DIRECT` is not supported, and
+//   without the variance of a real device, for tests and benchmarks.
+// - Requests can be given a simulated latency, counted in `SsdMetrics` like
+//   the time a device takes.
+// - The pages live as long as any handle to them, see `MemoryStore::reopen`.
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info};
+
+use super::device::{Durability, SsdDevice, SsdError, SsdMetrics};
+use super::manifest::Manifest;
+use super::page::Page;
+use super::store::PageStore;
+
+/// Time each kind of request takes on a `MemoryStore`, none by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SimulatedLatency {
+    pub read: Duration,
+    pub write: Duration,
+    pub sync: Duration,
+}
+
+/// Page store in memory, see `PageStore`
+#[derive(Debug)]
+pub struct MemoryStore {
+    page_size: u32,
+    /// Shared by all handles opened with `reopen`
+    contents: Arc<RwLock<Contents>>,
+    metrics: SsdMetrics,
+    durability: Durability,
+    latency: SimulatedLatency,
+}
+
+#[derive(Debug)]
+struct Contents {
+    /// Serialized pages by page id, None for pages never written or discarded
+    pages: Vec<Option<Box<[u8]>>>,
+    manifest: Manifest,
+}
+
+impl MemoryStore {
+    /// Creates an empty store of pages of `page_size` bytes
+    pub fn new(page_size: u32) -> Result<Self, SsdError> {
+        Self::with_latency(page_size, SimulatedLatency::default())
+    }
+
+    /// Creates an empty store whose requests take `latency`
+    pub fn with_latency(page_size: u32, latency: SimulatedLatency) -> Result<Self, SsdError> {
+        if page_size == 0 {
+            error!("Attempted to create MemoryStore with invalid page size: 0");
+            return Err(SsdError::InvalidPageSize);
+        }
+        info!(
+            "Creating new MemoryStore with page_size: {}, latency: {:?}",
+            page_size, latency
+        );
+
+        Ok(MemoryStore {
+            page_size,
+            contents: Arc::new(RwLock::new(Contents {
+                pages: Vec::new(),
+                manifest: Manifest::new(page_size),
+            })),
+            metrics: SsdMetrics::default(),
+            durability: Durability::None,
+            latency,
+        })
+    }
+
+    /// Opens another handle to the same pages, like reopening a data file.
+    /// The handle starts with fresh metrics and `Durability::None`.
+    pub fn reopen(&self) -> MemoryStore {
+        MemoryStore {
+            page_size: self.page_size,
+            contents: Arc::clone(&self.contents),
+            metrics: SsdMetrics::default(),
+            durability: Durability::None,
+            latency: self.latency,
+        }
+    }
+
+    pub fn latency(&self) -> SimulatedLatency {
+        self.latency
+    }
+
+    // Sync after a write when the durability mode asks for it
+    fn sync_for_durability(&self) -> Result<(), SsdError> {
+        match self.durability {
+            Durability::Fsync => self.sync(),
+            Durability::Fdatasync => self.sync_data(),
+            Durability::None | Durability::Periodic(_) => Ok(()),
+        }
+    }
+}
+
+/// Wait out a simulated latency
+fn simulate(latency: Duration) {
+    if !latency.is_zero() {
+        thread::sleep(latency);
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    fn read_page(&self, page_id: u64) -> Result<Page, SsdError> {
+        debug!("Reading page {} from memory", page_id);
+
+        let start = Instant::now();
+        simulate(self.latency.read);
+        let page = match self.contents.read().unwrap().pages.get(page_id as usize) {
+            Some(Some(buffer)) => SsdDevice::decode_page(page_id, buffer),
+            // Reads back like a hole in the data file
+            _ => SsdDevice::decode_page(page_id, &vec![0; self.page_size as usize]),
+        };
+        let elapsed_nanos = start.elapsed().as_nanos() as u64;
+        self.metrics
+            .record_read(self.page_size as usize, elapsed_nanos);
+        page
+    }
+
+    fn write_page(&self, page: &mut Page) -> Result<(), SsdError> {
+        if page.capacity() as u32 != self.page_size {
+            error!(
+                "Page size mismatch: expected {}, got {}",
+                self.page_size,
+                page.capacity()
+            );
+            return Err(SsdError::InvalidPageSize);
+        }
+        debug!("Writing page {} to memory", page.id());
+
+        let start = Instant::now();
+        simulate(self.latency.write);
+        let mut buffer = vec![0; self.page_size as usize].into_boxed_slice();
+        page.write_to_buffer(&mut buffer);
+        {
+            let pages = &mut self.contents.write().unwrap().pages;
+            let index = page.id() as usize;
+            if index >= pages.len() {
+                pages.resize(index + 1, None);
+            }
+            pages[index] = Some(buffer);
+        }
+        let elapsed_nanos = start.elapsed().as_nanos() as u64;
+        self.metrics
+            .record_write(self.page_size as usize, elapsed_nanos);
+        self.sync_for_durability()
+    }
+
+    fn discard_page(&self, page_id: u64) -> Result<(), SsdError> {
+        debug!("Discarding page {} in memory", page_id);
+        simulate(self.latency.write);
+        let mut contents = self.contents.write().unwrap();
+        if let Some(page) = contents.pages.get_mut(page_id as usize) {
+            *page = None;
+        }
+        drop(contents);
+        self.metrics.record_discard();
+        self.sync_for_durability()
+    }
+
+    // Memory is as durable as it gets, syncs only take their latency
+    fn sync(&self) -> Result<(), SsdError> {
+        let start = Instant::now();
+        simulate(self.latency.sync);
+        self.metrics.record_sync(start.elapsed().as_nanos() as u64);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<(), SsdError> {
+        self.sync()
+    }
+
+    fn set_durability(&mut self, durability: Durability) -> Result<(), SsdError> {
+        self.durability = durability;
+        Ok(())
+    }
+
+    fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    fn page_count(&self) -> Result<u64, SsdError> {
+        Ok(self.contents.read().unwrap().pages.len() as u64)
+    }
+
+    fn manifest(&self) -> Manifest {
+        self.contents.read().unwrap().manifest.clone()
+    }
+
+    fn write_manifest(&self, manifest: Manifest) -> Result<(), SsdError> {
+        self.contents.write().unwrap().manifest = manifest;
+        Ok(())
+    }
+
+    fn metrics(&self) -> &SsdMetrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::page::CorruptionError;
+
+    #[test]
+    fn test_memory_store_operations() {
+        let store = MemoryStore::new(4096).unwrap();
+        for page_id in 0..3 {
+            let mut page = Page::new(page_id, 4096);
+            page.push_entry(b"key", b"value", page_id).unwrap();
+            store.write_page(&mut page).unwrap();
+        }
+        store.discard_page(1).unwrap();
+
+        assert_eq!(store.page_count().unwrap(), 3);
+        let page = store.read_page(2).unwrap();
+        assert_eq!(page.get(0, b"key").unwrap(), b"value");
+        // Discarded and never written pages read like holes in a file
+        for page_id in [1, 7] {
+            assert!(matches!(
+                store.read_page(page_id),
+                Err(SsdError::Corruption {
+                    error: CorruptionError::Unwritten,
+                    ..
+                })
+            ));
+        }
+
+        let metrics = store.metrics();
+        assert_eq!(metrics.writes(), 3);
+        assert_eq!(metrics.reads(), 3);
+        assert_eq!(metrics.discards(), 1);
+        assert!(matches!(
+            store.write_page(&mut Page::new(0, 8192)),
+            Err(SsdError::InvalidPageSize)
+        ));
+
+        // Reopening keeps the pages and the manifest
+        let mut manifest = store.manifest();
+        manifest.page_count = 3;
+        store.write_manifest(manifest).unwrap();
+        let store = store.reopen();
+        assert_eq!(store.manifest().page_count, 3);
+        assert_eq!(
+            store.read_page(0).unwrap().get(0, b"key").unwrap(),
+            b"value"
+        );
+        assert_eq!(store.metrics().reads(), 1);
+    }
+
+    #[test]
+    fn test_simulated_latency() {
+        let latency = SimulatedLatency {
+            read: Duration::from_millis(2),
+            write: Duration::from_millis(1),
+            sync: Duration::from_millis(3),
+        };
+        let mut store = MemoryStore::with_latency(4096, latency).unwrap();
+        store.set_durability(Durability::Fdatasync).unwrap();
+
+        let mut page = Page::new(0, 4096);
+        page.push_entry(b"key", b"value", 0).unwrap();
+        store.write_page(&mut page).unwrap();
+        store.read_page(0).unwrap();
+
+        // Latencies are counted in microseconds, like the device's
+        let metrics = store.metrics();
+        assert_eq!(metrics.syncs(), 1);
+        assert!(metrics.read_latency_percentile(100.0) >= 2000.0);
+        assert!(metrics.write_latency_percentile(100.0) >= 1000.0);
+        assert!(metrics.sync_latency_percentile(100.0) >= 3000.0);
+    }
+}
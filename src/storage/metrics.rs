@@ -101,9 +101,10 @@ impl Metrics {
             "count",
             "sum (s)"
         );
-        println!("{}", std::iter::repeat("-").take(134).collect::<String>());
+        println!("{}", "-".repeat(134));
 
         let p = |mut tuples: Vec<(String, _, _, _, _, _, _, _, _, _)>| {
+            #[allow(clippy::neg_multiply)]
             tuples.sort_by_key(|t| (t.9 * -1. * 1e3) as i64);
             for v in tuples {
                 println!(
@@ -138,7 +139,7 @@ impl Metrics {
             lat("ticket q pop", &self.ticket_queue_pop),
         ]);
 
-        println!("{}", std::iter::repeat("-").take(134).collect::<String>());
+        println!("{}", "-".repeat(134));
         println!("cq:");
         p(vec![
             lat("cq_mu_wait", &self.cq_mu_wait),
@@ -147,13 +148,13 @@ impl Metrics {
             lat("ticket q push", &self.ticket_queue_push),
         ]);
 
-        println!("{}", std::iter::repeat("-").take(134).collect::<String>());
+        println!("{}", "-".repeat(134));
         println!("reaping and waiting:");
         p(vec![
             lat("reap_ready", &self.reap_ready),
             lat("wait", &self.wait),
         ]);
 
-        println!("{}", std::iter::repeat("-").take(134).collect::<String>());
+        println!("{}", "-".repeat(134));
     }
 }
@@ -1,10 +1,17 @@
+pub mod cache;
 mod completion;
 pub mod device;
+pub mod eviction;
+pub mod fault;
 mod histogram;
 pub mod io_uring;
 mod lazy;
+pub mod manifest;
+pub mod memory;
 mod metrics;
 pub mod page;
+pub mod store;
+pub mod wal;
 
 /// Create a new IO system.
 pub fn new() -> std::io::Result<Rio> {
@@ -15,6 +22,7 @@ pub fn new() -> std::io::Result<Rio> {
 /// can be operated on as if they were a libc::iovec
 pub trait AsIoVec {
     /// Returns the address of this object.
+    #[allow(clippy::wrong_self_convention)]
     fn into_new_iovec(&self) -> libc::iovec;
 }
 
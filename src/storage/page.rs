@@ -12,7 +12,25 @@ use tracing::info;
 
 const MAGIC_HEADER: &str = "blitzkv";
 
-#[derive(Debug)]
+/// Version of the on-disk page layout, bump it whenever the serialized form changes
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Reasons a buffer could not be decoded into a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionError {
+    /// The buffer is all zeroes: the page was never written or was discarded
+    Unwritten,
+    /// The buffer does not start with the page magic but holds data
+    BadMagic,
+    /// The checksum of the data section does not match the header
+    BadCrc { stored: u32, computed: u32 },
+    /// An entry extends past the end of the page
+    TruncatedEntry { index: usize },
+    /// The page header names a different page than the one requested
+    PageIdMismatch { expected: u64, found: u64 },
+}
+
+#[derive(Debug, Clone)]
 pub struct Page {
     header: PageHeader,
     data: Vec<Entry>,
@@ -33,25 +51,28 @@ impl std::fmt::Display for Page {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct PageHeader {
     magic: String, // Magic header to identify storage format
     id: u64,       // Unique identifier for the storage unit
     size: u32,     // Total size of the storage unit in bytes
     crc32: u32,    // CRC32 checksum of the data section
+    flags: u8,     // Page flags, see `PAGE_FLAG_*`
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Entry {
     metadata: EntryMetadata,
     key: Vec<u8>,   // Key stored as bytes for flexibility
     value: Vec<u8>, // Value stored as bytes for flexibility
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct EntryMetadata {
     key_size: u32,
     value_size: u32,
+    seq: u64,  // Sequence number of the write, newer writes have larger values
+    flags: u8, // Entry flags, see `ENTRY_FLAG_*`
 }
 
 // Constants for fixed sizes
@@ -59,9 +80,22 @@ const MAGIC_SIZE: usize = 7; // Length of "blitzkv"
 const ID_SIZE: usize = std::mem::size_of::<u64>();
 const SIZE_FIELD_SIZE: usize = std::mem::size_of::<u32>();
 const CRC32_SIZE: usize = std::mem::size_of::<u32>();
-const HEADER_SIZE: usize = MAGIC_SIZE + ID_SIZE + SIZE_FIELD_SIZE + CRC32_SIZE;
+const FLAGS_SIZE: usize = std::mem::size_of::<u8>();
+const HEADER_SIZE: usize = MAGIC_SIZE + ID_SIZE + SIZE_FIELD_SIZE + CRC32_SIZE + FLAGS_SIZE;
+
+const SEQ_SIZE: usize = std::mem::size_of::<u64>();
+const ENTRY_FLAGS_SIZE: usize = std::mem::size_of::<u8>();
+pub const ENTRY_METADATA_SIZE: usize = SIZE_FIELD_SIZE * 2 + SEQ_SIZE + ENTRY_FLAGS_SIZE; // key_size + value_size + seq + flags
+
+// The page holds hot entries
+const PAGE_FLAG_HOT: u8 = 1;
+
+/// The entry records the deletion of its key and carries no value
+pub const ENTRY_FLAG_TOMBSTONE: u8 = 1;
+/// The entry was written by a write batch and only counts once the batch
+/// is committed, see `Manifest::batch_watermark`
+pub const ENTRY_FLAG_BATCH: u8 = 2;
 
-const ENTRY_METADATA_SIZE: usize = SIZE_FIELD_SIZE * 2; // key_size + value_size + deleted flag
 impl PageHeader {
     // Serialize header into a mutable buffer
     fn write_to_buffer(&self, buf: &mut [u8]) -> usize {
@@ -72,6 +106,8 @@ impl PageHeader {
         buf[size_offset..size_offset + SIZE_FIELD_SIZE].copy_from_slice(&self.size.to_le_bytes());
         let crc32_offset = size_offset + SIZE_FIELD_SIZE;
         buf[crc32_offset..crc32_offset + CRC32_SIZE].copy_from_slice(&self.crc32.to_le_bytes());
+        let flags_offset = crc32_offset + CRC32_SIZE;
+        buf[flags_offset] = self.flags;
         HEADER_SIZE
     }
 
@@ -93,6 +129,8 @@ impl PageHeader {
                 .try_into()
                 .unwrap(),
         );
+        let flags_offset = crc32_offset + CRC32_SIZE;
+        let flags = buf[flags_offset];
 
         (
             PageHeader {
@@ -100,6 +138,7 @@ impl PageHeader {
                 id,
                 size,
                 crc32,
+                flags,
             },
             HEADER_SIZE,
         )
@@ -112,26 +151,36 @@ impl EntryMetadata {
         assert!(buf.len() >= ENTRY_METADATA_SIZE);
         buf[0..SIZE_FIELD_SIZE].copy_from_slice(&self.key_size.to_le_bytes());
         buf[SIZE_FIELD_SIZE..SIZE_FIELD_SIZE * 2].copy_from_slice(&self.value_size.to_le_bytes());
+        let seq_offset = SIZE_FIELD_SIZE * 2;
+        buf[seq_offset..seq_offset + SEQ_SIZE].copy_from_slice(&self.seq.to_le_bytes());
+        buf[seq_offset + SEQ_SIZE] = self.flags;
         ENTRY_METADATA_SIZE
     }
 
-    // Deserialize metadata from a buffer
-    fn read_from_buffer(buf: &[u8]) -> (Self, usize) {
-        assert!(buf.len() >= ENTRY_METADATA_SIZE);
+    // Deserialize metadata from a buffer, None if the buffer is too short
+    fn read_from_buffer(buf: &[u8]) -> Option<(Self, usize)> {
+        if buf.len() < ENTRY_METADATA_SIZE {
+            return None;
+        }
         let key_size = u32::from_le_bytes(buf[0..SIZE_FIELD_SIZE].try_into().unwrap());
         let value_size = u32::from_le_bytes(
             buf[SIZE_FIELD_SIZE..SIZE_FIELD_SIZE * 2]
                 .try_into()
                 .unwrap(),
         );
+        let seq_offset = SIZE_FIELD_SIZE * 2;
+        let seq = u64::from_le_bytes(buf[seq_offset..seq_offset + SEQ_SIZE].try_into().unwrap());
+        let flags = buf[seq_offset + SEQ_SIZE];
 
-        (
+        Some((
             EntryMetadata {
                 key_size,
                 value_size,
+                seq,
+                flags,
             },
             ENTRY_METADATA_SIZE,
-        )
+        ))
     }
 }
 
@@ -158,16 +207,19 @@ impl Entry {
         offset
     }
 
-    // Deserialize entry from a buffer
-    fn read_from_buffer(buf: &[u8]) -> (Self, usize) {
+    // Deserialize entry from a buffer, None if the entry does not fit in the buffer
+    fn read_from_buffer(buf: &[u8]) -> Option<(Self, usize)> {
         let mut offset = 0;
 
         // Read metadata
-        let (metadata, meta_size) = EntryMetadata::read_from_buffer(&buf[offset..]);
+        let (metadata, meta_size) = EntryMetadata::read_from_buffer(&buf[offset..])?;
         offset += meta_size;
 
         let key_size = metadata.key_size as usize;
         let value_size = metadata.value_size as usize;
+        if buf.len() - offset < key_size + value_size {
+            return None;
+        }
 
         // Read key
         let key = buf[offset..offset + key_size].to_vec();
@@ -177,14 +229,14 @@ impl Entry {
         let value = buf[offset..offset + value_size].to_vec();
         offset += value_size;
 
-        (
+        Some((
             Entry {
                 metadata,
                 key,
                 value,
             },
             offset,
-        )
+        ))
     }
 
     // Calculate total size of the entry when serialized
@@ -200,6 +252,22 @@ impl Entry {
     pub fn value(&self) -> &[u8] {
         &self.value
     }
+
+    pub fn seq(&self) -> u64 {
+        self.metadata.seq
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.metadata.flags
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.metadata.flags & ENTRY_FLAG_TOMBSTONE != 0
+    }
+
+    pub fn is_batch(&self) -> bool {
+        self.metadata.flags & ENTRY_FLAG_BATCH != 0
+    }
 }
 
 impl Page {
@@ -211,6 +279,7 @@ impl Page {
                 id,
                 size,
                 crc32: 0,
+                flags: 0,
             },
             data: Vec::new(),
             current_size: HEADER_SIZE + SIZE_FIELD_SIZE, // Initial size includes header and entry count
@@ -221,11 +290,39 @@ impl Page {
         self.header.size - self.current_size as u32
     }
 
+    // Space an entry with the given key and value occupies once serialized
+    pub fn entry_size(key: &[u8], value: &[u8]) -> usize {
+        ENTRY_METADATA_SIZE + key.len() + value.len()
+    }
+
+    pub fn is_hot(&self) -> bool {
+        self.header.flags & PAGE_FLAG_HOT != 0
+    }
+
+    pub fn set_hot(&mut self, is_hot: bool) {
+        if is_hot {
+            self.header.flags |= PAGE_FLAG_HOT;
+        } else {
+            self.header.flags &= !PAGE_FLAG_HOT;
+        }
+    }
+
     // Attempt to add an entry to the storage unit
     // Returns the offset of the entry if successful, or None if the entry exceeds the size limit
-    pub fn push_entry(&mut self, key: &[u8], value: &[u8]) -> Option<usize> {
+    pub fn push_entry(&mut self, key: &[u8], value: &[u8], seq: u64) -> Option<usize> {
+        self.push_entry_with_flags(key, value, seq, 0)
+    }
+
+    // Like `push_entry`, marking the entry with `ENTRY_FLAG_*` flags
+    pub fn push_entry_with_flags(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        seq: u64,
+        flags: u8,
+    ) -> Option<usize> {
         let offset = self.data.len();
-        let new_size = self.current_size + ENTRY_METADATA_SIZE + key.len() + value.len();
+        let new_size = self.current_size + Self::entry_size(key, value);
 
         if new_size as u32 > self.header.size {
             return None; // Exceeds the size limit
@@ -235,6 +332,8 @@ impl Page {
             metadata: EntryMetadata {
                 key_size: key.len() as u32,
                 value_size: value.len() as u32,
+                seq,
+                flags,
             },
             key: key.to_vec(),
             value: value.to_vec(),
@@ -245,7 +344,7 @@ impl Page {
 
     pub fn get(&self, page_index: usize, key: &[u8]) -> Option<Vec<u8>> {
         self.data.get(page_index).and_then(|entry| {
-            if entry.key() == key {
+            if entry.key() == key && !entry.is_tombstone() {
                 Some(entry.value().to_vec())
             } else {
                 info!("key is not match");
@@ -288,23 +387,37 @@ impl Page {
     }
 
     // Deserialize entire storage unit from a buffer
-    pub fn read_from_buffer(buf: &[u8]) -> Self {
+    pub fn read_from_buffer(buf: &[u8]) -> Result<Self, CorruptionError> {
         let mut offset = 0;
 
         // Read header
         let (header, header_size) = PageHeader::read_from_buffer(&buf[offset..]);
         offset += header_size;
+        if header.magic != MAGIC_HEADER {
+            if buf.iter().all(|&byte| byte == 0) {
+                return Err(CorruptionError::Unwritten);
+            }
+            return Err(CorruptionError::BadMagic);
+        }
+
+        // Entries may not extend past the size recorded in the header
+        let end = (header.size as usize).min(buf.len());
+        if end < offset + SIZE_FIELD_SIZE {
+            return Err(CorruptionError::TruncatedEntry { index: 0 });
+        }
 
         // Read number of entries
         let entry_count =
             u32::from_le_bytes(buf[offset..offset + SIZE_FIELD_SIZE].try_into().unwrap()) as usize;
         offset += SIZE_FIELD_SIZE;
 
-        let mut data = Vec::with_capacity(entry_count);
+        // Every entry takes at least its metadata, don't trust the count for the allocation
+        let mut data = Vec::with_capacity(entry_count.min((end - offset) / ENTRY_METADATA_SIZE));
 
         // Read entries
-        for _ in 0..entry_count {
-            let (entry, entry_size) = Entry::read_from_buffer(&buf[offset..]);
+        for index in 0..entry_count {
+            let (entry, entry_size) = Entry::read_from_buffer(&buf[offset..end])
+                .ok_or(CorruptionError::TruncatedEntry { index })?;
             offset += entry_size;
             data.push(entry);
         }
@@ -314,18 +427,21 @@ impl Page {
         let crc32_end = offset;
         let computed_crc32 = crc32fast::hash(&buf[crc32_start..crc32_end]);
         if computed_crc32 != header.crc32 {
-            panic!("CRC32 checksum mismatch");
+            return Err(CorruptionError::BadCrc {
+                stored: header.crc32,
+                computed: computed_crc32,
+            });
         }
 
-        Page {
+        Ok(Page {
             header,
             data,
             current_size: offset,
-        }
+        })
     }
 
     // Returns an iterator over the entries
-    pub fn iter(&self) -> Iter<Entry> {
+    pub fn iter(&self) -> Iter<'_, Entry> {
         self.data.iter()
     }
 
@@ -355,6 +471,39 @@ impl Page {
         self.header.id
     }
 
+    // Turn the entry at `page_index` into a tombstone written by `seq`, dropping its value.
+    // The entry keeps its slot so the positions of other entries don't change.
+    // Returns false if the slot does not hold a live entry for `key`.
+    pub fn tombstone_entry(&mut self, page_index: usize, key: &[u8], seq: u64) -> bool {
+        match self.data.get_mut(page_index) {
+            Some(entry) if entry.key == key && !entry.is_tombstone() => {
+                self.current_size -= entry.value.len();
+                entry.value = Vec::new();
+                entry.metadata.value_size = 0;
+                entry.metadata.seq = seq;
+                entry.metadata.flags |= ENTRY_FLAG_TOMBSTONE;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Keep only the entries for which `keep` returns true, returns how many were removed.
+    // Entries after a removed one move to a lower index.
+    pub fn retain_entries<F: FnMut(&Entry) -> bool>(&mut self, mut keep: F) -> usize {
+        let count = self.data.len();
+        let mut current_size = self.current_size;
+        self.data.retain(|entry| {
+            let kept = keep(entry);
+            if !kept {
+                current_size -= entry.total_size();
+            }
+            kept
+        });
+        self.current_size = current_size;
+        count - self.data.len()
+    }
+
     pub fn remove_entry(&mut self, key: &[u8]) -> bool {
         let mut index = 0;
         let mut found = false;
@@ -374,3 +523,56 @@ impl Page {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialized_page() -> Vec<u8> {
+        let mut page = Page::new(7, 4096);
+        page.push_entry(b"key1", b"value1", 0).unwrap();
+        page.push_entry(b"key2", b"value2", 1).unwrap();
+        let mut buf = vec![0u8; 4096];
+        page.write_to_buffer(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_read_corrupted_page() {
+        let buf = serialized_page();
+        let page = Page::read_from_buffer(&buf).unwrap();
+        assert_eq!(page.id(), 7);
+        assert_eq!(page.get(1, b"key2"), Some(b"value2".to_vec()));
+
+        let zeroed = vec![0u8; 4096];
+        assert_eq!(
+            Page::read_from_buffer(&zeroed).unwrap_err(),
+            CorruptionError::Unwritten
+        );
+        let mut garbage = zeroed.clone();
+        garbage[4000] = 1;
+        assert_eq!(
+            Page::read_from_buffer(&garbage).unwrap_err(),
+            CorruptionError::BadMagic
+        );
+
+        // Flip a bit in the value of the last entry
+        let mut flipped = buf.clone();
+        let last_value = HEADER_SIZE + SIZE_FIELD_SIZE + 2 * (ENTRY_METADATA_SIZE + 10) - 1;
+        flipped[last_value] ^= 1;
+        assert!(matches!(
+            Page::read_from_buffer(&flipped),
+            Err(CorruptionError::BadCrc { .. })
+        ));
+
+        // Make the second entry claim a value larger than the page
+        let mut truncated = buf;
+        let value_size = HEADER_SIZE + SIZE_FIELD_SIZE + ENTRY_METADATA_SIZE + 10 + SIZE_FIELD_SIZE;
+        truncated[value_size..value_size + SIZE_FIELD_SIZE]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            Page::read_from_buffer(&truncated).unwrap_err(),
+            CorruptionError::TruncatedEntry { index: 1 }
+        );
+    }
+}
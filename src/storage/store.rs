@@ -0,0 +1,166 @@
+// A page store holds the pages of a database and the manifest describing
+// them. The page manager only talks to its store through `PageStore`, so the
+// database runs the same on any of them:
+// - `SsdDevice`, the data file opened with `O_DIRECT`, issuing its I/O as
+//   syscalls or through io_uring, see `IoBackend`
+// - `MemoryStore`, pages kept in memory, for tests and for measuring the
+//   database without a device
+// A store is shared by the threads of a `SharedDatabase`, so every request
+// but `set_durability` takes a shared reference. The page manager never
+// writes the same page from two threads at once.
+use std::fmt;
+use std::fs::File;
+
+use super::device::{Durability, SsdDevice, SsdError, SsdMetrics};
+use super::io_uring::Rio;
+use super::manifest::Manifest;
+use super::page::Page;
+
+/// Storage of fixed size pages addressed by page id
+pub trait PageStore: fmt::Debug + Send + Sync {
+    /// Size of every page in bytes
+    fn page_size(&self) -> u32;
+
+    /// Read a page. Pages never written or discarded fail with
+    /// `CorruptionError::Unwritten`.
+    fn read_page(&self, page_id: u64) -> Result<Page, SsdError>;
+
+    /// Write a page, syncing it if the durability asks for it
+    fn write_page(&self, page: &mut Page) -> Result<(), SsdError>;
+
+    /// Read several pages at once, results in the order of `page_ids`.
+    /// Stores that can batch their I/O override this.
+    fn read_pages(&self, page_ids: &[u64]) -> Vec<Result<Page, SsdError>> {
+        page_ids
+            .iter()
+            .map(|&page_id| self.read_page(page_id))
+            .collect()
+    }
+
+    /// Write several pages at once, stopping at the first error
+    fn write_pages(&self, pages: &mut [&mut Page]) -> Result<(), SsdError> {
+        pages.iter_mut().try_for_each(|page| self.write_page(page))
+    }
+
+    /// Drop the content of a page, it reads back as never written
+    fn discard_page(&self, page_id: u64) -> Result<(), SsdError>;
+
+    /// Make all writes so far durable, including metadata
+    fn sync(&self) -> Result<(), SsdError>;
+
+    /// Make all writes so far durable, skipping metadata not needed to read
+    /// them back
+    fn sync_data(&self) -> Result<(), SsdError>;
+
+    /// Change when page writes are synced
+    fn set_durability(&mut self, durability: Durability) -> Result<(), SsdError>;
+
+    fn durability(&self) -> Durability;
+
+    /// Number of pages up to the highest one ever written
+    fn page_count(&self) -> Result<u64, SsdError>;
+
+    /// Copy of the manifest as last written
+    fn manifest(&self) -> Manifest;
+
+    /// Persist the manifest next to the pages
+    fn write_manifest(&self, manifest: Manifest) -> Result<(), SsdError>;
+
+    fn metrics(&self) -> &SsdMetrics;
+
+    /// The file pages are kept in, for I/O issued around the store, e.g.
+    /// by the async API of `SharedDatabase`. Stores that are not a file
+    /// return None.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Offset of a page in `file`
+    fn page_offset(&self, page_id: u64) -> u64 {
+        page_id * self.page_size() as u64
+    }
+
+    /// The ring the store issues its I/O through, if any
+    fn uring(&self) -> Option<&Rio> {
+        None
+    }
+
+    /// Account a page write issued around the store through `file`
+    fn record_external_write(&self, bytes: usize, elapsed_nanos: u64) {
+        self.metrics().record_write(bytes, elapsed_nanos);
+    }
+}
+
+impl PageStore for SsdDevice {
+    fn page_size(&self) -> u32 {
+        SsdDevice::page_size(self)
+    }
+
+    fn read_page(&self, page_id: u64) -> Result<Page, SsdError> {
+        SsdDevice::read_page(self, page_id)
+    }
+
+    fn write_page(&self, page: &mut Page) -> Result<(), SsdError> {
+        SsdDevice::write_page(self, page)
+    }
+
+    fn read_pages(&self, page_ids: &[u64]) -> Vec<Result<Page, SsdError>> {
+        SsdDevice::read_pages(self, page_ids)
+    }
+
+    fn write_pages(&self, pages: &mut [&mut Page]) -> Result<(), SsdError> {
+        SsdDevice::write_pages(self, pages)
+    }
+
+    fn discard_page(&self, page_id: u64) -> Result<(), SsdError> {
+        SsdDevice::discard_page(self, page_id)
+    }
+
+    fn sync(&self) -> Result<(), SsdError> {
+        SsdDevice::sync(self)
+    }
+
+    fn sync_data(&self) -> Result<(), SsdError> {
+        SsdDevice::sync_data(self)
+    }
+
+    fn set_durability(&mut self, durability: Durability) -> Result<(), SsdError> {
+        SsdDevice::set_durability(self, durability)
+    }
+
+    fn durability(&self) -> Durability {
+        SsdDevice::durability(self)
+    }
+
+    fn page_count(&self) -> Result<u64, SsdError> {
+        SsdDevice::page_count(self)
+    }
+
+    fn manifest(&self) -> Manifest {
+        SsdDevice::manifest(self)
+    }
+
+    fn write_manifest(&self, manifest: Manifest) -> Result<(), SsdError> {
+        SsdDevice::write_manifest(self, manifest)
+    }
+
+    fn metrics(&self) -> &SsdMetrics {
+        SsdDevice::metrics(self)
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(SsdDevice::file(self))
+    }
+
+    fn page_offset(&self, page_id: u64) -> u64 {
+        self.calculate_offset(page_id)
+    }
+
+    fn uring(&self) -> Option<&Rio> {
+        SsdDevice::uring(self)
+    }
+
+    fn record_external_write(&self, bytes: usize, elapsed_nanos: u64) {
+        SsdDevice::record_external_write(self, bytes, elapsed_nanos)
+    }
+}
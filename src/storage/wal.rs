@@ -0,0 +1,589 @@
+// The write-ahead log records every mutation before the affected pages are
+// written, so that pages can be flushed lazily and rebuilt after a crash.
+// - **Record layout**: [CRC32] + [Payload length] + [Payload]
+//   - **Payload**: [Kind] + [Seq] + [Key size] + [Value size] + [Key] + [Value]
+//   - A write batch is a single record of kind batch whose value holds the
+//     serialized records of the batch, so it is replayed entirely or not at all.
+// - Records are buffered and fsynced in groups (group commit). A torn record
+//   at the end of the log is discarded when the log is reopened.
+// - A background thread commits a group that waited `group_interval`, so the
+//   last records of a burst don't wait for the next append.
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info, instrument, warn};
+
+const KIND_SET: u8 = 1;
+const KIND_DELETE: u8 = 2;
+const KIND_BATCH: u8 = 3;
+
+// Constants for fixed sizes
+const CRC32_SIZE: usize = std::mem::size_of::<u32>();
+const LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+const RECORD_HEADER_SIZE: usize = CRC32_SIZE + LENGTH_SIZE;
+const KIND_SIZE: usize = std::mem::size_of::<u8>();
+const SEQ_SIZE: usize = std::mem::size_of::<u64>();
+const SIZE_FIELD_SIZE: usize = std::mem::size_of::<u32>();
+const PAYLOAD_HEADER_SIZE: usize = KIND_SIZE + SEQ_SIZE + SIZE_FIELD_SIZE * 2;
+
+/// Group commit and checkpoint settings of the write-ahead log.
+#[derive(Debug, Clone, Copy)]
+pub struct WalConfig {
+    /// Number of records fsynced together in one group.
+    pub group_size: usize,
+    /// Longest time a record may wait for its group to be fsynced.
+    /// Checked whenever a record is appended, and by a background thread
+    /// waking up at this interval.
+    pub group_interval: Duration,
+    /// Size of the log that triggers a checkpoint, which flushes all
+    /// dirty pages and truncates the log.
+    pub checkpoint_size: u64,
+}
+
+impl Default for WalConfig {
+    fn default() -> WalConfig {
+        WalConfig {
+            group_size: 64,
+            group_interval: Duration::from_millis(10),
+            checkpoint_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A single logged mutation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalRecord {
+    Set {
+        seq: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        seq: u64,
+        key: Vec<u8>,
+    },
+}
+
+impl WalRecord {
+    pub fn seq(&self) -> u64 {
+        match self {
+            WalRecord::Set { seq, .. } | WalRecord::Delete { seq, .. } => *seq,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WalRecord::Set { key, .. } | WalRecord::Delete { key, .. } => key,
+        }
+    }
+
+    // Append the serialized record to `buf`
+    fn write_to_buffer(&self, buf: &mut Vec<u8>) {
+        match self {
+            WalRecord::Set { seq, key, value } => write_frame(buf, KIND_SET, *seq, key, value),
+            WalRecord::Delete { seq, key } => write_frame(buf, KIND_DELETE, *seq, key, &[]),
+        }
+    }
+
+    // Append the records of a write batch to `buf` as a single record
+    fn write_batch_to_buffer(records: &[WalRecord], buf: &mut Vec<u8>) {
+        let mut nested = Vec::new();
+        for record in records {
+            record.write_to_buffer(&mut nested);
+        }
+        let seq = records.first().map_or(0, WalRecord::seq);
+        write_frame(buf, KIND_BATCH, seq, &[], &nested);
+    }
+
+    // Deserialize the records framed at the start of `buf`, several for a batch.
+    // Returns None if the record is incomplete or fails its checksum.
+    fn read_from_buffer(buf: &[u8]) -> Option<(Vec<Self>, usize)> {
+        let (kind, seq, key, value, size) = read_frame(buf)?;
+        let records = match kind {
+            KIND_SET => vec![WalRecord::Set { seq, key, value }],
+            KIND_DELETE => vec![WalRecord::Delete { seq, key }],
+            KIND_BATCH => {
+                let mut records = Vec::new();
+                let mut offset = 0;
+                while offset < value.len() {
+                    let (nested, nested_size) = Self::read_from_buffer(&value[offset..])?;
+                    records.extend(nested);
+                    offset += nested_size;
+                }
+                records
+            }
+            _ => return None,
+        };
+        Some((records, size))
+    }
+}
+
+// Append a framed record to `buf`
+fn write_frame(buf: &mut Vec<u8>, kind: u8, seq: u64, key: &[u8], value: &[u8]) {
+    let start = buf.len();
+    let payload_size = PAYLOAD_HEADER_SIZE + key.len() + value.len();
+    // Reserve the record header, the CRC32 is filled in once the payload is written
+    buf.extend_from_slice(&[0u8; CRC32_SIZE]);
+    buf.extend_from_slice(&(payload_size as u32).to_le_bytes());
+
+    let payload_start = buf.len();
+    buf.push(kind);
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+
+    let crc32 = crc32fast::hash(&buf[payload_start..]);
+    buf[start..start + CRC32_SIZE].copy_from_slice(&crc32.to_le_bytes());
+}
+
+// Decode the framed record at the start of `buf` into kind, seq, key, value and its size.
+// Returns None if the record is incomplete or fails its checksum.
+#[allow(clippy::type_complexity)]
+fn read_frame(buf: &[u8]) -> Option<(u8, u64, Vec<u8>, Vec<u8>, usize)> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let crc32 = u32::from_le_bytes(buf[0..CRC32_SIZE].try_into().unwrap());
+    let payload_size =
+        u32::from_le_bytes(buf[CRC32_SIZE..RECORD_HEADER_SIZE].try_into().unwrap()) as usize;
+    if payload_size < PAYLOAD_HEADER_SIZE || buf.len() < RECORD_HEADER_SIZE + payload_size {
+        return None;
+    }
+
+    let payload = &buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_size];
+    if crc32fast::hash(payload) != crc32 {
+        return None;
+    }
+
+    let mut offset = 0;
+    let kind = payload[offset];
+    offset += KIND_SIZE;
+    let seq = u64::from_le_bytes(payload[offset..offset + SEQ_SIZE].try_into().unwrap());
+    offset += SEQ_SIZE;
+    let key_size = u32::from_le_bytes(
+        payload[offset..offset + SIZE_FIELD_SIZE]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    offset += SIZE_FIELD_SIZE;
+    let value_size = u32::from_le_bytes(
+        payload[offset..offset + SIZE_FIELD_SIZE]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    offset += SIZE_FIELD_SIZE;
+    if offset + key_size + value_size != payload_size {
+        return None;
+    }
+
+    let key = payload[offset..offset + key_size].to_vec();
+    offset += key_size;
+    let value = payload[offset..offset + value_size].to_vec();
+
+    Some((kind, seq, key, value, RECORD_HEADER_SIZE + payload_size))
+}
+
+/// Append-only log file with group commit.
+#[derive(Debug)]
+pub struct Wal {
+    /// Shared with the group commit thread
+    log: Arc<Mutex<Log>>,
+    config: WalConfig,
+    // Stops with the log
+    _committer: Option<GroupCommitter>,
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    /// Serialized records waiting for the next group commit
+    pending: Vec<u8>,
+    pending_records: usize,
+    /// When the oldest pending record was appended
+    group_start: Option<Instant>,
+    /// Bytes durably written to the log file
+    synced_len: u64,
+    commits: u64,
+}
+
+/// Background thread committing groups older than `group_interval`
+#[derive(Debug)]
+struct GroupCommitter {
+    // Dropping the sender stops the thread
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for GroupCommitter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Log {
+    /// Write and fsync all pending records
+    fn commit(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        debug!(
+            "Committing group of {} records ({} bytes)",
+            self.pending_records,
+            self.pending.len()
+        );
+        let written = self
+            .file
+            .write_all(&self.pending)
+            .and_then(|()| self.file.sync_data());
+        if let Err(e) = written {
+            // Drop whatever part of the group reached the file, records
+            // appended after it would not be replayed otherwise
+            self.file.set_len(self.synced_len)?;
+            self.file.seek(SeekFrom::Start(self.synced_len))?;
+            return Err(e);
+        }
+
+        self.synced_len += self.pending.len() as u64;
+        self.pending.clear();
+        self.pending_records = 0;
+        self.group_start = None;
+        self.commits += 1;
+        Ok(())
+    }
+
+    // Commit the current group once it is full or too old
+    fn maybe_commit(&mut self, config: &WalConfig) -> io::Result<()> {
+        let group_start = *self.group_start.get_or_insert_with(Instant::now);
+
+        if self.pending_records >= config.group_size
+            || group_start.elapsed() >= config.group_interval
+        {
+            self.commit()?;
+        }
+        Ok(())
+    }
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if missing.
+    /// Returns the log together with every intact record it holds.
+    #[instrument(skip(path))]
+    pub fn open<P: AsRef<Path>>(path: P, config: WalConfig) -> io::Result<(Self, Vec<WalRecord>)> {
+        info!("Opening write-ahead log at {:?}", path.as_ref());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some((read, size)) = WalRecord::read_from_buffer(&buf[offset..]) {
+            records.extend(read);
+            offset += size;
+        }
+
+        if offset < buf.len() {
+            // A crash in the middle of a group commit leaves a torn tail
+            warn!(
+                "Discarding {} bytes of torn records at the end of the log",
+                buf.len() - offset
+            );
+            file.set_len(offset as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::Start(offset as u64))?;
+        info!("Read {} records from write-ahead log", records.len());
+
+        let log = Arc::new(Mutex::new(Log {
+            file,
+            pending: Vec::new(),
+            pending_records: 0,
+            group_start: None,
+            synced_len: offset as u64,
+            commits: 0,
+        }));
+        let committer = Self::start_committer(&log, config.group_interval);
+        Ok((
+            Wal {
+                log,
+                config,
+                _committer: committer,
+            },
+            records,
+        ))
+    }
+
+    fn start_committer(log: &Arc<Mutex<Log>>, interval: Duration) -> Option<GroupCommitter> {
+        if interval.is_zero() {
+            // Every append commits
+            return None;
+        }
+        let log = Arc::clone(log);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let mut log = log.lock().unwrap();
+                if log
+                    .group_start
+                    .is_none_or(|start| start.elapsed() < interval)
+                {
+                    continue;
+                }
+                // The records stay pending, the next append or commit retries
+                if let Err(e) = log.commit() {
+                    error!("Background group commit failed: {:?}", e);
+                }
+            }
+        });
+        Some(GroupCommitter {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// Append a record to the current group, committing the group once it is
+    /// full or has been open for longer than `group_interval`. If this fails
+    /// the record is not in the log.
+    pub fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let start = log.pending.len();
+        record.write_to_buffer(&mut log.pending);
+        log.pending_records += 1;
+        let result = log.maybe_commit(&self.config);
+        if result.is_err() {
+            // The caller reports the write as failed, a later group must not
+            // commit it
+            log.pending.truncate(start);
+            log.pending_records -= 1;
+        }
+        result
+    }
+
+    /// Append the records of a write batch, which are replayed all together or
+    /// not at all. If this fails the batch is not in the log.
+    pub fn append_batch(&mut self, records: &[WalRecord]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let start = log.pending.len();
+        WalRecord::write_batch_to_buffer(records, &mut log.pending);
+        log.pending_records += records.len();
+        let result = log.maybe_commit(&self.config);
+        if result.is_err() {
+            // The caller reverts the batch, a later group must not commit it
+            log.pending.truncate(start);
+            log.pending_records -= records.len();
+        }
+        result
+    }
+
+    /// Write and fsync all pending records
+    #[instrument(skip(self))]
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.log.lock().unwrap().commit()
+    }
+
+    /// Drop every record, called once the pages they describe are durable
+    #[instrument(skip(self))]
+    pub fn truncate(&mut self) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        log.pending.clear();
+        log.pending_records = 0;
+        log.group_start = None;
+        log.file.set_len(0)?;
+        log.file.seek(SeekFrom::Start(0))?;
+        log.file.sync_data()?;
+        log.synced_len = 0;
+        Ok(())
+    }
+
+    /// Whether the log has grown past the checkpoint size
+    pub fn needs_checkpoint(&self) -> bool {
+        self.len() >= self.config.checkpoint_size
+    }
+
+    /// Size of the log including records not yet committed
+    pub fn len(&self) -> u64 {
+        let log = self.log.lock().unwrap();
+        log.synced_len + log.pending.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of group commits since the log was opened
+    pub fn commits(&self) -> u64 {
+        self.log.lock().unwrap().commits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wal_group_commit_and_replay() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.wal");
+        let config = WalConfig {
+            group_size: 2,
+            group_interval: Duration::from_secs(60),
+            ..WalConfig::default()
+        };
+
+        let records = vec![
+            WalRecord::Set {
+                seq: 0,
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+            },
+            WalRecord::Delete {
+                seq: 1,
+                key: b"key1".to_vec(),
+            },
+            WalRecord::Set {
+                seq: 2,
+                key: b"key2".to_vec(),
+                value: b"value2".to_vec(),
+            },
+        ];
+
+        {
+            let (mut wal, replayed) = Wal::open(&path, config).unwrap();
+            assert!(replayed.is_empty());
+            for record in &records {
+                wal.append(record).unwrap();
+            }
+            // The first two records form a full group, the third is still pending
+            assert_eq!(wal.commits(), 1);
+        }
+
+        let (mut wal, replayed) = Wal::open(&path, config).unwrap();
+        assert_eq!(replayed, records[..2]);
+
+        // A torn record at the tail is dropped on the next open
+        wal.append(&records[2]).unwrap();
+        wal.commit().unwrap();
+        let len = wal.len();
+        wal.log.lock().unwrap().file.set_len(len - 1).unwrap();
+        drop(wal);
+
+        let (wal, replayed) = Wal::open(&path, config).unwrap();
+        assert_eq!(replayed, records[..2]);
+        assert!(wal.len() < len);
+    }
+
+    #[test]
+    fn test_wal_commits_idle_group_in_background() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("idle.wal");
+        let config = WalConfig {
+            group_size: 64,
+            group_interval: Duration::from_millis(5),
+            ..WalConfig::default()
+        };
+        let record = WalRecord::Set {
+            seq: 0,
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        };
+
+        let (mut wal, _) = Wal::open(&path, config).unwrap();
+        wal.append(&record).unwrap();
+
+        // No further append comes, the group is committed anyway
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while wal.commits() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(wal.commits(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), wal.len());
+    }
+
+    #[test]
+    fn test_wal_drops_failed_append() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("failed.wal");
+        let config = WalConfig {
+            group_size: 1,
+            ..WalConfig::default()
+        };
+        let records: Vec<WalRecord> = (0..3)
+            .map(|seq| WalRecord::Set {
+                seq,
+                key: format!("key{seq}").into_bytes(),
+                value: b"value".to_vec(),
+            })
+            .collect();
+
+        let (mut wal, _) = Wal::open(&path, config).unwrap();
+        wal.append(&records[0]).unwrap();
+        // A read-only handle makes the commit of the next record fail
+        let readonly = std::fs::File::open(&path).unwrap();
+        let file = std::mem::replace(&mut wal.log.lock().unwrap().file, readonly);
+        assert!(wal.append(&records[1]).is_err());
+        wal.log.lock().unwrap().file = file;
+        wal.append(&records[2]).unwrap();
+        drop(wal);
+
+        // The failed record was not committed along with the next one
+        let (_, replayed) = Wal::open(&path, config).unwrap();
+        assert_eq!(replayed, vec![records[0].clone(), records[2].clone()]);
+    }
+
+    #[test]
+    fn test_wal_batch_is_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("batch.wal");
+        let config = WalConfig::default();
+        let single = WalRecord::Set {
+            seq: 0,
+            key: b"key0".to_vec(),
+            value: b"value0".to_vec(),
+        };
+        let batch = vec![
+            WalRecord::Set {
+                seq: 1,
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+            },
+            WalRecord::Delete {
+                seq: 2,
+                key: b"key0".to_vec(),
+            },
+        ];
+
+        let (mut wal, _) = Wal::open(&path, config).unwrap();
+        wal.append(&single).unwrap();
+        wal.append_batch(&batch).unwrap();
+        wal.commit().unwrap();
+        let len = wal.len();
+        drop(wal);
+
+        let (wal, replayed) = Wal::open(&path, config).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(replayed[1..], batch[..]);
+
+        // Tearing the end of the batch drops all of it
+        wal.log.lock().unwrap().file.set_len(len - 1).unwrap();
+        drop(wal);
+        let (_, replayed) = Wal::open(&path, config).unwrap();
+        assert_eq!(replayed, vec![single]);
+    }
+}